@@ -1,5 +1,6 @@
 mod tree;
-use crate::avl::tree::{AvlNode, AvlTree};
+pub use crate::avl::tree::AvlNode;
+use crate::avl::tree::AvlTree;
 use std::iter::FromIterator;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -16,23 +17,15 @@ impl<K: Ord + Clone + Sync + Send, V: Clone + Sync + Send> AvlTreeMap<K, V> {
     }
 }
 impl<K: Ord + Clone + Sync + Send, V: Clone + Sync + Send> AvlTreeMap<K, V> {
-    pub fn insert(&mut self, key: K, value: V) {
+    /// Inserts the key and returns the value it replaced, if any.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
         if let Some(node) = &mut self.root {
-            node.insert(key, value);
+            node.insert(key, value)
         } else {
             self.root = Some(Box::new(AvlNode::new(key, value)));
+            None
         }
     }
-    fn delete(&mut self, key: &K) {
-        if let Some(node) = &mut self.root {
-            self.root = node.clone().delete(key)
-        } else {
-            self.root = None
-        }
-    }
-    pub fn search(&self, key: &K) -> Option<&V> {
-        self.root.as_ref().map_or(None, |node| node.search(key))
-    }
 }
 
 impl<K: Ord + Clone + Sync + Send, V: Clone + Sync + Send> AvlTreeMap<K, V> {
@@ -42,6 +35,24 @@ impl<K: Ord + Clone + Sync + Send, V: Clone + Sync + Send> AvlTreeMap<K, V> {
             current_tree: &self.root,
         }
     }
+
+    /// Iterates in key order from the first key not less than `key`.
+    pub fn seek(&self, key: &K) -> Iter<'_, K, V> {
+        let mut prev_nodes = Vec::new();
+        let mut tree = &self.root;
+        while let Some(node) = tree {
+            if node.key >= *key {
+                prev_nodes.push(node.as_ref());
+                tree = &node.left;
+            } else {
+                tree = &node.right;
+            }
+        }
+        Iter {
+            prev_nodes,
+            current_tree: &None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -61,14 +72,14 @@ impl<'a, K: 'a + Ord + Clone + Sync + Send, V: Clone + Sync + Send> Iterator for
             match *self.current_tree {
                 None => match self.prev_nodes.pop() {
                     None => return None,
-                    Some(ref prev_node) => {
+                    Some(prev_node) => {
                         self.current_tree = &prev_node.right;
                         return Some((&prev_node.key, &prev_node.value));
                     }
                 },
                 Some(ref current_node) => {
                     if current_node.left.is_some() {
-                        self.prev_nodes.push(&current_node);
+                        self.prev_nodes.push(current_node);
                         self.current_tree = &current_node.left;
                         continue;
                     }
@@ -99,13 +110,12 @@ impl<K: Ord + Clone + Sync + Send, V: Clone + Sync + Send> FromIterator<(K, V)>
 #[cfg(test)]
 mod tests {
     use crate::avl::AvlTreeMap;
-    use crate::memtable::Memtable;
 
     #[test]
     fn iter() {
         let mut map = AvlTreeMap::new();
 
-        for i in (1..4 as usize).rev() {
+        for i in (1..4_usize).rev() {
             map.insert(i, i + 1);
         }
 
@@ -115,4 +125,16 @@ mod tests {
         assert_eq!(iter.next(), Some((&3, &4)));
         assert_eq!(iter.next(), None);
     }
+
+    #[test]
+    fn seek() {
+        let map: AvlTreeMap<usize, usize> = (0..20).map(|i| (i * 2, i)).collect();
+        assert_eq!(
+            map.seek(&7).map(|(k, _)| *k).collect::<Vec<_>>(),
+            (8..40).step_by(2).collect::<Vec<_>>()
+        );
+        assert_eq!(map.seek(&0).count(), 20);
+        assert_eq!(map.seek(&38).next(), Some((&38, &19)));
+        assert_eq!(map.seek(&39).next(), None);
+    }
 }
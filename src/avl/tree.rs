@@ -1,5 +1,4 @@
 use std::cmp::{max, Ordering};
-use std::fmt::Display;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct AvlNode<K: Ord, V>
@@ -24,76 +23,35 @@ impl<K: Ord + Clone, V: Clone> AvlNode<K, V> {
             height: 1,
         }
     }
-    pub fn insert(&mut self, key: K, value: V) {
-        match self.key.cmp(&key) {
+    /// Inserts the key and returns the value it replaced, if any.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let replaced = match self.key.cmp(&key) {
             Ordering::Less => {
                 if let Some(node) = &mut self.right {
-                    node.insert(key, value);
+                    let replaced = node.insert(key, value);
                     node.update_height();
+                    replaced
                 } else {
                     self.right = Option::from(Box::new(Self::new(key, value)));
                     self.update_height();
+                    None
                 }
             }
             Ordering::Greater => {
                 if let Some(node) = &mut self.left {
-                    node.insert(key, value);
+                    let replaced = node.insert(key, value);
                     node.update_height();
+                    replaced
                 } else {
                     self.left = Option::from(Box::new(Self::new(key, value)));
                     self.update_height();
+                    None
                 }
             }
-            Ordering::Equal => self.value = value,
-        }
+            Ordering::Equal => Some(std::mem::replace(&mut self.value, value)),
+        };
         self.rebalance();
-    }
-    pub fn delete(mut self, key: &K) -> AvlTree<K, V> {
-        match self.key.cmp(key) {
-            Ordering::Less => {
-                if let Some(right) = self.right {
-                    self.right = right.delete(key);
-                    self.rebalance();
-                    return Option::from(Box::new(self));
-                }
-            }
-            Ordering::Greater => {
-                if let Some(left) = self.left {
-                    self.left = left.delete(key);
-                    self.rebalance();
-                    return Option::from(Box::new(self));
-                }
-            }
-            Ordering::Equal => return self.delete_root(),
-        }
-        return Option::from(Box::new(self));
-    }
-    fn delete_root(self) -> AvlTree<K, V> {
-        match (self.left, self.right) {
-            (None, None) => None,
-            (Some(l), None) => Option::from(l),
-            (None, Some(r)) => Option::from(r),
-            (Some(l), Some(r)) => Option::from(Box::new(r.combine(l))),
-        }
-    }
-    fn combine(self, left: Box<AvlNode<K, V>>) -> AvlNode<K, V> {
-        let (rest, max_key, max_value) = left.delete_max();
-        let mut new_root = Self::new(max_key, max_value);
-        new_root.left = rest;
-        new_root.right = Option::from(Box::new(self));
-        new_root.rebalance();
-        new_root
-    }
-    fn delete_max(mut self) -> (AvlTree<K, V>, K, V) {
-        match self.right {
-            Some(right) => {
-                let (rest, key, value) = right.delete_max();
-                self.right = rest;
-                self.rebalance();
-                (Option::from(Box::new(self)), key, value)
-            }
-            None => (self.left, self.key, self.value),
-        }
+        replaced
     }
     fn left_height(&self) -> usize {
         self.left.as_ref().map_or(0, |l| l.height)
@@ -143,30 +101,6 @@ impl<K: Ord + Clone, V: Clone> AvlNode<K, V> {
         }
         self.update_height()
     }
-
-    pub fn search(&self, key: &K) -> Option<&V> {
-        match self.key.cmp(key) {
-            Ordering::Less => self.right.as_ref().map_or(None, |node| node.search(key)),
-            Ordering::Greater => self.left.as_ref().map_or(None, |node| node.search(key)),
-            Ordering::Equal => Option::from(&self.value),
-        }
-    }
-}
-impl<K: Ord + Display + Clone, V: Clone> AvlNode<K, V> {
-    fn print(&self) {
-        self.print_tree(0);
-    }
-    fn print_tree(&self, depth: usize) {
-        self.right
-            .as_ref()
-            .into_iter()
-            .for_each(|n| n.print_tree(depth + 1));
-        println!("{}+{}", " ".repeat(depth), self.key);
-        self.left
-            .as_ref()
-            .into_iter()
-            .for_each(|n| n.print_tree(depth + 1));
-    }
 }
 
 #[cfg(test)]
@@ -176,7 +110,7 @@ mod tests {
     #[test]
     fn insert() {
         let mut node = AvlNode::new(1, 1);
-        for i in (1..3 as usize).rev() {
+        for i in (1..3_usize).rev() {
             node.insert(i, i + 1);
         }
         assert_eq!(
@@ -200,7 +134,7 @@ mod tests {
     fn rebalance() {
         let mut node = AvlNode::new(1, 1);
 
-        for i in (2..10 as usize).rev() {
+        for i in (2..10_usize).rev() {
             node.insert(i, i);
         }
         assert_eq!(
@@ -262,117 +196,4 @@ mod tests {
             }
         );
     }
-
-    #[test]
-    fn delete() {
-        let mut node = AvlNode::new(1, 1);
-        for i in (2..10 as usize).rev() {
-            node.insert(i, i);
-        }
-        let mut new_node = *node.clone().delete(&2).unwrap();
-        new_node = *new_node.delete(&5).unwrap();
-        assert_eq!(
-            new_node,
-            AvlNode {
-                key: 6,
-                value: 6,
-                height: 3,
-                left: Some(Box::new(AvlNode {
-                    key: 3,
-                    value: 3,
-                    height: 2,
-                    left: Some(Box::new(AvlNode {
-                        key: 1,
-                        value: 1,
-                        height: 1,
-                        left: None,
-                        right: None
-                    })),
-                    right: Some(Box::new(AvlNode {
-                        key: 4,
-                        value: 4,
-                        height: 1,
-                        left: None,
-                        right: None
-                    }))
-                })),
-                right: Some(Box::new(AvlNode {
-                    key: 8,
-                    value: 8,
-                    height: 2,
-                    left: Some(Box::new(AvlNode {
-                        key: 7,
-                        value: 7,
-                        height: 1,
-                        left: None,
-                        right: None
-                    })),
-                    right: Some(Box::new(AvlNode {
-                        key: 9,
-                        value: 9,
-                        height: 1,
-                        left: None,
-                        right: None
-                    }))
-                })),
-            }
-        );
-
-        new_node = *node.clone().delete(&8).unwrap();
-        new_node = *new_node.delete(&9).unwrap();
-        assert_eq!(
-            new_node,
-            AvlNode {
-                key: 4,
-                value: 4,
-                height: 3,
-                left: Some(Box::new(AvlNode {
-                    key: 2,
-                    value: 2,
-                    height: 2,
-                    left: Some(Box::new(AvlNode {
-                        key: 1,
-                        value: 1,
-                        height: 1,
-                        left: None,
-                        right: None
-                    })),
-                    right: Some(Box::new(AvlNode {
-                        key: 3,
-                        value: 3,
-                        height: 1,
-                        left: None,
-                        right: None
-                    }))
-                })),
-                right: Some(Box::new(AvlNode {
-                    key: 6,
-                    value: 6,
-                    height: 2,
-                    left: Some(Box::new(AvlNode {
-                        key: 5,
-                        value: 5,
-                        height: 1,
-                        left: None,
-                        right: None
-                    })),
-                    right: Some(Box::new(AvlNode {
-                        key: 7,
-                        value: 7,
-                        height: 1,
-                        left: None,
-                        right: None
-                    }))
-                })),
-            }
-        );
-    }
-    fn search() {
-        let mut node = AvlNode::new(1, 1);
-        for i in (2..10 as usize).rev() {
-            node.insert(i, i);
-        }
-        assert_eq!(node.search(&1), Some(&1));
-        assert_eq!(node.search(&0), None)
-    }
 }
@@ -14,6 +14,7 @@ fn main() {
 }
 
 fn handler(stream: TcpStream) -> Result<(), Error> {
+    let mut reader = BufReader::new(&stream);
     loop {
         let mut input = String::new();
         print!(">> ");
@@ -21,40 +22,29 @@ fn handler(stream: TcpStream) -> Result<(), Error> {
 
         std::io::stdin().read_line(&mut input)?;
 
-        if input
-            .split_whitespace()
-            .next()
-            .map_or(false, |v| v == "set")
-        {
+        let command = input.split_whitespace().next().unwrap_or("").to_string();
+        if command == "set" {
             let mut body = String::new();
             std::io::stdin().read_line(&mut body)?;
-            input = input + &*body;
+            input += &*body;
         }
 
         let mut writer = BufWriter::new(&stream);
-        writer.write(input.as_bytes())?;
+        writer.write_all(input.as_bytes())?;
         writer.flush()?;
-        let mut reader = BufReader::new(&stream);
-        let mut response = String::new();
-        if input
-            .split_whitespace()
-            .next()
-            .map_or(false, |v| v == "get")
-        {
+        // get, scan, keys and stats answer with several lines terminated by END
+        let multi_line = matches!(command.as_str(), "get" | "scan" | "keys" | "stats");
+        loop {
+            let mut response = String::new();
             let nbytes = reader.read_line(&mut response)?;
             if nbytes == 0 {
                 println!("receive EOF");
-                break;
+                return Ok(());
             }
             print!("{}", response);
-            continue;
-        }
-        let nbytes = reader.read_line(&mut response)?;
-        if nbytes == 0 {
-            println!("receive EOF");
-            break;
+            if !multi_line || response.trim_end() == "END" || response.starts_with("[error]") {
+                break;
+            }
         }
-        print!("{}", response);
     }
-    return Ok(());
 }
@@ -1,93 +1,135 @@
-use anyhow::{Error, Result};
-use lsm_engine::decoder;
-use lsm_engine::executor::Executor;
-use lsm_engine::memtable::{AvlMemtable, Memtable};
-use lsm_engine::sstable::{HashMapSSTable, SSTable};
-use lsm_engine::wal::Wal;
-use std::io::{stdout, BufWriter, ErrorKind, Write};
-use std::net::{TcpListener, TcpStream};
+use anyhow::{anyhow, Context, Result};
+use lsm_engine::compression;
+use lsm_engine::db::Db;
+use lsm_engine::executor::ServerStats;
+use lsm_engine::options::{Options, ServerOptions};
+use lsm_engine::server;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use std::net::TcpListener;
 use std::path::Path;
-use std::result::Result::Ok;
-use std::sync::{Arc, RwLock};
-use std::{fs, thread};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{env, fmt, process};
 
 #[macro_use]
 extern crate log;
 
 fn main() {
     env_logger::init();
+    let started = Instant::now();
+    let (options, server_options) = match options_from_env() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            usage();
+        }
+    };
     let address = "0.0.0.0:33333";
     let listener = TcpListener::bind(address).expect("Error. failed to bind.");
     info!("Listening on {}", address);
-    let wal_path = Path::new("data/wal/wal.bin");
-    if let Some(parent) = wal_path.parent() {
-        if !parent.exists() {
-            fs::create_dir_all(parent).unwrap();
+    let flush_on_shutdown = env::var("FLUSH_ON_SHUTDOWN").is_ok_and(|v| v == "1" || v == "true");
+    let db = match Db::open(Path::new("data"), options) {
+        Ok(db) => Arc::new(db),
+        Err(e) => {
+            error!("failed to open the data directory: {:#}", e);
+            process::exit(1);
         }
-    }
-    let wal = Wal::new(wal_path).unwrap();
-    let avl_memtable = AvlMemtable::new(wal).unwrap();
-    let memtable: Arc<RwLock<Box<dyn Memtable>>> = Arc::new(RwLock::new(Box::new(avl_memtable)));
+    };
 
-    let sstable_dir = Path::new("data/sstable");
-    if !sstable_dir.exists() {
-        fs::create_dir_all(sstable_dir).unwrap();
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGTERM] {
+        signal_hook::flag::register(signal, shutdown.clone()).expect("failed to handle signals");
     }
-    let hashmap_sstable = HashMapSSTable::new(sstable_dir).unwrap();
-    let sstable: Arc<RwLock<Box<dyn SSTable>>> = Arc::new(RwLock::new(Box::new(hashmap_sstable)));
-
-    for streams in listener.incoming() {
-        match streams {
-            Err(e) => {
-                error!("listener incoming error: {}", e)
-            }
-            Ok(stream) => {
-                let memtable = memtable.clone();
-                let sstable = sstable.clone();
-                thread::spawn(move || {
-                    handler(stream, memtable, sstable)
-                        .unwrap_or_else(|error| debug!("{:?}", error));
-                });
-            }
+    let stats = Arc::new(ServerStats::new(server_options));
+    let mut clean = true;
+    let commands = match server::serve(listener, db.clone(), stats.clone(), &shutdown) {
+        Ok(commands) => commands,
+        Err(e) => {
+            error!("server failed: {:#}", e);
+            clean = false;
+            0
         }
+    };
+    if flush_on_shutdown {
+        if let Err(e) = db.flush() {
+            error!("flush on shutdown failed: {:#}", e);
+            clean = false;
+        }
+    }
+    if let Err(e) = db.sync_wal() {
+        error!("wal sync on shutdown failed: {:#}", e);
+        clean = false;
+    }
+    // the last reference, so this stops the background threads and releases the lock
+    drop(db);
+    info!(
+        "shut down after {:.1}s: {} connections, {} commands{}",
+        started.elapsed().as_secs_f64(),
+        stats.total_connections.load(Ordering::Relaxed),
+        commands,
+        if flush_on_shutdown {
+            ", memtables flushed"
+        } else {
+            ""
+        }
+    );
+    if !clean {
+        process::exit(1);
     }
 }
 
-fn handler(
-    stream: TcpStream,
-    memtable: Arc<RwLock<Box<dyn Memtable>>>,
-    sstable: Arc<RwLock<Box<dyn SSTable>>>,
-) -> Result<()> {
-    debug!("Connection from {}", stream.peer_addr()?);
-    let mut decoder = decoder::new(&stream);
-    let mut writer = BufWriter::new(&stream);
-    let mut executor = Executor::new(memtable, sstable);
-    loop {
-        let decoded = decoder.decode();
-        match decoded {
-            Ok(c) => match executor.execute(c) {
-                Ok(result) => {
-                    debug!("write response: {}", result);
-                    writer.write(format!("{}\n", result).as_bytes())?;
-                    writer.flush()?;
-                }
-                Err(e) => {
-                    let error = format!("[error] {}", e);
-                    debug!("write response: {}", error);
-                    writer.write(format!("{}\n", error).as_bytes())?;
-                    writer.flush()?;
-                }
-            },
-            Err(e) => {
-                if e.kind() == ErrorKind::UnexpectedEof {
-                    return Err(Error::from(e));
-                }
-                let error = format!("[error] {}", e);
-                debug!("write response write: {}", error);
-                writer.write(format!("{}\n", error).as_bytes())?;
-                writer.flush()?;
-            }
-        }
-        stdout().flush()?;
+fn usage() -> ! {
+    eprintln!(
+        "usage: server, configured by the environment variables\n\
+         \x20 WAL_SYNC_POLICY    always, never or periodic_<n>ms\n\
+         \x20 COMPRESSION        codec per level, e.g. none,snappy (none, snappy or lz4)\n\
+         \x20 WORKER_THREADS     number of threads running commands\n\
+         \x20 MAX_CONNECTIONS    open connections beyond which new ones are rejected\n\
+         \x20 IDLE_TIMEOUT       seconds before an idle connection is closed, 0 for never\n\
+         \x20 FLUSH_ON_SHUTDOWN  1 or true to flush the memtables on shutdown"
+    );
+    process::exit(2);
+}
+
+/// Engine and server settings, the defaults overridden by the environment variables that are
+/// set.
+fn options_from_env() -> Result<(Options, ServerOptions)> {
+    let mut options = Options::default();
+    if let Some(policy) = env_var("WAL_SYNC_POLICY")? {
+        options.wal_sync_policy = policy;
+    }
+    if let Ok(compression) = env::var("COMPRESSION") {
+        options.compression_per_level = compression::parse_per_level(&compression)
+            .with_context(|| format!("COMPRESSION={}", compression))?;
+    }
+    let mut server_options = ServerOptions::default();
+    if let Some(threads) = env_var("WORKER_THREADS")? {
+        server_options.worker_threads = threads;
+    }
+    if let Some(max) = env_var("MAX_CONNECTIONS")? {
+        server_options.max_connections = max;
+    }
+    if let Some(seconds) = env_var("IDLE_TIMEOUT")? {
+        server_options.idle_timeout = match seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        };
+    }
+    Ok((options, server_options))
+}
+
+/// The environment variable `name` parsed, if it is set.
+fn env_var<T: FromStr>(name: &str) -> Result<Option<T>>
+where
+    T::Err: fmt::Display,
+{
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow!("{}={}: {}", name, value, e)),
+        Err(_) => Ok(None),
     }
 }
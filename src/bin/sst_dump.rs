@@ -0,0 +1,73 @@
+use lsm_engine::compression;
+use lsm_engine::dump::{format_record, read_table, usage, Args, Summary};
+use std::{env, process};
+
+fn main() {
+    env_logger::init();
+    let args =
+        Args::parse(env::args().skip(1)).unwrap_or_else(|| usage("sst_dump", "sstable file"));
+    let mut failed = false;
+    for path in &args.paths {
+        let dump = match read_table(path) {
+            Ok(dump) => dump,
+            Err(e) => {
+                eprintln!("error: {:?}: {:#}", path, e);
+                failed = true;
+                continue;
+            }
+        };
+        args.info(&format!("file: {:?}", path));
+        args.info(&format!("size: {} bytes", dump.file_size));
+        match &dump.layout {
+            Ok(layout) => {
+                args.info(&format!(
+                    "format: {} version {}",
+                    layout.format, layout.version
+                ));
+                let mut codecs: Vec<(String, usize)> = vec![];
+                for (_, _, id) in &layout.blocks {
+                    let name = codec_name(*id);
+                    match codecs.iter_mut().find(|(n, _)| *n == name) {
+                        Some((_, count)) => *count += 1,
+                        None => codecs.push((name, 1)),
+                    }
+                }
+                if !layout.blocks.is_empty() {
+                    let codecs: Vec<_> = codecs
+                        .iter()
+                        .map(|(name, count)| format!("{}: {}", name, count))
+                        .collect();
+                    args.info(&format!(
+                        "blocks: {} ({})",
+                        layout.blocks.len(),
+                        codecs.join(", ")
+                    ));
+                }
+            }
+            Err(e) => args.info(&format!("format: unreadable, {}", e)),
+        }
+        for corruption in &dump.damage {
+            failed = true;
+            args.info(&format!("{}", corruption));
+        }
+
+        let mut summary = Summary::default();
+        for (key, value) in &dump.records {
+            if !args.selects(key) {
+                continue;
+            }
+            summary.add(key, value.as_ref());
+            if !args.summary_only {
+                println!("{}", format_record(None, key, value.as_ref(), args.output));
+            }
+        }
+        args.info(&summary.to_string());
+    }
+    if failed {
+        process::exit(1);
+    }
+}
+
+fn codec_name(id: u8) -> String {
+    compression::codec(id).map_or(format!("codec {}", id), |c| c.name().to_string())
+}
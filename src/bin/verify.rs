@@ -0,0 +1,64 @@
+use anyhow::Context;
+use lsm_engine::compression;
+use lsm_engine::options::Options;
+use lsm_engine::repair::{repair, verify};
+use std::path::Path;
+use std::{env, process};
+
+const USAGE: &str = "usage: verify [--repair] [data directory]";
+
+fn main() {
+    env_logger::init();
+    let mut repair_mode = false;
+    let mut dir = "data".to_string();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--repair" => repair_mode = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ if arg.starts_with('-') => {
+                eprintln!("{}", USAGE);
+                process::exit(2);
+            }
+            _ => dir = arg,
+        }
+    }
+    let dir = Path::new(&dir);
+    // checked before anything is done, as a bad value is a usage error
+    let mut options = Options::default();
+    if let Ok(compression) = env::var("COMPRESSION") {
+        options.compression_per_level = run(compression::parse_per_level(&compression)
+            .with_context(|| format!("COMPRESSION={}", compression)));
+    }
+
+    let report = run(verify(dir));
+    print!("{}", report);
+    if report.is_ok() {
+        println!("no problems found");
+        return;
+    }
+    if !repair_mode {
+        println!("problems found, run with --repair to fix them");
+        process::exit(1);
+    }
+
+    for action in run(repair(dir, &options)) {
+        println!("repair: {}", action);
+    }
+    let report = run(verify(dir));
+    print!("{}", report);
+    if !report.is_ok() {
+        println!("problems remain after repair");
+        process::exit(1);
+    }
+    println!("repaired");
+}
+
+fn run<T>(result: anyhow::Result<T>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("error: {:#}", e);
+        process::exit(2);
+    })
+}
@@ -0,0 +1,49 @@
+use lsm_engine::dump::{format_record, read_wal, usage, Args, Summary};
+use std::{env, process};
+
+fn main() {
+    env_logger::init();
+    let args = Args::parse(env::args().skip(1)).unwrap_or_else(|| usage("wal_dump", "wal file"));
+    let mut failed = false;
+    for path in &args.paths {
+        let dump = match read_wal(path) {
+            Ok(dump) => dump,
+            Err(e) => {
+                eprintln!("error: {:?}: {:#}", path, e);
+                failed = true;
+                continue;
+            }
+        };
+        args.info(&format!("file: {:?}", path));
+        args.info(&format!("size: {} bytes", dump.file_size));
+        args.info(&match dump.format_version {
+            0 => "format: legacy".to_string(),
+            version => format!("format: segment version {}", version),
+        });
+        if dump.dropped_bytes > 0 {
+            failed = true;
+            args.info(&format!(
+                "skipped {} bytes of corrupt records",
+                dump.dropped_bytes
+            ));
+        }
+
+        let mut summary = Summary::default();
+        for (column_family, key, value) in &dump.records {
+            if !args.selects(key) {
+                continue;
+            }
+            summary.add(key, value.as_ref());
+            if !args.summary_only {
+                println!(
+                    "{}",
+                    format_record(Some(*column_family), key, value.as_ref(), args.output)
+                );
+            }
+        }
+        args.info(&summary.to_string());
+    }
+    if failed {
+        process::exit(1);
+    }
+}
@@ -0,0 +1,244 @@
+use crate::key::{InternalKey, ValueType};
+use crate::record::{get_value, put_value};
+use crate::value::Value;
+use crate::varint::{get_bytes, get_varint, put_varint};
+use anyhow::{bail, Result};
+use std::convert::{TryFrom, TryInto};
+use std::mem::size_of;
+
+/// Entries from one restart point to the next; the user key at a restart point is stored in
+/// full.
+const RESTART_INTERVAL: usize = 16;
+
+/// Builds a block of entries added in internal key order, laid out as
+/// `entry* | restart offset(u32)* | restart count(u32)`.
+///
+/// An entry is `shared | unshared | key suffix | tag`, followed for a value by the value as
+/// written by `record::put_value`, integers as varints. Its user key is the first `shared`
+/// bytes of the previous entry's followed by the `unshared` bytes of the suffix, so keys with
+/// long common prefixes take little space.
+pub struct BlockBuilder {
+    buffer: Vec<u8>,
+    restarts: Vec<u32>,
+    last_key: String,
+    entries: usize,
+}
+
+impl BlockBuilder {
+    pub fn new() -> Self {
+        Self {
+            buffer: vec![],
+            restarts: vec![],
+            last_key: String::new(),
+            entries: 0,
+        }
+    }
+
+    pub fn add(&mut self, key: &InternalKey, value: Option<&Value>) {
+        let shared = if self.entries.is_multiple_of(RESTART_INTERVAL) {
+            self.restarts.push(self.buffer.len() as u32);
+            0
+        } else {
+            shared_prefix_len(&self.last_key, &key.user_key)
+        };
+        let suffix = &key.user_key.as_bytes()[shared..];
+        put_varint(&mut self.buffer, shared as u64);
+        put_varint(&mut self.buffer, suffix.len() as u64);
+        self.buffer.extend(suffix);
+        put_varint(&mut self.buffer, key.tag());
+        if let Some(value) = value {
+            put_value(&mut self.buffer, value);
+        }
+        self.last_key.clone_from(&key.user_key);
+        self.entries += 1;
+    }
+
+    /// Size of the block if finished now.
+    pub fn len(&self) -> usize {
+        self.buffer.len() + (self.restarts.len() + 1) * size_of::<u32>()
+    }
+
+    /// Returns the block and empties the builder for the next one.
+    pub fn finish(&mut self) -> Vec<u8> {
+        let mut block = std::mem::take(&mut self.buffer);
+        for restart in &self.restarts {
+            block.extend(&restart.to_le_bytes());
+        }
+        block.extend(&(self.restarts.len() as u32).to_le_bytes());
+        self.restarts.clear();
+        self.last_key.clear();
+        self.entries = 0;
+        block
+    }
+}
+
+/// Length of the longest common prefix of the keys that ends on a character boundary.
+fn shared_prefix_len(a: &str, b: &str) -> usize {
+    let mut len = a.bytes().zip(b.bytes()).take_while(|(a, b)| a == b).count();
+    while !b.is_char_boundary(len) {
+        len -= 1;
+    }
+    len
+}
+
+/// A block written by `BlockBuilder`.
+pub struct Block<'a> {
+    entries: &'a [u8],
+    restarts: Vec<usize>,
+}
+
+impl<'a> Block<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        let count_offset = match data.len().checked_sub(size_of::<u32>()) {
+            Some(offset) => offset,
+            None => bail!("block shorter than its restart count"),
+        };
+        let count = u32::from_le_bytes(data[count_offset..].try_into()?) as usize;
+        let restarts_offset = match count
+            .checked_mul(size_of::<u32>())
+            .and_then(|len| count_offset.checked_sub(len))
+        {
+            Some(offset) => offset,
+            None => bail!("invalid restart count {}", count),
+        };
+        let restarts: Vec<usize> = data[restarts_offset..count_offset]
+            .chunks(size_of::<u32>())
+            .map(|restart| u32::from_le_bytes(restart.try_into().unwrap()) as usize)
+            .collect();
+        if restarts.iter().any(|restart| *restart >= restarts_offset) {
+            bail!("invalid restart point");
+        }
+        Ok(Self {
+            entries: &data[..restarts_offset],
+            restarts,
+        })
+    }
+
+    /// Decodes the entry at `offset` following an entry with the user key `previous`, returning
+    /// it with the offset of the next one.
+    fn entry(&self, offset: usize, previous: &str) -> Result<(InternalKey, Option<Value>, usize)> {
+        let mut index = offset;
+        let shared = usize::try_from(get_varint(self.entries, &mut index)?)?;
+        let unshared = usize::try_from(get_varint(self.entries, &mut index)?)?;
+        let prefix = match previous.as_bytes().get(..shared) {
+            Some(prefix) => prefix,
+            None => bail!(
+                "entry at {} shares {} bytes of {:?}",
+                offset,
+                shared,
+                previous
+            ),
+        };
+        let mut user_key = prefix.to_vec();
+        user_key.extend(get_bytes(self.entries, &mut index, unshared)?);
+        let user_key = String::from_utf8(user_key)?;
+        let tag = get_varint(self.entries, &mut index)?;
+        let key = match InternalKey::from_tag(user_key, tag) {
+            Some(key) => key,
+            None => bail!("invalid entry tag {} at {}", tag, offset),
+        };
+        let value = match key.value_type {
+            ValueType::Value => Some(get_value(self.entries, &mut index)?),
+            ValueType::Deletion => None,
+        };
+        Ok((key, value, index))
+    }
+
+    /// Appends every entry, in order.
+    pub fn read_all(&self, records: &mut Vec<(InternalKey, Option<Value>)>) -> Result<()> {
+        let mut offset = 0;
+        let mut previous = String::new();
+        while offset < self.entries.len() {
+            let (key, value, next) = self.entry(offset, &previous)?;
+            previous.clone_from(&key.user_key);
+            records.push((key, value));
+            offset = next;
+        }
+        Ok(())
+    }
+
+    /// The first entry at or after `target`: a binary search over the restart points finds the
+    /// last one before `target`, from which the entries are scanned.
+    pub fn seek(&self, target: &InternalKey) -> Result<Option<(InternalKey, Option<Value>)>> {
+        // restart points before `left` have keys before `target`, those from `right` do not
+        let (mut left, mut right) = (0, self.restarts.len());
+        while left < right {
+            let middle = (left + right) / 2;
+            let (key, _, _) = self.entry(self.restarts[middle], "")?;
+            if key < *target {
+                left = middle + 1;
+            } else {
+                right = middle;
+            }
+        }
+        let mut offset = match left.checked_sub(1) {
+            Some(restart) => self.restarts[restart],
+            None => 0,
+        };
+        let mut previous = String::new();
+        while offset < self.entries.len() {
+            let (key, value, next) = self.entry(offset, &previous)?;
+            if key >= *target {
+                return Ok(Some((key, value)));
+            }
+            previous = key.user_key;
+            offset = next;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::block::{Block, BlockBuilder};
+    use crate::key::{InternalKey, ValueType, MAX_SEQUENCE};
+    use crate::value::Value;
+
+    #[test]
+    fn build_and_seek() {
+        let value = Value::new("value".to_string(), 1, 2);
+        let keys: Vec<_> = (0..100)
+            .flat_map(|i| {
+                let user_key = format!("tenant:123:user:{:04}", i * 2);
+                vec![
+                    InternalKey::new(user_key.clone(), 200 + i, ValueType::Deletion),
+                    InternalKey::new(user_key, 100 + i, ValueType::Value),
+                ]
+            })
+            .collect();
+        let mut builder = BlockBuilder::new();
+        let mut full_keys = 0;
+        for key in &keys {
+            let value = (key.value_type == ValueType::Value).then_some(&value);
+            builder.add(key, value);
+            full_keys += key.user_key.len();
+        }
+        let data = builder.finish();
+        // shared prefixes leave mostly the differing digits
+        assert!(data.len() < full_keys / 2 + keys.len() * 10);
+
+        let block = Block::new(&data).unwrap();
+        let mut records = vec![];
+        block.read_all(&mut records).unwrap();
+        let decoded: Vec<_> = records.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(decoded, keys);
+        assert_eq!(records[1].1, Some(value.clone()));
+
+        for (i, key) in keys.iter().enumerate() {
+            assert_eq!(block.seek(key).unwrap().unwrap().0, *key, "{}", i);
+        }
+        // an absent key seeks to the next one, a key past the end to nothing
+        let absent = InternalKey::for_seek("tenant:123:user:0051", MAX_SEQUENCE);
+        assert_eq!(block.seek(&absent).unwrap().unwrap().0, keys[52]);
+        let older = InternalKey::for_seek("tenant:123:user:0010", 150);
+        assert_eq!(block.seek(&older).unwrap().unwrap().0, keys[11]);
+        let past = InternalKey::for_seek("tenant:123:user:9999", MAX_SEQUENCE);
+        assert!(block.seek(&past).unwrap().is_none());
+
+        assert!(Block::new(&data[..data.len() - 1]).is_err());
+        let mut corrupted = data.clone();
+        let count_offset = data.len() - 4;
+        corrupted[count_offset..].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(Block::new(&corrupted).is_err());
+    }
+}
@@ -0,0 +1,34 @@
+/// Name of the column family that exists in every database and cannot be dropped.
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+/// Handle to a column family: a keyspace with its own memtables, SSTables and settings, sharing
+/// the WAL, and so atomic batches and sequence numbers, with the others.
+///
+/// Obtained from `Db::create_column_family` or `Db::column_family`; operations through a
+/// handle fail once its column family is dropped.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnFamily {
+    id: u32,
+    name: String,
+}
+
+impl ColumnFamily {
+    pub(crate) fn new(id: u32, name: String) -> Self {
+        Self { id, name }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl Default for ColumnFamily {
+    /// The default column family.
+    fn default() -> Self {
+        Self::new(0, DEFAULT_COLUMN_FAMILY.to_string())
+    }
+}
@@ -1,11 +1,50 @@
-use crate::command::Command::{Delete, Get, Set, Stats};
+use crate::command::Command::{
+    CreateKeyspace, Delete, Discard, DropKeyspace, Exec, Get, Keys, Multi, Release, Scan, Set,
+    Snapshot, Stats, Use,
+};
 use crate::value::Value;
 
 pub enum Command {
-    Set { key: String, value: Value },
-    Get { key: String },
-    Delete { key: String },
-    Stats {},
+    Set {
+        key: String,
+        value: Value,
+    },
+    Get {
+        key: String,
+    },
+    Delete {
+        key: String,
+    },
+    Scan {
+        start: String,
+        end: String,
+        limit: usize,
+    },
+    /// Lists keys starting with `prefix`, continuing after `cursor` if given.
+    Keys {
+        prefix: String,
+        limit: usize,
+        cursor: Option<String>,
+    },
+    Stats {
+        group: Option<String>,
+    },
+    Snapshot,
+    Release,
+    /// Starts queueing sets and deletes into a batch, applied atomically by `Exec`.
+    Multi,
+    Exec,
+    Discard,
+    /// Selects the keyspace, i.e. column family, the following commands of the connection use.
+    Use {
+        name: String,
+    },
+    CreateKeyspace {
+        name: String,
+    },
+    DropKeyspace {
+        name: String,
+    },
 }
 
 impl Command {
@@ -18,7 +57,41 @@ impl Command {
     pub fn new_delete(key: String) -> Self {
         Delete { key }
     }
-    pub fn new_stats() -> Self {
-        Stats {}
+    pub fn new_scan(start: String, end: String, limit: usize) -> Self {
+        Scan { start, end, limit }
+    }
+    pub fn new_keys(prefix: String, limit: usize, cursor: Option<String>) -> Self {
+        Keys {
+            prefix,
+            limit,
+            cursor,
+        }
+    }
+    pub fn new_stats(group: Option<String>) -> Self {
+        Stats { group }
+    }
+    pub fn new_snapshot() -> Self {
+        Snapshot
+    }
+    pub fn new_release() -> Self {
+        Release
+    }
+    pub fn new_multi() -> Self {
+        Multi
+    }
+    pub fn new_exec() -> Self {
+        Exec
+    }
+    pub fn new_discard() -> Self {
+        Discard
+    }
+    pub fn new_use(name: String) -> Self {
+        Use { name }
+    }
+    pub fn new_create_keyspace(name: String) -> Self {
+        CreateKeyspace { name }
+    }
+    pub fn new_drop_keyspace(name: String) -> Self {
+        DropKeyspace { name }
     }
 }
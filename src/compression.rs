@@ -0,0 +1,157 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Compresses SSTable blocks. Each block records the id of the codec it was written with, so
+/// files written with different codecs stay readable.
+pub trait Codec: Sync + Send {
+    fn id(&self) -> u8;
+    /// Name of the codec, as given in `Options::compression_per_level`.
+    fn name(&self) -> &'static str;
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>>;
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>>;
+}
+
+struct NoCompression;
+
+impl Codec for NoCompression {
+    fn id(&self) -> u8 {
+        0
+    }
+
+    fn name(&self) -> &'static str {
+        "none"
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(data.to_vec())
+    }
+}
+
+struct Snappy;
+
+impl Codec for Snappy {
+    fn id(&self) -> u8 {
+        1
+    }
+
+    fn name(&self) -> &'static str {
+        "snappy"
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(snap::raw::Encoder::new().compress_vec(data)?)
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(snap::raw::Decoder::new().decompress_vec(data)?)
+    }
+}
+
+struct Lz4;
+
+impl Codec for Lz4 {
+    fn id(&self) -> u8 {
+        2
+    }
+
+    fn name(&self) -> &'static str {
+        "lz4"
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(lz4_flex::block::compress_prepend_size(data))
+    }
+
+    fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(lz4_flex::block::decompress_size_prepended(data)?)
+    }
+}
+
+const CODECS: [&dyn Codec; 3] = [&NoCompression, &Snappy, &Lz4];
+
+/// The codec a block trailer names.
+pub fn codec(id: u8) -> Option<&'static dyn Codec> {
+    CODECS.get(id as usize).copied()
+}
+
+/// Block compression of the tables of one level, see `Options::compression_per_level`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    Snappy,
+    Lz4,
+}
+
+impl Compression {
+    pub fn codec(&self) -> &'static dyn Codec {
+        match self {
+            Compression::None => &NoCompression,
+            Compression::Snappy => &Snappy,
+            Compression::Lz4 => &Lz4,
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.codec().name())
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "snappy" => Ok(Compression::Snappy),
+            "lz4" => Ok(Compression::Lz4),
+            _ => Err(anyhow!("invalid compression: {}", s)),
+        }
+    }
+}
+
+/// Parses `Options::compression_per_level` from a comma-separated list, e.g. `none,snappy`.
+pub fn parse_per_level(list: &str) -> Result<Vec<Compression>> {
+    list.split(',').map(str::parse).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{codec, parse_per_level, Compression};
+
+    #[test]
+    fn round_trip() {
+        let data = "{\"name\":\"value\"}".repeat(100);
+        for compression in [Compression::None, Compression::Snappy, Compression::Lz4] {
+            let codec = codec(compression.codec().id()).unwrap();
+            let compressed = codec.compress(data.as_bytes()).unwrap();
+            if compression != Compression::None {
+                assert!(compressed.len() < data.len() / 4);
+            }
+            assert_eq!(codec.decompress(&compressed).unwrap(), data.as_bytes());
+            assert_eq!(
+                compression.to_string().parse::<Compression>().unwrap(),
+                compression
+            );
+        }
+        assert_eq!(codec(2).map(|c| c.name()), Some("lz4"));
+        assert!(codec(3).is_none());
+    }
+
+    #[test]
+    fn per_level() {
+        assert_eq!(
+            parse_per_level("none,snappy,lz4").unwrap(),
+            vec![Compression::None, Compression::Snappy, Compression::Lz4]
+        );
+        for invalid in ["", "none,", "zstd", "none, snappy"] {
+            assert!(parse_per_level(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
@@ -0,0 +1,1599 @@
+use crate::column_family::{ColumnFamily, DEFAULT_COLUMN_FAMILY};
+use crate::iterator::{MergingIterator, SortedRun};
+use crate::key::{InternalKey, MAX_SEQUENCE};
+use crate::lock::DirLock;
+use crate::manifest::{ColumnFamilyVersion, Manifest, VersionEdit};
+use crate::memtable::{AvlMemtable, Memtable};
+use crate::options::{ColumnFamilyOptions, CompactionStyle, Options, ReadOptions, WalSyncPolicy};
+use crate::snapshot::{drop_hidden_versions, Snapshot, SnapshotList};
+use crate::sstable::{prepare_dir, remove_tables, HashMapSSTable, SSTable};
+use crate::table::Corruption;
+use crate::transaction::{Conflict, Transaction};
+use crate::value::Value;
+use crate::wal::{list_logs, remove_obsolete_logs, GroupCommit, Wal};
+use crate::write_batch::WriteBatch;
+use anyhow::{anyhow, bail, Result};
+use log::{error, info, warn};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Subdirectory of the data directory holding the WAL segments.
+pub(crate) const WAL_DIR: &str = "wal";
+/// Subdirectory of the data directory holding the table files.
+pub(crate) const SSTABLE_DIR: &str = "sstable";
+
+struct Memtables {
+    active: Arc<dyn Memtable>,
+    /// Memtables waiting to be flushed, oldest first.
+    immutables: VecDeque<Arc<dyn Memtable>>,
+}
+
+/// The memtables and tables of one column family.
+struct ColumnFamilyData {
+    handle: ColumnFamily,
+    options: ColumnFamilyOptions,
+    memtables: RwLock<Memtables>,
+    sstable: Box<dyn SSTable>,
+    /// Set once dropped, so that a flush already picked up writes no table for it.
+    dropped: AtomicBool,
+}
+
+impl ColumnFamilyData {
+    /// Looks the key up in the active memtable, then the immutable ones, newest first, then
+    /// the SSTables. Returns the sequence and value of the newest version up to `sequence`.
+    fn search(&self, key: &str, sequence: u64) -> Result<Option<(u64, Option<Value>)>> {
+        {
+            let memtables = self.memtables.read().unwrap();
+            if let Some(version) = memtables.active.search(key, sequence) {
+                return Ok(Some(version));
+            }
+            for memtable in memtables.immutables.iter().rev() {
+                if let Some(version) = memtable.search(key, sequence) {
+                    return Ok(Some(version));
+                }
+            }
+        }
+        self.sstable.search(key, sequence)
+    }
+
+    /// Iterates over the live keys within `range` as of `sequence`, see `Db::range`.
+    fn range<R: RangeBounds<String>>(&self, sequence: u64, range: R) -> DbIterator {
+        let mut runs: Vec<Arc<dyn SortedRun>> = vec![];
+        {
+            let memtables = self.memtables.read().unwrap();
+            runs.push(memtables.active.clone());
+            for memtable in memtables.immutables.iter().rev() {
+                runs.push(memtable.clone());
+            }
+        }
+        // taken after the memtables, so a memtable flushed in between is found in the tables
+        runs.extend(self.sstable.runs());
+        let mut iter = DbIterator {
+            merged: MergingIterator::new(runs),
+            sequence,
+            end: range.end_bound().cloned(),
+            skip: None,
+        };
+        match range.start_bound() {
+            Bound::Included(start) => iter.seek(start),
+            Bound::Excluded(start) => {
+                iter.seek(start);
+                iter.skip = Some(start.clone());
+            }
+            Bound::Unbounded => iter.seek(""),
+        }
+        iter
+    }
+
+    /// First WAL segment holding versions of this column family not yet in a table.
+    fn log_number(&self) -> u64 {
+        let memtables = self.memtables.read().unwrap();
+        memtables
+            .immutables
+            .front()
+            .map_or(memtables.active.log_number(), |m| m.log_number())
+    }
+}
+
+#[derive(Default)]
+struct FlushState {
+    shutdown: bool,
+    /// First failure of a flush, WAL sync or WAL write. Once set, flushes stop and writes
+    /// fail until the database is reopened.
+    error: Option<String>,
+}
+
+/// A batch waiting for group commit, with the versions read by its transaction, if any, as
+/// `(column family, key, sequence)`: the batch is rejected if one of the keys has a newer
+/// version.
+struct PendingWrite {
+    batch: WriteBatch,
+    reads: Vec<(u32, String, u64)>,
+}
+
+struct Shared {
+    options: Options,
+    wal_dir: PathBuf,
+    sstable_dir: PathBuf,
+    manifest: Arc<Mutex<Manifest>>,
+    /// Current WAL segment, shared by all column families and written by the group commit
+    /// leader.
+    wal: Mutex<Wal>,
+    /// Sequence number of the last write applied to the memtables.
+    last_sequence: AtomicU64,
+    snapshots: Mutex<SnapshotList>,
+    group_commit: GroupCommit<PendingWrite>,
+    column_families: RwLock<BTreeMap<u32, Arc<ColumnFamilyData>>>,
+    /// Held while a column family's tables are written or dropped.
+    table_work: Mutex<()>,
+    flush_state: Mutex<FlushState>,
+    /// Signalled when a memtable becomes immutable or on shutdown.
+    flush_requested: Condvar,
+    /// Signalled when an immutable memtable is flushed or flushing fails.
+    flush_done: Condvar,
+    /// Signalled on shutdown to stop the periodic WAL sync.
+    sync_stop: Condvar,
+    /// Held until the last user of the directory is gone.
+    _lock: DirLock,
+}
+
+/// Storage engine: column families, each with an active memtable, immutable memtables being
+/// flushed in the background and SSTables, sharing one WAL, all under one data directory.
+pub struct Db {
+    shared: Arc<Shared>,
+    flush_thread: Option<JoinHandle<()>>,
+    sync_thread: Option<JoinHandle<()>>,
+}
+
+impl Db {
+    pub fn open(dir: &Path, options: Options) -> Result<Self> {
+        let wal_dir = dir.join(WAL_DIR);
+        let sstable_dir = dir.join(SSTABLE_DIR);
+        fs::create_dir_all(dir)?;
+        let lock = DirLock::acquire(dir)?;
+        fs::create_dir_all(&wal_dir)?;
+        fs::create_dir_all(&sstable_dir)?;
+        let manifest = Arc::new(Mutex::new(Manifest::open(dir)?));
+        let (families, log_number, mut last_sequence) = {
+            let mut manifest = manifest.lock().unwrap();
+            prepare_dir(&sstable_dir, &mut manifest)?;
+            (
+                manifest.column_families().clone(),
+                manifest.log_number(),
+                manifest.last_sequence(),
+            )
+        };
+        let family_options = |family: &ColumnFamilyVersion| {
+            family
+                .options
+                .clone()
+                .unwrap_or_else(|| options.column_family_options())
+        };
+
+        let mut immutables: HashMap<u32, VecDeque<Arc<dyn Memtable>>> = HashMap::new();
+        // consecutive segments are replayed into one memtable per column family until it is full
+        let mut recovering: HashMap<u32, AvlMemtable> = HashMap::new();
+        for number in list_logs(&wal_dir, log_number)? {
+            manifest.lock().unwrap().mark_file_number_used(number);
+            let wal = Wal::open(&wal_dir, number, options.wal_sync_policy)?;
+            for (id, mut key, value) in wal.recover(options.wal_recovery_mode)?.records {
+                if key.sequence == 0 {
+                    // logged before sequence numbers existed
+                    key.sequence = last_sequence + 1;
+                }
+                last_sequence = last_sequence.max(key.sequence);
+                // skip versions of dropped column families and those already in a table
+                if families.get(&id).is_none_or(|f| number < f.log_number) {
+                    continue;
+                }
+                recovering
+                    .entry(id)
+                    .or_insert_with(|| AvlMemtable::new(number))
+                    .add(key, value);
+            }
+            for (id, family) in &families {
+                let full = recovering.get(id).is_some_and(|m| {
+                    m.approximate_size() >= family_options(family).write_buffer_size
+                });
+                if full {
+                    let memtable = recovering.remove(id).unwrap();
+                    immutables
+                        .entry(*id)
+                        .or_default()
+                        .push_back(Arc::new(memtable));
+                }
+            }
+        }
+        for (id, memtable) in recovering {
+            immutables
+                .entry(id)
+                .or_default()
+                .push_back(Arc::new(memtable));
+        }
+        let active_wal = {
+            let mut manifest = manifest.lock().unwrap();
+            let number = manifest.new_file_number();
+            for id in families.keys().filter(|id| !immutables.contains_key(id)) {
+                manifest.log_and_apply(VersionEdit {
+                    column_family: *id,
+                    log_number: Some(number),
+                    ..VersionEdit::default()
+                })?;
+            }
+            Wal::open(&wal_dir, number, options.wal_sync_policy)?
+        };
+        remove_obsolete_logs(
+            &wal_dir,
+            manifest.lock().unwrap().log_number(),
+            options.wal_archive_retention,
+        )?;
+        info!(
+            "recovered {} memtables to flush, last sequence {}",
+            immutables.values().map(VecDeque::len).sum::<usize>(),
+            last_sequence
+        );
+
+        let mut column_families = BTreeMap::new();
+        for (id, family) in &families {
+            let sstable = HashMapSSTable::new(&sstable_dir, manifest.clone(), *id, &options)?;
+            column_families.insert(
+                *id,
+                Arc::new(ColumnFamilyData {
+                    handle: ColumnFamily::new(*id, family.name.clone()),
+                    options: family_options(family),
+                    memtables: RwLock::new(Memtables {
+                        active: Arc::new(AvlMemtable::new(active_wal.number())),
+                        immutables: immutables.remove(id).unwrap_or_default(),
+                    }),
+                    sstable: Box::new(sstable),
+                    dropped: AtomicBool::new(false),
+                }),
+            );
+        }
+        let shared = Arc::new(Shared {
+            options,
+            wal_dir,
+            sstable_dir,
+            manifest,
+            wal: Mutex::new(active_wal),
+            last_sequence: AtomicU64::new(last_sequence),
+            snapshots: Mutex::new(SnapshotList::default()),
+            group_commit: GroupCommit::new(),
+            column_families: RwLock::new(column_families),
+            table_work: Mutex::new(()),
+            flush_state: Mutex::new(FlushState::default()),
+            flush_requested: Condvar::new(),
+            flush_done: Condvar::new(),
+            sync_stop: Condvar::new(),
+            _lock: lock,
+        });
+        let flush_shared = shared.clone();
+        let flush_thread = thread::Builder::new()
+            .name("flush".to_string())
+            .spawn(move || flush_shared.flush_loop())?;
+        let sync_thread = match shared.options.wal_sync_policy {
+            WalSyncPolicy::Periodic { interval_ms } => {
+                let sync_shared = shared.clone();
+                Some(
+                    thread::Builder::new()
+                        .name("wal-sync".to_string())
+                        .spawn(move || sync_shared.sync_loop(Duration::from_millis(interval_ms)))?,
+                )
+            }
+            _ => None,
+        };
+        Ok(Self {
+            shared,
+            flush_thread: Some(flush_thread),
+            sync_thread,
+        })
+    }
+
+    pub fn options(&self) -> &Options {
+        &self.shared.options
+    }
+
+    /// Creates an empty column family with its own memtables, tables and settings.
+    pub fn create_column_family(
+        &self,
+        name: &str,
+        options: ColumnFamilyOptions,
+    ) -> Result<ColumnFamily> {
+        // versions of the new id can only be in this segment or later ones
+        let log_number = self.shared.wal.lock().unwrap().number();
+        let mut families = self.shared.column_families.write().unwrap();
+        if families.values().any(|f| f.handle.name() == name) {
+            bail!("column family {} already exists", name);
+        }
+        let id = {
+            let mut manifest = self.shared.manifest.lock().unwrap();
+            let id = manifest.new_column_family_id();
+            manifest.log_and_apply(VersionEdit {
+                column_family: id,
+                add_column_family: Some((name.to_string(), options.clone())),
+                log_number: Some(log_number),
+                ..VersionEdit::default()
+            })?;
+            id
+        };
+        let sstable = HashMapSSTable::new(
+            &self.shared.sstable_dir,
+            self.shared.manifest.clone(),
+            id,
+            &self.shared.options,
+        )?;
+        let handle = ColumnFamily::new(id, name.to_string());
+        families.insert(
+            id,
+            Arc::new(ColumnFamilyData {
+                handle: handle.clone(),
+                options,
+                memtables: RwLock::new(Memtables {
+                    active: Arc::new(AvlMemtable::new(log_number)),
+                    immutables: VecDeque::new(),
+                }),
+                sstable: Box::new(sstable),
+                dropped: AtomicBool::new(false),
+            }),
+        );
+        info!("create column family {} with id {}", name, id);
+        Ok(handle)
+    }
+
+    /// Drops the column family and deletes its tables; its unflushed versions are discarded.
+    pub fn drop_column_family(&self, name: &str) -> Result<()> {
+        if name == DEFAULT_COLUMN_FAMILY {
+            bail!("cannot drop the default column family");
+        }
+        let _work = self.shared.table_work.lock().unwrap();
+        let (family, tables) = {
+            let mut families = self.shared.column_families.write().unwrap();
+            let id = families
+                .values()
+                .find(|f| f.handle.name() == name)
+                .map(|f| f.handle.id())
+                .ok_or_else(|| anyhow!("column family {} does not exist", name))?;
+            let mut manifest = self.shared.manifest.lock().unwrap();
+            let tables = manifest.tables(id).to_vec();
+            manifest.log_and_apply(VersionEdit {
+                column_family: id,
+                drop_column_family: true,
+                ..VersionEdit::default()
+            })?;
+            (families.remove(&id).unwrap(), tables)
+        };
+        family.dropped.store(true, Ordering::Release);
+        family.memtables.write().unwrap().immutables.clear();
+        remove_tables(&self.shared.sstable_dir, &tables)?;
+        // wake writers stalled on its immutable memtables
+        let _state = self.shared.flush_state.lock().unwrap();
+        self.shared.flush_done.notify_all();
+        info!("drop column family {}", name);
+        Ok(())
+    }
+
+    /// Handle to the live column family named `name`.
+    pub fn column_family(&self, name: &str) -> Option<ColumnFamily> {
+        self.shared
+            .column_families
+            .read()
+            .unwrap()
+            .values()
+            .find(|f| f.handle.name() == name)
+            .map(|f| f.handle.clone())
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<Value>> {
+        self.get_with_options(&ReadOptions::default(), key)
+    }
+
+    /// Reads the key, ignoring versions newer than the snapshot if one is given.
+    pub fn get_with_options(&self, options: &ReadOptions, key: &str) -> Result<Option<Value>> {
+        self.get_cf(options, &ColumnFamily::default(), key)
+    }
+
+    /// Reads the key from the column family, see `get_with_options`.
+    pub fn get_cf(
+        &self,
+        options: &ReadOptions,
+        column_family: &ColumnFamily,
+        key: &str,
+    ) -> Result<Option<Value>> {
+        let sequence = options.snapshot.map_or(MAX_SEQUENCE, Snapshot::sequence);
+        Ok(self
+            .shared
+            .column_family(column_family)?
+            .search(key, sequence)?
+            .and_then(|(_, value)| value))
+    }
+
+    /// Iterates over the live keys in order, see `range`.
+    pub fn iter(&self, options: &ReadOptions) -> DbIterator {
+        self.range(options, ..)
+    }
+
+    /// Iterates over the live keys within `range` in order, as of the snapshot if one is given
+    /// and otherwise as of the call.
+    ///
+    /// The iterator merges the memtables and SSTables it was created with and reads them
+    /// lazily, so it neither blocks writers nor sees versions written after it was created.
+    pub fn range<R: RangeBounds<String>>(&self, options: &ReadOptions, range: R) -> DbIterator {
+        self.shared
+            .default_family()
+            .range(self.shared.read_sequence(options), range)
+    }
+
+    /// Iterates over the live keys of the column family within `range`, see `range`.
+    pub fn range_cf<R: RangeBounds<String>>(
+        &self,
+        options: &ReadOptions,
+        column_family: &ColumnFamily,
+        range: R,
+    ) -> Result<DbIterator> {
+        Ok(self
+            .shared
+            .column_family(column_family)?
+            .range(self.shared.read_sequence(options), range))
+    }
+
+    /// Iterates over the live keys starting with `prefix` in order, see `range`.
+    pub fn prefix_iter(&self, options: &ReadOptions, prefix: &str) -> DbIterator {
+        self.range(options, prefix_range(prefix))
+    }
+
+    /// Iterates over the live keys of the column family starting with `prefix`, see `range`.
+    pub fn prefix_iter_cf(
+        &self,
+        options: &ReadOptions,
+        column_family: &ColumnFamily,
+        prefix: &str,
+    ) -> Result<DbIterator> {
+        self.range_cf(options, column_family, prefix_range(prefix))
+    }
+
+    /// Pins the current state for reads until `release_snapshot`.
+    pub fn snapshot(&self) -> Snapshot {
+        // loaded under the lock, so that a flush or compaction reading the snapshots either
+        // sees this one or ran before its sequence was current
+        let mut snapshots = self.shared.snapshots.lock().unwrap();
+        let sequence = self.shared.last_sequence.load(Ordering::Acquire);
+        snapshots.acquire(sequence)
+    }
+
+    pub fn release_snapshot(&self, snapshot: Snapshot) {
+        self.shared.snapshots.lock().unwrap().release(snapshot);
+    }
+
+    pub fn put(&self, key: String, value: Value) -> Result<()> {
+        self.put_cf(&ColumnFamily::default(), key, value)
+    }
+
+    pub fn delete(&self, key: &str) -> Result<()> {
+        self.delete_cf(&ColumnFamily::default(), key)
+    }
+
+    pub fn put_cf(&self, column_family: &ColumnFamily, key: String, value: Value) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_cf(column_family, key, value);
+        self.write(batch)
+    }
+
+    pub fn delete_cf(&self, column_family: &ColumnFamily, key: &str) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_cf(column_family, key.to_string());
+        self.write(batch)
+    }
+
+    /// Applies the batch atomically: after a crash either all or none of it is recovered.
+    pub fn write(&self, batch: WriteBatch) -> Result<()> {
+        self.shared.write(PendingWrite {
+            batch,
+            reads: vec![],
+        })
+    }
+
+    pub fn begin_transaction(&self) -> Transaction<'_> {
+        Transaction::new(self)
+    }
+
+    /// Sequence and value of the newest version of the key.
+    pub(crate) fn get_version(
+        &self,
+        column_family: &ColumnFamily,
+        key: &str,
+    ) -> Result<Option<(u64, Option<Value>)>> {
+        self.shared
+            .column_family(column_family)?
+            .search(key, MAX_SEQUENCE)
+    }
+
+    /// Writes the batch unless a key in `reads` has a version newer than the sequence read.
+    pub(crate) fn commit_transaction(
+        &self,
+        batch: WriteBatch,
+        reads: Vec<(u32, String, u64)>,
+    ) -> Result<()> {
+        self.shared.write(PendingWrite { batch, reads })
+    }
+
+    /// Turns the non-empty active memtables immutable and waits until every immutable
+    /// memtable is written to a table, so that nothing is left to replay from the WAL.
+    pub fn flush(&self) -> Result<()> {
+        let families: Vec<_> = self
+            .shared
+            .column_families
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect();
+        for family in families {
+            if !family.memtables.read().unwrap().active.is_empty() {
+                self.shared.switch_memtable(&family)?;
+            }
+        }
+        let mut state = self.shared.flush_state.lock().unwrap();
+        self.shared.flush_requested.notify_one();
+        loop {
+            if let Some(e) = &state.error {
+                bail!("background flush failed: {}", e);
+            }
+            if self.shared.next_to_flush().is_none() {
+                return Ok(());
+            }
+            state = self.shared.flush_done.wait(state).unwrap();
+        }
+    }
+
+    /// Fsyncs the current WAL segment, whatever the sync policy.
+    pub fn sync_wal(&self) -> Result<()> {
+        let file = self.shared.wal.lock().unwrap().sync_handle();
+        file.sync_data()?;
+        Ok(())
+    }
+
+    /// Number of live items held in memtables, over all column families.
+    pub fn memtable_items(&self) -> usize {
+        let families = self.shared.column_families.read().unwrap();
+        families
+            .values()
+            .map(|family| {
+                let memtables = family.memtables.read().unwrap();
+                memtables.active.live_keys()
+                    + memtables
+                        .immutables
+                        .iter()
+                        .map(|m| m.live_keys())
+                        .sum::<usize>()
+            })
+            .sum()
+    }
+}
+
+/// Smallest string greater than every string starting with `prefix`, if there is one.
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    while let Some(last) = chars.pop() {
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    None
+}
+
+/// Range of the strings starting with `prefix`.
+fn prefix_range(prefix: &str) -> (Bound<String>, Bound<String>) {
+    let end = match prefix_successor(prefix) {
+        Some(successor) => Bound::Excluded(successor),
+        None => Bound::Unbounded,
+    };
+    (Bound::Included(prefix.to_string()), end)
+}
+
+/// Newest live version of each key in key order, see `Db::range`.
+pub struct DbIterator {
+    merged: MergingIterator,
+    /// Versions with a higher sequence are ignored.
+    sequence: u64,
+    end: Bound<String>,
+    /// User key whose remaining, older versions are skipped.
+    skip: Option<String>,
+}
+
+impl DbIterator {
+    /// Repositions at the first key not less than `key`; the end bound still applies.
+    pub fn seek(&mut self, key: &str) {
+        self.merged.seek(&InternalKey::for_seek(key, self.sequence));
+        self.skip = None;
+    }
+
+    /// The error that ended the iteration early, e.g. a damaged table block.
+    pub fn status(&self) -> Result<(), Corruption> {
+        self.merged.status()
+    }
+}
+
+impl Iterator for DbIterator {
+    type Item = (String, Value);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let key = self.merged.key()?;
+            let past_end = match &self.end {
+                Bound::Included(end) => key.user_key > *end,
+                Bound::Excluded(end) => key.user_key >= *end,
+                Bound::Unbounded => false,
+            };
+            if past_end {
+                return None;
+            }
+            if key.sequence > self.sequence || self.skip.as_ref() == Some(&key.user_key) {
+                self.merged.next();
+                continue;
+            }
+            let user_key = key.user_key.clone();
+            let value = self.merged.value().cloned();
+            self.skip = Some(user_key.clone());
+            self.merged.next();
+            // a deletion hides the older versions and the key
+            if let Some(value) = value {
+                return Some((user_key, value));
+            }
+        }
+    }
+}
+
+impl Drop for Db {
+    fn drop(&mut self) {
+        self.shared.flush_state.lock().unwrap().shutdown = true;
+        self.shared.flush_requested.notify_one();
+        self.shared.sync_stop.notify_one();
+        for handle in self
+            .flush_thread
+            .take()
+            .into_iter()
+            .chain(self.sync_thread.take())
+        {
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Shared {
+    fn family(&self, id: u32) -> Option<Arc<ColumnFamilyData>> {
+        self.column_families.read().unwrap().get(&id).cloned()
+    }
+
+    fn column_family(&self, handle: &ColumnFamily) -> Result<Arc<ColumnFamilyData>> {
+        self.family(handle.id())
+            .ok_or_else(|| anyhow!("column family {} does not exist", handle.name()))
+    }
+
+    fn default_family(&self) -> Arc<ColumnFamilyData> {
+        self.column_families.read().unwrap()[&0].clone()
+    }
+
+    /// Sequence an iterator reads as of: the snapshot's if one is given, otherwise the latest.
+    fn read_sequence(&self, options: &ReadOptions) -> u64 {
+        options.snapshot.map_or_else(
+            || self.last_sequence.load(Ordering::Acquire),
+            Snapshot::sequence,
+        )
+    }
+
+    /// Logs the batch to the WAL and applies it to the active memtables, merged into one batch
+    /// with those of concurrent writers. The operations get consecutive sequence numbers.
+    fn write(&self, write: PendingWrite) -> Result<()> {
+        if write.batch.is_empty() && write.reads.is_empty() {
+            return Ok(());
+        }
+        self.group_commit.commit(write, |writes| {
+            if let Some(e) = &self.flush_state.lock().unwrap().error {
+                bail!("write rejected after an earlier failure: {}", e);
+            }
+            let mut group = WriteBatch::new();
+            let mut families = BTreeMap::new();
+            let mut written = HashSet::new();
+            // validated in commit order, so a write earlier in the group counts as a conflict
+            let results: Vec<Result<()>> = writes
+                .into_iter()
+                .map(|write| {
+                    let family = |id| {
+                        self.family(id)
+                            .ok_or_else(|| anyhow!("column family {} does not exist", id))
+                    };
+                    for (id, key, sequence) in &write.reads {
+                        let family = family(*id)?;
+                        let current = family.search(key, MAX_SEQUENCE)?.map_or(0, |(s, _)| s);
+                        if written.contains(&(*id, key.clone())) || current > *sequence {
+                            return Err(Conflict { key: key.clone() }.into());
+                        }
+                    }
+                    let ids: BTreeSet<u32> = write.batch.keys().map(|(id, _)| id).collect();
+                    let batch_families = ids
+                        .into_iter()
+                        .map(|id| Ok((id, family(id)?)))
+                        .collect::<Result<Vec<_>>>()?;
+                    families.extend(batch_families);
+                    written.extend(write.batch.keys().map(|(id, key)| (id, key.clone())));
+                    group.append(write.batch);
+                    Ok(())
+                })
+                .collect();
+            if group.is_empty() {
+                return Ok(results);
+            }
+            for family in families.values() {
+                self.make_room_for_write(family)?;
+            }
+            let first_sequence = self.last_sequence.load(Ordering::Acquire) + 1;
+            let last_sequence = first_sequence + group.len() as u64 - 1;
+            if last_sequence > MAX_SEQUENCE {
+                bail!("sequence numbers exhausted");
+            }
+            let records = group.into_records(first_sequence);
+            // the segment is rotated only once the versions are in the memtables, as rotating
+            // moves column families with an empty memtable to the new segment
+            let mut wal = self.wal.lock().unwrap();
+            if let Err(e) = wal.write(&records) {
+                // the segment may now end in a partial record, where recovery stops reading,
+                // so no later write may be acknowledged; its sequences are not used either
+                error!("wal write failed: {:?}", e);
+                self.flush_state.lock().unwrap().error = Some(format!("wal write failed: {}", e));
+                self.flush_done.notify_all();
+                return Err(e);
+            }
+            let mut by_family: BTreeMap<u32, Vec<_>> = BTreeMap::new();
+            for (id, key, value) in records {
+                by_family.entry(id).or_default().push((key, value));
+            }
+            for (id, records) in by_family {
+                families[&id]
+                    .memtables
+                    .read()
+                    .unwrap()
+                    .active
+                    .add_all(records);
+            }
+            self.last_sequence.store(last_sequence, Ordering::Release);
+            if wal.size() >= self.options.wal_segment_size {
+                self.rotate_wal(&mut wal)?;
+            }
+            Ok(results)
+        })
+    }
+
+    /// Switches the column family to a new memtable once the active one is full, first
+    /// stalling while too many of its immutable memtables are waiting to be flushed. Only
+    /// called by the group commit leader.
+    fn make_room_for_write(&self, family: &ColumnFamilyData) -> Result<()> {
+        let mut state = self.flush_state.lock().unwrap();
+        loop {
+            if let Some(e) = &state.error {
+                return Err(anyhow!("background flush failed: {}", e));
+            }
+            let memtables = family.memtables.read().unwrap();
+            if memtables.active.approximate_size() < family.options.write_buffer_size {
+                return Ok(());
+            }
+            if memtables.immutables.len() < self.options.max_immutable_memtables {
+                break;
+            }
+            warn!(
+                "stall write: {} memtables of column family {} waiting for flush",
+                memtables.immutables.len(),
+                family.handle.name()
+            );
+            drop(memtables);
+            state = self.flush_done.wait(state).unwrap();
+        }
+        drop(state);
+        self.switch_memtable(family)?;
+        let _state = self.flush_state.lock().unwrap();
+        self.flush_requested.notify_one();
+        Ok(())
+    }
+
+    /// Replaces the current WAL segment with a new one and returns its number. The old segment
+    /// is fsynced first unless syncing is disabled, as the periodic sync only covers the current
+    /// one.
+    ///
+    /// Column families with an empty active memtable get a new one on the new segment, so that
+    /// idle ones do not keep old segments from being retired.
+    fn rotate_wal(&self, wal: &mut Wal) -> Result<u64> {
+        if self.options.wal_sync_policy != WalSyncPolicy::Never {
+            wal.sync_handle().sync_data()?;
+        }
+        let number = self.manifest.lock().unwrap().new_file_number();
+        *wal = Wal::open(&self.wal_dir, number, self.options.wal_sync_policy)?;
+        for family in self.column_families.read().unwrap().values() {
+            let mut memtables = family.memtables.write().unwrap();
+            if memtables.active.is_empty() {
+                memtables.active = Arc::new(AvlMemtable::new(number));
+            }
+        }
+        info!("rotate to wal segment {}", number);
+        Ok(number)
+    }
+
+    /// Turns the column family's active memtable immutable and starts a new one on a fresh
+    /// WAL segment.
+    fn switch_memtable(&self, family: &ColumnFamilyData) -> Result<()> {
+        let number = self.rotate_wal(&mut self.wal.lock().unwrap())?;
+        let mut memtables = family.memtables.write().unwrap();
+        let immutable =
+            std::mem::replace(&mut memtables.active, Arc::new(AvlMemtable::new(number)));
+        info!(
+            "switch column family {} to memtable with wal {}",
+            family.handle.name(),
+            number
+        );
+        memtables.immutables.push_back(immutable);
+        Ok(())
+    }
+
+    /// Column family whose oldest immutable memtable is on the oldest WAL segment, if any.
+    fn next_to_flush(&self) -> Option<Arc<ColumnFamilyData>> {
+        self.column_families
+            .read()
+            .unwrap()
+            .values()
+            .filter_map(|family| {
+                let memtables = family.memtables.read().unwrap();
+                let log_number = memtables.immutables.front()?.log_number();
+                Some((log_number, family.clone()))
+            })
+            .min_by_key(|(log_number, _)| *log_number)
+            .map(|(_, family)| family)
+    }
+
+    fn flush_loop(&self) {
+        loop {
+            let family = {
+                let mut state = self.flush_state.lock().unwrap();
+                loop {
+                    if state.shutdown || state.error.is_some() {
+                        return;
+                    }
+                    if let Some(family) = self.next_to_flush() {
+                        break family;
+                    }
+                    state = self.flush_requested.wait(state).unwrap();
+                }
+            };
+            let result = self.flush(&family);
+            let mut state = self.flush_state.lock().unwrap();
+            if let Err(e) = result {
+                error!("flush failed: {:?}", e);
+                state.error = Some(e.to_string());
+            }
+            self.flush_done.notify_all();
+        }
+    }
+
+    /// Fsyncs the active WAL every `interval` until shutdown.
+    fn sync_loop(&self, interval: Duration) {
+        loop {
+            {
+                let mut state = self.flush_state.lock().unwrap();
+                if !state.shutdown {
+                    state = self.sync_stop.wait_timeout(state, interval).unwrap().0;
+                }
+                if state.shutdown || state.error.is_some() {
+                    return;
+                }
+            }
+            let file = self.wal.lock().unwrap().sync_handle();
+            if let Err(e) = file.sync_data() {
+                error!("wal sync failed: {:?}", e);
+                self.flush_state.lock().unwrap().error = Some(e.to_string());
+                self.flush_done.notify_all();
+            }
+        }
+    }
+
+    /// Writes the column family's oldest immutable memtable to a table.
+    fn flush(&self, family: &ColumnFamilyData) -> Result<()> {
+        let _work = self.table_work.lock().unwrap();
+        if family.dropped.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        // once this memtable is in a table, the oldest WAL the column family still needs is
+        // the next memtable's
+        let (memtable, log_number) = {
+            let memtables = family.memtables.read().unwrap();
+            let memtable = match memtables.immutables.front() {
+                Some(memtable) => memtable.clone(),
+                None => return Ok(()),
+            };
+            let log_number = memtables
+                .immutables
+                .get(1)
+                .map_or(memtables.active.log_number(), |m| m.log_number());
+            (memtable, log_number)
+        };
+        // the new table and the WAL switch are recorded in one MANIFEST edit, so a crash at any
+        // point either keeps the old WAL or the new table
+        let snapshots = self.snapshots.lock().unwrap().sequences();
+        let records = memtable.to_records();
+        let records = drop_hidden_versions(
+            records
+                .iter()
+                .map(|(key, value)| (key, value.as_ref()))
+                .collect(),
+            &snapshots,
+            false,
+        );
+        family.sstable.create(
+            records,
+            VersionEdit {
+                log_number: Some(log_number),
+                last_sequence: Some(self.last_sequence.load(Ordering::Acquire)),
+                ..VersionEdit::default()
+            },
+        )?;
+        family.memtables.write().unwrap().immutables.pop_front();
+        // the WAL is shared, so a segment is retired once no column family needs it
+        let oldest_log = self
+            .column_families
+            .read()
+            .unwrap()
+            .values()
+            .map(|f| f.log_number())
+            .min()
+            .unwrap_or(log_number);
+        remove_obsolete_logs(
+            &self.wal_dir,
+            oldest_log,
+            self.options.wal_archive_retention,
+        )?;
+        let options = &family.options;
+        let mut expired = match options.ttl {
+            Some(ttl) => family.sstable.expired_tables(ttl)?,
+            None => 0,
+        };
+        if options.compaction_style == CompactionStyle::Fifo {
+            let count = family.sstable.table_count();
+            expired = expired.max(count.saturating_sub(options.compaction_trigger));
+        }
+        if expired > 0 {
+            family.sstable.drop_oldest(expired)?;
+        }
+        // compaction also rewrites tables of older format versions
+        let merge = options.compaction_style == CompactionStyle::Merge
+            && family.sstable.table_count() >= options.compaction_trigger;
+        if merge || family.sstable.needs_upgrade() {
+            let snapshots = self.snapshots.lock().unwrap().sequences();
+            family.sstable.compact(&snapshots)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::column_family::DEFAULT_COLUMN_FAMILY;
+    use crate::compression::Compression;
+    use crate::db::{prefix_successor, Db, DbIterator};
+    use crate::key::{InternalKey, ValueType};
+    use crate::options::{
+        ColumnFamilyOptions, CompactionStyle, Options, ReadOptions, WalSyncPolicy,
+    };
+    use crate::record::encode_legacy;
+    use crate::sstable::write_table_file;
+    use crate::table::{format_version, layout, Corruption, FORMAT_VERSION};
+    use crate::value::Value;
+    use crate::write_batch::WriteBatch;
+    use std::fs::{create_dir_all, read, read_dir, remove_dir_all, write};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn flush_and_recover() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_flush_and_recover");
+        let _ = remove_dir_all(&dir);
+
+        let options = Options {
+            write_buffer_size: 1024,
+            wal_sync_policy: WalSyncPolicy::Periodic { interval_ms: 10 },
+            ..Options::default()
+        };
+        let db = Db::open(&dir, options.clone()).unwrap();
+        for i in 0..100 {
+            db.put(format!("key{}", i), Value::new(format!("value{}", i), 0, 0))
+                .unwrap();
+        }
+        db.delete("key3").unwrap();
+        let mut batch = WriteBatch::new();
+        batch.delete("key4".to_string());
+        batch.put("key5".to_string(), Value::new("batch".to_string(), 0, 0));
+        db.write(batch).unwrap();
+        assert!(Db::open(&dir, options.clone()).is_err());
+        drop(db);
+
+        let db = Db::open(&dir, options.clone()).unwrap();
+        for i in 0..100 {
+            let key = format!("key{}", i);
+            let value = db.get(&key).unwrap().map(|v| v.to_string(key.clone()));
+            let expected = match i {
+                3 | 4 => None,
+                5 => Some(Value::new("batch".to_string(), 0, 0).to_string(key)),
+                _ => Some(Value::new(format!("value{}", i), 0, 0).to_string(key)),
+            };
+            assert_eq!(value, expected);
+        }
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flush_before_shutdown() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_flush_before_shutdown");
+        let _ = remove_dir_all(&dir);
+
+        let db = Db::open(&dir, Options::default()).unwrap();
+        let family = db
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        db.put("key".to_string(), Value::new("value".to_string(), 0, 0))
+            .unwrap();
+        db.put_cf(
+            &family,
+            "user".to_string(),
+            Value::new("name".to_string(), 0, 0),
+        )
+        .unwrap();
+        db.flush().unwrap();
+        db.sync_wal().unwrap();
+        assert_eq!(db.memtable_items(), 0);
+        assert_eq!(read_dir(dir.join("sstable")).unwrap().count(), 2);
+        drop(db);
+
+        // nothing left to replay
+        let db = Db::open(&dir, Options::default()).unwrap();
+        assert_eq!(db.memtable_items(), 0);
+        let family = db.column_family("users").unwrap();
+        assert!(db.get("key").unwrap().is_some());
+        assert!(db
+            .get_cf(&ReadOptions::default(), &family, "user")
+            .unwrap()
+            .is_some());
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn wal_sync_policies() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_wal_sync_policies");
+        for policy in [
+            WalSyncPolicy::Always,
+            WalSyncPolicy::Periodic { interval_ms: 1 },
+            WalSyncPolicy::Never,
+        ] {
+            let _ = remove_dir_all(&dir);
+            let options = Options {
+                wal_sync_policy: policy,
+                ..Options::default()
+            };
+            let db = Db::open(&dir, options.clone()).unwrap();
+            // only the periodic policy syncs in the background
+            assert_eq!(
+                db.sync_thread.is_some(),
+                matches!(policy, WalSyncPolicy::Periodic { .. })
+            );
+            db.put("key".to_string(), Value::new(policy.to_string(), 0, 0))
+                .unwrap();
+            drop(db);
+
+            let db = Db::open(&dir, options).unwrap();
+            assert_eq!(
+                db.get("key").unwrap(),
+                Some(Value::new(policy.to_string(), 0, 0))
+            );
+            drop(db);
+        }
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_wal_write() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_failed_wal_write");
+        let _ = remove_dir_all(&dir);
+        let value = |data: &str| Value::new(data.to_string(), 0, 0);
+
+        let db = Db::open(&dir, Options::default()).unwrap();
+        db.put("key".to_string(), value("old")).unwrap();
+        db.shared.wal.lock().unwrap().fail_next_write = true;
+        assert!(db.put("torn".to_string(), value("torn")).is_err());
+        // appended after the partial record, this write would be lost on recovery
+        assert!(db.put("after".to_string(), value("after")).is_err());
+        assert!(db.flush().is_err());
+        drop(db);
+
+        let db = Db::open(&dir, Options::default()).unwrap();
+        assert_eq!(db.get("torn").unwrap(), None);
+        assert_eq!(db.get("after").unwrap(), None);
+        db.put("key".to_string(), value("new")).unwrap();
+        assert_eq!(db.get("key").unwrap(), Some(value("new")));
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn failed_group_commit() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_failed_group_commit");
+        let _ = remove_dir_all(&dir);
+
+        let db = Arc::new(Db::open(&dir, Options::default()).unwrap());
+        let put = |i: usize| {
+            let db = db.clone();
+            thread::spawn(move || {
+                db.put(format!("key{}", i), Value::new(i.to_string(), 0, 0))
+                    .is_err()
+            })
+        };
+        let handles = {
+            let mut wal = db.shared.wal.lock().unwrap();
+            wal.fail_next_write = true;
+            // the first leader waits for the WAL with its group, the others queue up behind it
+            // as the next group, one leading and the rest following
+            let mut handles = vec![put(0)];
+            thread::sleep(Duration::from_millis(50));
+            handles.extend((1..8).map(put));
+            thread::sleep(Duration::from_millis(50));
+            handles
+        };
+        for handle in handles {
+            assert!(handle.join().unwrap());
+        }
+        drop(Arc::try_unwrap(db).ok().unwrap());
+
+        let db = Db::open(&dir, Options::default()).unwrap();
+        assert_eq!(db.iter(&ReadOptions::default()).count(), 0);
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leftover_flush_files() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_leftover_flush_files");
+        let _ = remove_dir_all(&dir);
+
+        let db = Db::open(&dir, Options::default()).unwrap();
+        db.put("key".to_string(), Value::new("logged".to_string(), 0, 0))
+            .unwrap();
+        drop(db);
+        // a crash before the rename leaves a temporary file, one before the MANIFEST edit a
+        // table that is not referenced
+        let sstable_dir = dir.join("sstable");
+        write(sstable_dir.join("00050.bin.tmp"), b"partial").unwrap();
+        let key = InternalKey::new("key".to_string(), 100, ValueType::Value);
+        let value = Value::new("unreferenced".to_string(), 0, 0);
+        write_table_file(&sstable_dir, 51, &[(&key, Some(&value))], Compression::None).unwrap();
+
+        let db = Db::open(&dir, Options::default()).unwrap();
+        assert_eq!(read_dir(&sstable_dir).unwrap().count(), 0);
+        assert_eq!(
+            db.get("key").unwrap(),
+            Some(Value::new("logged".to_string(), 0, 0))
+        );
+        db.flush().unwrap();
+        drop(db);
+
+        let db = Db::open(&dir, Options::default()).unwrap();
+        assert_eq!(db.memtable_items(), 0);
+        assert_eq!(
+            db.get("key").unwrap(),
+            Some(Value::new("logged".to_string(), 0, 0))
+        );
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotate_and_archive_wal() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_rotate_and_archive_wal");
+        let _ = remove_dir_all(&dir);
+
+        let options = Options {
+            write_buffer_size: 4096,
+            wal_segment_size: 256,
+            wal_archive_retention: Some(3),
+            ..Options::default()
+        };
+        let db = Db::open(&dir, options.clone()).unwrap();
+        for i in 0..20 {
+            db.put(format!("key{}", i), Value::new(format!("value{}", i), 0, 0))
+                .unwrap();
+        }
+        drop(db);
+        let segments = read_dir(dir.join("wal"))
+            .unwrap()
+            .filter(|e| e.as_ref().unwrap().path().is_file())
+            .count();
+        assert!(segments > 1);
+
+        // recovery replays every segment; once flushed they move to the bounded archive
+        let db = Db::open(&dir, options.clone()).unwrap();
+        for i in 20..200 {
+            db.put(format!("key{}", i), Value::new(format!("value{}", i), 0, 0))
+                .unwrap();
+        }
+        for i in 0..200 {
+            let key = format!("key{}", i);
+            let value = db.get(&key).unwrap().map(|v| v.to_string(key.clone()));
+            assert_eq!(
+                value,
+                Some(Value::new(format!("value{}", i), 0, 0).to_string(key))
+            );
+        }
+        drop(db);
+        let archived = read_dir(dir.join("wal").join("archive")).unwrap().count();
+        assert_eq!(archived, 3);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_survives_compaction() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_snapshot_survives_compaction");
+        let _ = remove_dir_all(&dir);
+
+        let options = Options {
+            write_buffer_size: 1024,
+            compaction_trigger: 2,
+            ..Options::default()
+        };
+        let db = Db::open(&dir, options).unwrap();
+        let value = |i| Value::new(format!("value{}", i), 0, 0);
+        for i in 0..10 {
+            db.put(format!("key{}", i), value(i)).unwrap();
+        }
+        let snapshot = db.snapshot();
+        let read_options = ReadOptions {
+            snapshot: Some(&snapshot),
+        };
+        db.delete("key0").unwrap();
+        for round in 1..50 {
+            for i in 1..10 {
+                db.put(format!("key{}", i), value(round * 10 + i)).unwrap();
+            }
+        }
+        db.put("key10".to_string(), value(10)).unwrap();
+
+        let formatted = |v: Option<Value>| v.map(|v| v.to_string(String::new()));
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            assert_eq!(
+                formatted(db.get_with_options(&read_options, &key).unwrap()),
+                formatted(Some(value(i)))
+            );
+        }
+        assert_eq!(formatted(db.get("key0").unwrap()), None);
+        assert_eq!(
+            formatted(db.get("key1").unwrap()),
+            formatted(Some(value(491)))
+        );
+
+        let keys = |options| db.iter(&options).map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(keys(read_options).len(), 10);
+        assert_eq!(keys(ReadOptions::default()).first().unwrap(), "key1");
+        assert!(keys(ReadOptions::default()).contains(&"key10".to_string()));
+        db.release_snapshot(snapshot);
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn range_scan() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_range_scan");
+        let _ = remove_dir_all(&dir);
+
+        let options = Options {
+            write_buffer_size: 512,
+            ..Options::default()
+        };
+        let db = Db::open(&dir, options).unwrap();
+        for i in (0..100).rev() {
+            db.put(format!("key{:03}", i), Value::new(format!("{}", i), 0, 0))
+                .unwrap();
+        }
+        for i in (0..100).step_by(3) {
+            db.delete(&format!("key{:03}", i)).unwrap();
+        }
+        db.put("key010".to_string(), Value::new("new".to_string(), 0, 0))
+            .unwrap();
+
+        let read_options = ReadOptions::default();
+        let keys = |iter: DbIterator| iter.map(|(k, _)| k).collect::<Vec<_>>();
+        assert_eq!(
+            keys(db.range(&read_options, "key005".to_string().."key012".to_string())),
+            vec!["key005", "key007", "key008", "key010", "key011"]
+        );
+        assert_eq!(
+            keys(db.range(&read_options, "key095".to_string()..)),
+            vec!["key095", "key097", "key098"]
+        );
+        assert_eq!(db.iter(&read_options).count(), 66);
+
+        let mut iter = db.range(&read_options, .."key050".to_string());
+        iter.seek("key010");
+        let (key, value) = iter.next().unwrap();
+        assert_eq!(value.to_string(key), "VALUE key010 0 0 3\nnew\n");
+        iter.seek("key048");
+        assert_eq!(keys(iter), vec!["key049"]);
+
+        assert_eq!(keys(db.prefix_iter(&read_options, "key09")).len(), 6);
+        assert_eq!(
+            keys(db.prefix_iter(&read_options, "key1")),
+            Vec::<String>::new()
+        );
+        assert_eq!(prefix_successor("a\u{d7ff}"), Some("a\u{e000}".to_string()));
+        assert_eq!(prefix_successor("\u{10ffff}"), None);
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn column_families() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_column_families");
+        let _ = remove_dir_all(&dir);
+        let value = |data: &str| Some(Value::new(data.to_string(), 0, 0));
+        let options = Options {
+            write_buffer_size: 1024,
+            ..Options::default()
+        };
+        let small = ColumnFamilyOptions {
+            write_buffer_size: 256,
+            compaction_trigger: 2,
+            ..ColumnFamilyOptions::default()
+        };
+
+        let db = Db::open(&dir, options.clone()).unwrap();
+        let users = db.create_column_family("users", small.clone()).unwrap();
+        let logs = db
+            .create_column_family("logs", ColumnFamilyOptions::default())
+            .unwrap();
+        assert!(db.create_column_family("users", small.clone()).is_err());
+        assert!(db.drop_column_family(DEFAULT_COLUMN_FAMILY).is_err());
+
+        let mut batch = WriteBatch::new();
+        batch.put("a".to_string(), Value::new("default".to_string(), 0, 0));
+        batch.put_cf(
+            &users,
+            "a".to_string(),
+            Value::new("user".to_string(), 0, 0),
+        );
+        batch.put_cf(&logs, "a".to_string(), Value::new("log".to_string(), 0, 0));
+        db.write(batch).unwrap();
+        // enough to flush and compact users several times while logs stays in its memtable
+        for i in 0..100 {
+            db.put_cf(
+                &users,
+                format!("user{:03}", i),
+                Value::new(i.to_string(), 0, 0),
+            )
+            .unwrap();
+        }
+        db.delete_cf(&logs, "missing").unwrap();
+        drop(db);
+
+        let db = Db::open(&dir, options.clone()).unwrap();
+        let users = db.column_family("users").unwrap();
+        let logs = db.column_family("logs").unwrap();
+        let read_options = ReadOptions::default();
+        assert_eq!(db.get("a").unwrap(), value("default"));
+        assert_eq!(
+            db.get_cf(&read_options, &users, "a").unwrap(),
+            value("user")
+        );
+        assert_eq!(db.get_cf(&read_options, &logs, "a").unwrap(), value("log"));
+        assert_eq!(db.get("user001").unwrap(), None);
+        assert_eq!(
+            db.prefix_iter_cf(&read_options, &users, "user")
+                .unwrap()
+                .count(),
+            100
+        );
+        assert_eq!(db.iter(&read_options).count(), 1);
+
+        db.drop_column_family("users").unwrap();
+        assert!(db.get_cf(&read_options, &users, "a").is_err());
+        assert!(db
+            .put_cf(&users, "b".to_string(), Value::new("b".to_string(), 0, 0))
+            .is_err());
+        let again = db.create_column_family("users", small).unwrap();
+        assert_ne!(again.id(), users.id());
+        assert_eq!(db.get_cf(&read_options, &again, "a").unwrap(), None);
+        drop(db);
+
+        let db = Db::open(&dir, options).unwrap();
+        let again = db.column_family("users").unwrap();
+        assert_eq!(db.get_cf(&read_options, &again, "a").unwrap(), None);
+        assert_eq!(db.get_cf(&read_options, &logs, "a").unwrap(), value("log"));
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn compaction_styles_and_ttl() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_compaction_styles_and_ttl");
+        let _ = remove_dir_all(&dir);
+        let merged = ColumnFamilyOptions {
+            compaction_trigger: 2,
+            ..ColumnFamilyOptions::default()
+        };
+        let fifo = ColumnFamilyOptions {
+            compaction_style: CompactionStyle::Fifo,
+            ..merged.clone()
+        };
+        let expiring = ColumnFamilyOptions {
+            compaction_trigger: 100,
+            ttl: Some(Duration::from_secs(1)),
+            ..ColumnFamilyOptions::default()
+        };
+
+        let db = Db::open(&dir, Options::default()).unwrap();
+        let families = [
+            db.create_column_family("merged", merged).unwrap(),
+            db.create_column_family("fifo", fifo).unwrap(),
+            db.create_column_family("expiring", expiring).unwrap(),
+        ];
+        // one table per family and round
+        for round in 0..4 {
+            for family in &families {
+                db.put_cf(
+                    family,
+                    format!("key{}", round),
+                    Value::new(round.to_string(), 0, 0),
+                )
+                .unwrap();
+            }
+            db.flush().unwrap();
+        }
+        drop(db);
+
+        let db = Db::open(&dir, Options::default()).unwrap();
+        let keys = |name: &str| {
+            let family = db.column_family(name).unwrap();
+            db.range_cf(&ReadOptions::default(), &family, ..)
+                .unwrap()
+                .map(|(key, _)| key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys("merged"), vec!["key0", "key1", "key2", "key3"]);
+        // the oldest tables beyond the trigger are gone with their keys
+        assert_eq!(keys("fifo"), vec!["key2", "key3"]);
+        assert_eq!(keys("expiring"), vec!["key0", "key1", "key2", "key3"]);
+
+        thread::sleep(Duration::from_millis(1100));
+        let expiring = db.column_family("expiring").unwrap();
+        db.put_cf(
+            &expiring,
+            "late".to_string(),
+            Value::new("late".to_string(), 0, 0),
+        )
+        .unwrap();
+        db.flush().unwrap();
+        assert_eq!(keys("expiring"), vec!["late"]);
+        assert_eq!(keys("merged").len(), 4);
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn corrupted_table() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_corrupted_table");
+        let _ = remove_dir_all(&dir);
+        let options = Options {
+            write_buffer_size: 1024,
+            compaction_trigger: 100,
+            ..Options::default()
+        };
+        let db = Db::open(&dir, options.clone()).unwrap();
+        for i in 0..100 {
+            db.put(format!("key{}", i), Value::new(format!("value{}", i), 0, 0))
+                .unwrap();
+        }
+        drop(db);
+
+        let path = read_dir(dir.join("sstable"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .find(|p| p.extension().is_some_and(|e| e == "bin"))
+            .unwrap();
+        // a flipped bit in a value of the first block, which follows the 12 byte header
+        let mut binary = read(&path).unwrap();
+        let position = binary.windows(5).position(|w| w == b"value").unwrap();
+        binary[position + 1] ^= 1;
+        write(&path, &binary).unwrap();
+        let error = Db::open(&dir, options.clone()).err().unwrap();
+        let corruption = error.downcast_ref::<Corruption>().unwrap();
+        assert_eq!(corruption.file, path);
+        assert_eq!(corruption.offset, 12);
+
+        let options = Options {
+            verify_checksums: false,
+            ..options
+        };
+        assert!(Db::open(&dir, options.clone()).is_ok());
+
+        // a block that does not decode fails the reads that reach it
+        let (offset, size, _) = layout(&binary).unwrap().blocks[0];
+        let count_offset = (offset + size) as usize - 4 - 1 - 4;
+        binary[count_offset..count_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        write(&path, &binary).unwrap();
+        let db = Db::open(&dir, options).unwrap();
+        let key = (0..100)
+            .map(|i| format!("key{}", i))
+            .find(|key| db.get(key).is_err())
+            .unwrap();
+        let error = db.get(&key).unwrap_err();
+        let corruption = error.downcast_ref::<Corruption>().unwrap();
+        assert_eq!((&corruption.file, corruption.offset), (&path, offset));
+        let mut iter = db.iter(&ReadOptions::default());
+        assert_eq!(iter.by_ref().count(), 0);
+        assert_eq!(iter.status().unwrap_err().offset, offset);
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn upgrade_legacy_files() {
+        let dir = std::env::temp_dir().join("lsm_engine_db_upgrade_legacy_files");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(dir.join("sstable")).unwrap();
+        create_dir_all(dir.join("wal")).unwrap();
+        // a table and a WAL as written before format versions and the MANIFEST
+        let mut table = Vec::new();
+        let mut wal = Vec::new();
+        for i in 0..10 {
+            let key = InternalKey::new(format!("key{}", i), 0, ValueType::Value);
+            let record = encode_legacy(&key, Some(&Value::new(format!("old{}", i), 0, 0)));
+            let target = if i < 5 { &mut table } else { &mut wal };
+            target.extend(&record);
+            target.extend(&(record.len() as i32).to_le_bytes());
+        }
+        write(dir.join("sstable").join("00001.bin"), &table).unwrap();
+        write(dir.join("wal").join("wal.bin"), &wal).unwrap();
+
+        let options = Options {
+            write_buffer_size: 1024,
+            compaction_trigger: 100,
+            ..Options::default()
+        };
+        let db = Db::open(&dir, options.clone()).unwrap();
+        for i in 0..10 {
+            let key = format!("key{}", i);
+            assert_eq!(
+                db.get(&key).unwrap(),
+                Some(Value::new(format!("old{}", i), 0, 0))
+            );
+        }
+        // the first flush compacts the legacy table away
+        for i in 10..100 {
+            db.put(format!("key{}", i), Value::new("new".to_string(), 0, 0))
+                .unwrap();
+        }
+        drop(db);
+        for entry in read_dir(dir.join("sstable")).unwrap() {
+            let binary = read(entry.unwrap().path()).unwrap();
+            assert_eq!(format_version(&binary), Ok(FORMAT_VERSION));
+        }
+        let db = Db::open(&dir, options).unwrap();
+        assert_eq!(
+            db.get("key0").unwrap(),
+            Some(Value::new("old0".to_string(), 0, 0))
+        );
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+}
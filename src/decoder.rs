@@ -1,43 +1,125 @@
 use crate::command::Command;
 use crate::value::Value;
 use std::io;
-use std::io::BufRead;
 
-pub struct Decoder<R: io::Read> {
-    reader: io::BufReader<R>,
+/// Number of keys `keys` lists when no limit is given.
+const DEFAULT_KEYS_LIMIT: usize = 100;
+
+/// Longest line, command or `set` data, that is buffered while waiting for its end; the
+/// rest of a longer one is skipped.
+const MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+/// Decodes commands from input arriving in arbitrary pieces, e.g. reads of a non-blocking
+/// socket: `feed` each piece, then `decode` until no complete command is left.
+#[derive(Default)]
+pub struct Decoder {
+    buffer: Vec<u8>,
+    /// Start of the input not decoded yet.
+    start: usize,
+    /// End of the input searched for a line end, so that a line is only scanned once.
+    searched: usize,
+    /// Key, flags and exptime of a `set` whose data line has not arrived yet.
+    pending_set: Option<(String, usize, usize)>,
+    /// Whether the rest of a line longer than `MAX_LINE_LENGTH` is being skipped.
+    skipping: bool,
 }
 
-pub fn new<R: io::Read>(reader: R) -> Decoder<R> {
-    let r = io::BufReader::new(reader);
-    Decoder { reader: r }
+pub fn new() -> Decoder {
+    Decoder::default()
 }
 
-impl<R: io::Read> Decoder<R> {
-    pub fn decode(&mut self) -> Result<Command, io::Error> {
-        let mut buf = String::new();
-        let nbytes = self.reader.read_line(&mut buf)?;
-        if nbytes == 0 {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "got eof"));
+impl Decoder {
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buffer.drain(..self.start);
+        self.searched -= self.start;
+        self.start = 0;
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// The next complete command, `None` until more input is fed. An invalid command is
+    /// reported once and skipped, and decoding goes on with the next line.
+    pub fn decode(&mut self) -> Result<Option<Command>, io::Error> {
+        let line = match self.next_line()? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let line = String::from_utf8(line).map_err(|_| {
+            self.pending_set = None;
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            )
+        })?;
+        if let Some((key, flags, exptime)) = self.pending_set.take() {
+            let value = Value::new(line.trim().to_string(), flags, exptime);
+            return Ok(Some(Command::new_set(key, value)));
         }
-        let commands: Vec<&str> = buf.trim().split_whitespace().collect();
+        let commands: Vec<&str> = line.split_whitespace().collect();
+        let command = match commands.first() {
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, "no content")),
+            Some(&"set") => {
+                self.decode_set(commands)?;
+                return self.decode();
+            }
+            Some(&"get") => self.decode_get(commands),
+            Some(&"delete") => self.decode_delete(commands),
+            Some(&"scan") => self.decode_scan(commands),
+            Some(&"keys") => self.decode_keys(commands),
+            Some(&"stats") => self.decode_stats(commands),
+            Some(&"snapshot") => self.decode_no_argument(commands, Command::new_snapshot()),
+            Some(&"release") => self.decode_no_argument(commands, Command::new_release()),
+            Some(&"multi") => self.decode_no_argument(commands, Command::new_multi()),
+            Some(&"exec") => self.decode_no_argument(commands, Command::new_exec()),
+            Some(&"discard") => self.decode_no_argument(commands, Command::new_discard()),
+            Some(&"use") => self.decode_keyspace(commands, Command::new_use),
+            Some(&"create_keyspace") => {
+                self.decode_keyspace(commands, Command::new_create_keyspace)
+            }
+            Some(&"drop_keyspace") => self.decode_keyspace(commands, Command::new_drop_keyspace),
+            Some(c) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown command: {}", c),
+            )),
+        };
+        command.map(Some)
+    }
 
-        commands
-            .clone()
-            .first()
-            .ok_or(io::Error::new(io::ErrorKind::InvalidInput, "no content"))
-            .and_then(move |c| match c {
-                &"set" => self.decode_set(commands),
-                &"get" => self.decode_get(commands),
-                &"delete" => self.decode_delete(commands),
-                &"stats" => Ok(Command::new_stats()),
-                _ => Err(io::Error::new(
-                    io::ErrorKind::InvalidInput,
-                    format!("unknown command: {}", c),
-                )),
-            })
+    /// Takes the next line, with its line end, if all of it has arrived.
+    fn next_line(&mut self) -> Result<Option<Vec<u8>>, io::Error> {
+        loop {
+            let end = match self.buffer[self.searched..]
+                .iter()
+                .position(|b| *b == b'\n')
+            {
+                Some(position) => self.searched + position + 1,
+                None => {
+                    self.searched = self.buffer.len();
+                    if self.skipping {
+                        self.start = self.searched;
+                    } else if self.searched - self.start > MAX_LINE_LENGTH {
+                        self.start = self.searched;
+                        self.skipping = true;
+                        self.pending_set = None;
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("line longer than {} bytes", MAX_LINE_LENGTH),
+                        ));
+                    }
+                    return Ok(None);
+                }
+            };
+            let line = self.buffer[self.start..end].to_vec();
+            self.start = end;
+            self.searched = end;
+            if !self.skipping {
+                return Ok(Some(line));
+            }
+            self.skipping = false;
+        }
     }
 
-    fn decode_set(&mut self, commands: Vec<&str>) -> Result<Command, io::Error> {
+    /// Reads the header of a `set`, whose data follows on the next line.
+    fn decode_set(&mut self, commands: Vec<&str>) -> Result<(), io::Error> {
         if commands.len() != 5 {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
@@ -54,13 +136,8 @@ impl<R: io::Read> Decoder<R> {
         let _bytes = commands[4]
             .parse::<usize>()
             .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
-        let mut buf = String::new();
-        let nbytes = self.reader.read_line(&mut buf)?;
-        if nbytes == 0 {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "got eof"));
-        }
-        let value = Value::new(buf.trim().parse().unwrap(), flags, exptime);
-        Ok(Command::new_set(key.to_string(), value))
+        self.pending_set = Some((key.to_string(), flags, exptime));
+        Ok(())
     }
 
     fn decode_get(&self, commands: Vec<&str>) -> Result<Command, io::Error> {
@@ -83,4 +160,169 @@ impl<R: io::Read> Decoder<R> {
         let key = commands[1];
         Ok(Command::new_delete(key.to_string()))
     }
+    fn decode_scan(&self, commands: Vec<&str>) -> Result<Command, io::Error> {
+        if commands.len() != 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "scan command length must be 4",
+            ));
+        }
+        let limit = commands[3]
+            .parse::<usize>()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        Ok(Command::new_scan(
+            commands[1].to_string(),
+            commands[2].to_string(),
+            limit,
+        ))
+    }
+    fn decode_keys(&self, commands: Vec<&str>) -> Result<Command, io::Error> {
+        if commands.len() < 2 || commands.len() > 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "keys command length must be 2 to 4",
+            ));
+        }
+        let limit = match commands.get(2) {
+            Some(limit) => limit
+                .parse::<usize>()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            None => DEFAULT_KEYS_LIMIT,
+        };
+        let cursor = commands.get(3).map(|cursor| cursor.to_string());
+        Ok(Command::new_keys(commands[1].to_string(), limit, cursor))
+    }
+    fn decode_stats(&self, commands: Vec<&str>) -> Result<Command, io::Error> {
+        if commands.len() > 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "stats command length must be 1 or 2",
+            ));
+        }
+        let group = commands.get(1).map(|group| group.to_string());
+        Ok(Command::new_stats(group))
+    }
+    fn decode_keyspace(
+        &self,
+        commands: Vec<&str>,
+        command: fn(String) -> Command,
+    ) -> Result<Command, io::Error> {
+        if commands.len() != 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} command length must be 2", commands[0]),
+            ));
+        }
+        Ok(command(commands[1].to_string()))
+    }
+    fn decode_no_argument(
+        &self,
+        commands: Vec<&str>,
+        command: Command,
+    ) -> Result<Command, io::Error> {
+        if commands.len() != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} command length must be 1", commands[0]),
+            ));
+        }
+        Ok(command)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::Command;
+    use crate::decoder::{self, DEFAULT_KEYS_LIMIT, MAX_LINE_LENGTH};
+
+    #[test]
+    fn decode_partial_input() {
+        let mut decoder = decoder::new();
+        let input = b"set key 1 2 5\r\nvalue\r\nget key\r\ndelete";
+        let mut commands = vec![];
+        // one byte at a time, resuming in the middle of lines and between a set's two lines
+        for byte in input.iter() {
+            decoder.feed(&[*byte]);
+            while let Some(command) = decoder.decode().unwrap() {
+                commands.push(command);
+            }
+        }
+        assert_eq!(commands.len(), 2);
+        match &commands[0] {
+            Command::Set { key, value } => {
+                assert_eq!(key, "key");
+                assert_eq!(
+                    (value.data(), value.flags(), value.exptime()),
+                    ("value", 1, 2)
+                );
+            }
+            _ => panic!("expected set"),
+        }
+        assert!(matches!(&commands[1], Command::Get { key } if key == "key"));
+        decoder.feed(b" key\n");
+        assert!(matches!(decoder.decode(), Ok(Some(Command::Delete { .. }))));
+        assert!(matches!(decoder.decode(), Ok(None)));
+
+        // an invalid line is reported once and skipped
+        decoder.feed(b"bogus\nget a b\nstats\n");
+        assert!(decoder.decode().is_err());
+        assert!(decoder.decode().is_err());
+        assert!(matches!(
+            decoder.decode(),
+            Ok(Some(Command::Stats { group: None }))
+        ));
+
+        // as is the rest of an overlong line, whatever pieces it arrives in
+        decoder.feed(&vec![b'x'; MAX_LINE_LENGTH + 1]);
+        assert!(decoder.decode().is_err());
+        decoder.feed(b"xxx");
+        assert!(matches!(decoder.decode(), Ok(None)));
+        decoder.feed(b"x\nget k\n");
+        assert!(matches!(decoder.decode(), Ok(Some(Command::Get { .. }))));
+    }
+
+    #[test]
+    fn bounded_input() {
+        // at most one line is kept undecoded, however long the input without a line end
+        let mut decoder = decoder::new();
+        let piece = vec![b'x'; 64 * 1024];
+        for _ in 0..64 {
+            decoder.feed(&piece);
+            while let Ok(Some(_)) | Err(_) = decoder.decode() {}
+            assert!(decoder.buffer.len() <= MAX_LINE_LENGTH + piece.len());
+        }
+        decoder.feed(b"\nget k\n");
+        assert!(matches!(decoder.decode(), Ok(Some(Command::Get { .. }))));
+    }
+
+    #[test]
+    fn decode_keys() {
+        let mut decoder = decoder::new();
+        decoder.feed(b"keys p\nkeys p 10\nkeys p 18446744073709551615 p3\n");
+        decoder.feed(b"keys\nkeys p 10 p3 extra\nkeys p -1\n");
+        let mut keys = || match decoder.decode() {
+            Ok(Some(Command::Keys {
+                prefix,
+                limit,
+                cursor,
+            })) => Ok((prefix, limit, cursor)),
+            Ok(_) => panic!("expected keys"),
+            Err(e) => Err(e.to_string()),
+        };
+        assert_eq!(keys(), Ok(("p".to_string(), DEFAULT_KEYS_LIMIT, None)));
+        assert_eq!(keys(), Ok(("p".to_string(), 10, None)));
+        assert_eq!(
+            keys(),
+            Ok(("p".to_string(), usize::MAX, Some("p3".to_string())))
+        );
+        assert_eq!(
+            keys(),
+            Err("keys command length must be 2 to 4".to_string())
+        );
+        assert_eq!(
+            keys(),
+            Err("keys command length must be 2 to 4".to_string())
+        );
+        assert!(keys().is_err());
+    }
 }
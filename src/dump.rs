@@ -0,0 +1,354 @@
+use crate::key::{InternalKey, ValueType};
+use crate::options::WalRecoveryMode;
+use crate::record::decode_file;
+use crate::table::{self, Corruption, Layout};
+use crate::value::Value;
+use crate::wal::{read_log, LEGACY_WAL_FILE};
+use crate::write_batch::BatchRecord;
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+/// Width of the longest bar of the value size histogram.
+const HISTOGRAM_WIDTH: usize = 40;
+
+/// How `sst_dump` and `wal_dump` print records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(anyhow!("invalid output format: {}", s)),
+        }
+    }
+}
+
+/// Command line of `sst_dump` and `wal_dump`:
+/// `[--output text|json] [--key <key>] [--summary] <file>...`.
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub output: Output,
+    /// Only records of this user key are dumped.
+    pub key: Option<String>,
+    /// Whether only the file information and summary are printed, not the records.
+    pub summary_only: bool,
+    pub paths: Vec<PathBuf>,
+}
+
+impl Args {
+    /// Parses the arguments following the program name; `None` if they are invalid.
+    pub fn parse<I: IntoIterator<Item = String>>(args: I) -> Option<Self> {
+        let mut parsed = Args {
+            output: Output::Text,
+            key: None,
+            summary_only: false,
+            paths: vec![],
+        };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--output" => parsed.output = args.next()?.parse().ok()?,
+                "--key" => parsed.key = Some(args.next()?),
+                "--summary" => parsed.summary_only = true,
+                _ if arg.starts_with('-') => return None,
+                _ => parsed.paths.push(PathBuf::from(arg)),
+            }
+        }
+        if parsed.paths.is_empty() {
+            return None;
+        }
+        Some(parsed)
+    }
+
+    /// Whether the record with this key is dumped.
+    pub fn selects(&self, key: &InternalKey) -> bool {
+        self.key.as_ref().is_none_or(|k| *k == key.user_key)
+    }
+
+    /// Prints a line of file information or summary. In JSON output it goes to stderr, so that
+    /// only the records go to stdout.
+    pub fn info(&self, line: &str) {
+        match self.output {
+            Output::Text => println!("{}", line),
+            Output::Json => eprintln!("{}", line),
+        }
+    }
+}
+
+/// Prints the usage of the dump binary `program`, which reads `files`, and exits.
+pub fn usage(program: &str, files: &str) -> ! {
+    eprintln!(
+        "usage: {} [--output text|json] [--key <key>] [--summary] <{}>...",
+        program, files
+    );
+    process::exit(2);
+}
+
+/// Contents of a table file, as far as they are readable.
+pub struct TableDump {
+    pub file_size: u64,
+    pub layout: Result<Layout, Corruption>,
+    pub records: Vec<(InternalKey, Option<Value>)>,
+    pub damage: Vec<Corruption>,
+}
+
+/// Reads a table file of any format, keeping the records of undamaged blocks.
+pub fn read_table(path: &Path) -> Result<TableDump> {
+    let buffer = fs::read(path)?;
+    let (records, mut damage) = table::salvage(&buffer);
+    for corruption in &mut damage {
+        corruption.file = path.to_path_buf();
+    }
+    Ok(TableDump {
+        file_size: buffer.len() as u64,
+        layout: table::layout(&buffer),
+        records,
+        damage,
+    })
+}
+
+/// Contents of a WAL file, as far as they are readable.
+pub struct WalDump {
+    pub file_size: u64,
+    /// 0 for a legacy `wal.bin`, see `wal::FORMAT_VERSION` for segments.
+    pub format_version: u32,
+    pub records: Vec<BatchRecord>,
+    /// Bytes of corrupt WAL records skipped.
+    pub dropped_bytes: usize,
+}
+
+/// Reads a WAL segment, skipping corrupt WAL records, or a legacy `wal.bin`.
+pub fn read_wal(path: &Path) -> Result<WalDump> {
+    let file_size = fs::metadata(path)?.len();
+    if path.file_name().is_some_and(|name| name == LEGACY_WAL_FILE) {
+        let records = decode_file(path)?
+            .into_iter()
+            .map(|(key, value)| (0, key, value))
+            .collect();
+        return Ok(WalDump {
+            file_size,
+            format_version: 0,
+            records,
+            dropped_bytes: 0,
+        });
+    }
+    let recovered = read_log(path, WalRecoveryMode::SkipAnyCorruptedRecords)?;
+    Ok(WalDump {
+        file_size,
+        format_version: recovered.format_version,
+        records: recovered.records,
+        dropped_bytes: recovered.dropped_bytes,
+    })
+}
+
+/// One record as a line, with its column family if given, e.g. for WAL records.
+pub fn format_record(
+    column_family: Option<u32>,
+    key: &InternalKey,
+    value: Option<&Value>,
+    output: Output,
+) -> String {
+    match output {
+        Output::Text => {
+            let column_family = column_family.map_or(String::new(), |id| format!("[{}] ", id));
+            let value = match value {
+                Some(v) => format!("{:?} flags={} exptime={}", v.data(), v.flags(), v.exptime()),
+                None => "DELETE".to_string(),
+            };
+            format!(
+                "{}{:?} @ {} : {}",
+                column_family, key.user_key, key.sequence, value
+            )
+        }
+        Output::Json => {
+            let mut fields = vec![];
+            if let Some(id) = column_family {
+                fields.push(format!("\"column_family\":{}", id));
+            }
+            fields.push(format!("\"key\":{}", json_string(&key.user_key)));
+            fields.push(format!("\"sequence\":{}", key.sequence));
+            match value {
+                Some(v) => {
+                    fields.push("\"type\":\"value\"".to_string());
+                    fields.push(format!("\"flags\":{}", v.flags()));
+                    fields.push(format!("\"exptime\":{}", v.exptime()));
+                    fields.push(format!("\"value\":{}", json_string(v.data())));
+                }
+                None => fields.push("\"type\":\"deletion\"".to_string()),
+            }
+            format!("{{{}}}", fields.join(","))
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len() + 2);
+    escaped.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+/// Statistics of the records of one or more files.
+#[derive(Debug, Default)]
+pub struct Summary {
+    pub records: usize,
+    pub tombstones: usize,
+    pub smallest_key: Option<String>,
+    pub largest_key: Option<String>,
+    pub smallest_sequence: Option<u64>,
+    pub largest_sequence: Option<u64>,
+    /// Number of values by data size, the bucket `i > 0` holding sizes in `[2^(i-1), 2^i)`
+    /// and the bucket 0 empty values.
+    pub value_sizes: Vec<usize>,
+}
+
+impl Summary {
+    pub fn add(&mut self, key: &InternalKey, value: Option<&Value>) {
+        self.records += 1;
+        if key.value_type == ValueType::Deletion {
+            self.tombstones += 1;
+        }
+        if self.smallest_key.as_ref().is_none_or(|k| key.user_key < *k) {
+            self.smallest_key = Some(key.user_key.clone());
+        }
+        if self.largest_key.as_ref().is_none_or(|k| key.user_key > *k) {
+            self.largest_key = Some(key.user_key.clone());
+        }
+        self.smallest_sequence = Some(
+            self.smallest_sequence
+                .map_or(key.sequence, |s| s.min(key.sequence)),
+        );
+        self.largest_sequence = Some(
+            self.largest_sequence
+                .map_or(key.sequence, |s| s.max(key.sequence)),
+        );
+        if let Some(value) = value {
+            let size = value.data().len();
+            let bucket = (usize::BITS - size.leading_zeros()) as usize;
+            if self.value_sizes.len() <= bucket {
+                self.value_sizes.resize(bucket + 1, 0);
+            }
+            self.value_sizes[bucket] += 1;
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "records: {} ({} tombstones)",
+            self.records, self.tombstones
+        )?;
+        if let (Some(smallest), Some(largest)) = (&self.smallest_key, &self.largest_key) {
+            writeln!(f, "keys: {:?} .. {:?}", smallest, largest)?;
+        }
+        if let (Some(smallest), Some(largest)) = (self.smallest_sequence, self.largest_sequence) {
+            writeln!(f, "sequences: {} .. {}", smallest, largest)?;
+        }
+        let max = self.value_sizes.iter().copied().max().unwrap_or(0);
+        if max > 0 {
+            writeln!(f, "value sizes:")?;
+        }
+        for (bucket, count) in self.value_sizes.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let (low, high) = match bucket {
+                0 => (0, 1),
+                _ => (1usize << (bucket - 1), 1usize << bucket),
+            };
+            let bar = "#".repeat((count * HISTOGRAM_WIDTH).div_ceil(max));
+            writeln!(f, "  [{:>8}, {:>8}) {:>8} {}", low, high, count, bar)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dump::{format_record, Args, Output, Summary};
+    use crate::key::{InternalKey, ValueType};
+    use crate::value::Value;
+
+    #[test]
+    fn summary_and_format() {
+        let mut summary = Summary::default();
+        for (i, size) in [0, 1, 3, 4, 100].iter().enumerate() {
+            let key = InternalKey::new(format!("key{}", i), i as u64 + 1, ValueType::Value);
+            summary.add(&key, Some(&Value::new("v".repeat(*size), 0, 0)));
+        }
+        let key = InternalKey::new("a".to_string(), 10, ValueType::Deletion);
+        summary.add(&key, None);
+        assert_eq!((summary.records, summary.tombstones), (6, 1));
+        assert_eq!(summary.smallest_key.as_deref(), Some("a"));
+        assert_eq!(summary.largest_key.as_deref(), Some("key4"));
+        assert_eq!(
+            (summary.smallest_sequence, summary.largest_sequence),
+            (Some(1), Some(10))
+        );
+        assert_eq!(summary.value_sizes, vec![1, 1, 1, 1, 0, 0, 0, 1]);
+        assert!(summary
+            .to_string()
+            .contains("[       2,        4)        1"));
+
+        let value = Value::new("say \"hi\"\n".to_string(), 1, 2);
+        let key = InternalKey::new("k".to_string(), 3, ValueType::Value);
+        assert_eq!(
+            format_record(Some(1), &key, Some(&value), Output::Json),
+            "{\"column_family\":1,\"key\":\"k\",\"sequence\":3,\"type\":\"value\",\"flags\":1,\
+             \"exptime\":2,\"value\":\"say \\\"hi\\\"\\n\"}"
+        );
+        assert_eq!(
+            format_record(None, &key, None, Output::Text),
+            "\"k\" @ 3 : DELETE"
+        );
+    }
+
+    #[test]
+    fn parse_args() {
+        let parse = |args: &[&str]| Args::parse(args.iter().map(|a| a.to_string()));
+        let args = parse(&["--output", "json", "--key", "k", "--summary", "a", "b"]).unwrap();
+        assert_eq!(args.output, Output::Json);
+        assert_eq!(args.key.as_deref(), Some("k"));
+        assert!(args.summary_only);
+        assert_eq!(args.paths.len(), 2);
+        let args = parse(&["a"]).unwrap();
+        assert_eq!(
+            (args.output, args.key, args.summary_only),
+            (Output::Text, None, false)
+        );
+        for invalid in [
+            &[][..],
+            &["--summary"],
+            &["--output", "xml", "a"],
+            &["a", "--key"],
+            &["--verbose", "a"],
+        ] {
+            assert_eq!(parse(invalid), None, "{:?}", invalid);
+        }
+    }
+}
@@ -1,54 +1,483 @@
+use crate::column_family::ColumnFamily;
 use crate::command::Command;
-use crate::memtable::Memtable;
+use crate::db::Db;
+use crate::options::{ReadOptions, ServerOptions};
+use crate::snapshot::Snapshot;
+use crate::write_batch::WriteBatch;
 
-use crate::sstable::SSTable;
 use std::error::Error;
-use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 
+/// Settings and connection counters of the server, reported by `stats`.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    pub options: ServerOptions,
+    pub curr_connections: AtomicUsize,
+    pub total_connections: AtomicU64,
+    /// Connections refused for exceeding `max_connections`.
+    pub rejected_connections: AtomicU64,
+}
+
+impl ServerStats {
+    pub fn new(options: ServerOptions) -> Self {
+        Self {
+            options,
+            ..Self::default()
+        }
+    }
+}
+
+/// Executes the commands of one connection.
 pub struct Executor {
-    memtable: Arc<RwLock<Box<dyn Memtable>>>,
-    sstable: Arc<RwLock<Box<dyn SSTable>>>,
+    db: Arc<Db>,
+    server: Arc<ServerStats>,
+    /// Snapshot taken with `snapshot` that gets read from until `release`.
+    snapshot: Option<Snapshot>,
+    /// Sets and deletes queued since `multi`, written by `exec`.
+    batch: Option<WriteBatch>,
+    /// Keyspace selected with `use`. Commands using it fail once it is dropped.
+    column_family: ColumnFamily,
 }
 
 impl Executor {
-    pub fn new(
-        memtable: Arc<RwLock<Box<dyn Memtable>>>,
-        sstable: Arc<RwLock<Box<dyn SSTable>>>,
-    ) -> Self {
-        Self { memtable, sstable }
+    pub fn new(db: Arc<Db>, server: Arc<ServerStats>) -> Self {
+        Self {
+            db,
+            server,
+            snapshot: None,
+            batch: None,
+            column_family: ColumnFamily::default(),
+        }
     }
+    fn read_options(&self) -> ReadOptions<'_> {
+        ReadOptions {
+            snapshot: self.snapshot.as_ref(),
+        }
+    }
+
     pub fn execute(&mut self, command: Command) -> Result<String, Box<dyn Error + '_>> {
+        let uses_keyspace = matches!(
+            command,
+            Command::Set { .. }
+                | Command::Get { .. }
+                | Command::Delete { .. }
+                | Command::Scan { .. }
+                | Command::Keys { .. }
+                | Command::Exec
+        );
+        // a keyspace dropped by another connection stays selected until the next `use`, even
+        // if one of the same name is created again
+        if uses_keyspace
+            && self.db.column_family(self.column_family.name()).as_ref()
+                != Some(&self.column_family)
+        {
+            return Err(format!("keyspace {} was dropped", self.column_family.name()).into());
+        }
+        if let Some(batch) = &mut self.batch {
+            match command {
+                Command::Set { key, value } => batch.put_cf(&self.column_family, key, value),
+                Command::Delete { key } => batch.delete_cf(&self.column_family, key),
+                Command::Exec => {
+                    let batch = self.batch.take().unwrap_or_default();
+                    let len = batch.len();
+                    self.db.write(batch)?;
+                    return Ok(format!("EXECUTED {}", len));
+                }
+                Command::Discard => {
+                    self.batch = None;
+                    return Ok("DISCARDED".to_string());
+                }
+                // starts over, dropping what was queued
+                Command::Multi => {
+                    self.batch = Some(WriteBatch::new());
+                    return Ok("OK".to_string());
+                }
+                _ => return Err("only set and delete can be queued in multi".into()),
+            }
+            return Ok("QUEUED".to_string());
+        }
         match command {
             Command::Set { key, value } => {
-                let mut memtable = self.memtable.write()?;
-                memtable.insert(key, value)?;
-                let records = memtable.to_records();
-                if records.len() > 10 {
-                    let mut sstable = self.sstable.write()?;
-                    sstable.create(records).unwrap();
-                    memtable.clear().unwrap();
-                }
+                self.db.put_cf(&self.column_family, key, value)?;
                 Ok("STORED".to_string())
             }
             Command::Get { key } => {
                 let formatted_value = self
-                    .memtable
-                    .read()?
-                    .search(&key)
-                    .or(self.sstable.read()?.search(&key))
-                    .flatten()
+                    .db
+                    .get_cf(&self.read_options(), &self.column_family, &key)?
                     .map_or(String::new(), |v| v.to_string(key));
                 Ok(format!("{}END", formatted_value))
             }
+            Command::Scan { start, end, limit } => {
+                let mut iter =
+                    self.db
+                        .range_cf(&self.read_options(), &self.column_family, start..end)?;
+                let formatted_values: String = iter
+                    .by_ref()
+                    .take(limit)
+                    .map(|(key, value)| value.to_string(key))
+                    .collect();
+                iter.status()?;
+                Ok(format!("{}END", formatted_values))
+            }
+            Command::Keys {
+                prefix,
+                limit,
+                cursor,
+            } => {
+                let mut iter =
+                    self.db
+                        .prefix_iter_cf(&self.read_options(), &self.column_family, &prefix)?;
+                if let Some(cursor) = &cursor {
+                    iter.seek(cursor.max(&prefix));
+                }
+                let keys: Vec<String> = iter
+                    .by_ref()
+                    .map(|(key, _)| key)
+                    .skip_while(|key| Some(key) == cursor.as_ref())
+                    .take(limit.saturating_add(1))
+                    .collect();
+                iter.status()?;
+                let mut formatted_keys: String = keys
+                    .iter()
+                    .take(limit)
+                    .map(|key| format!("KEY {}\n", key))
+                    .collect();
+                // more keys left: the last one listed is where the next page starts
+                if keys.len() > limit && limit > 0 {
+                    formatted_keys.push_str(&format!("CURSOR {}\n", keys[limit - 1]));
+                }
+                Ok(format!("{}END", formatted_keys))
+            }
             Command::Delete { key } => {
-                let mut memtable = self.memtable.write()?;
-                memtable.delete(&key)?;
+                self.db.delete_cf(&self.column_family, &key)?;
                 Ok("DELETED".to_string())
             }
-            Command::Stats {} => {
-                let memtable = self.memtable.read()?;
-                Ok(format!("STAT curr_items {}", memtable.to_vec().len()))
+            Command::Stats { group } => {
+                let stats = match group.as_deref() {
+                    None => vec![
+                        ("curr_items", self.db.memtable_items().to_string()),
+                        (
+                            "curr_connections",
+                            self.server
+                                .curr_connections
+                                .load(Ordering::Relaxed)
+                                .to_string(),
+                        ),
+                        (
+                            "total_connections",
+                            self.server
+                                .total_connections
+                                .load(Ordering::Relaxed)
+                                .to_string(),
+                        ),
+                        (
+                            "rejected_connections",
+                            self.server
+                                .rejected_connections
+                                .load(Ordering::Relaxed)
+                                .to_string(),
+                        ),
+                    ],
+                    Some("settings") => {
+                        let mut settings = self.db.options().settings();
+                        settings.extend(self.server.options.settings());
+                        settings
+                    }
+                    Some(group) => return Err(format!("unknown stats group: {}", group).into()),
+                };
+                let formatted_stats: String = stats
+                    .into_iter()
+                    .map(|(name, value)| format!("STAT {} {}\n", name, value))
+                    .collect();
+                Ok(format!("{}END", formatted_stats))
+            }
+            Command::Snapshot => {
+                if self.snapshot.is_some() {
+                    return Err("snapshot already taken".into());
+                }
+                let snapshot = self.db.snapshot();
+                let sequence = snapshot.sequence();
+                self.snapshot = Some(snapshot);
+                Ok(format!("SNAPSHOT {}", sequence))
+            }
+            Command::Multi => {
+                self.batch = Some(WriteBatch::new());
+                Ok("OK".to_string())
+            }
+            Command::Exec | Command::Discard => Err("not in multi".into()),
+            Command::Use { name } => match self.db.column_family(&name) {
+                Some(column_family) => {
+                    self.column_family = column_family;
+                    Ok("OK".to_string())
+                }
+                None => Err(format!("unknown keyspace: {}", name).into()),
+            },
+            Command::CreateKeyspace { name } => {
+                let options = self.db.options().column_family_options();
+                self.db.create_column_family(&name, options)?;
+                Ok("CREATED".to_string())
             }
+            Command::DropKeyspace { name } => {
+                self.db.drop_column_family(&name)?;
+                Ok("DROPPED".to_string())
+            }
+            Command::Release => match self.snapshot.take() {
+                Some(snapshot) => {
+                    self.db.release_snapshot(snapshot);
+                    Ok("RELEASED".to_string())
+                }
+                None => Err("no snapshot taken".into()),
+            },
         }
     }
 }
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        if let Some(snapshot) = self.snapshot.take() {
+            self.db.release_snapshot(snapshot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::Command;
+    use crate::db::Db;
+    use crate::executor::{Executor, ServerStats};
+    use crate::options::{Options, ServerOptions, WalSyncPolicy};
+    use crate::value::Value;
+    use std::fs::remove_dir_all;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    fn open(name: &str, options: Options) -> (PathBuf, Arc<Db>) {
+        let dir = std::env::temp_dir().join(format!("lsm_engine_executor_{}", name));
+        let _ = remove_dir_all(&dir);
+        let db = Arc::new(Db::open(&dir, options).unwrap());
+        (dir, db)
+    }
+
+    fn execute(executor: &mut Executor, command: Command) -> Result<String, String> {
+        executor.execute(command).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn stats_settings() {
+        let options = Options {
+            wal_sync_policy: WalSyncPolicy::Periodic { interval_ms: 100 },
+            ..Options::default()
+        };
+        let (dir, db) = open("stats_settings", options);
+        let server = Arc::new(ServerStats::new(ServerOptions::default()));
+        let mut executor = Executor::new(db, server);
+
+        let settings = execute(
+            &mut executor,
+            Command::new_stats(Some("settings".to_string())),
+        )
+        .unwrap();
+        assert!(settings.contains("STAT wal_sync_policy periodic_100ms\n"));
+        assert!(settings.contains("STAT worker_threads 4\n"));
+        assert!(settings.ends_with("\nEND"));
+        assert_eq!(
+            execute(&mut executor, Command::new_stats(Some("other".to_string()))),
+            Err("unknown stats group: other".to_string())
+        );
+        drop(executor);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keys_paging() {
+        let (dir, db) = open("keys_paging", Options::default());
+        let server = Arc::new(ServerStats::new(ServerOptions::default()));
+        let mut executor = Executor::new(db, server);
+        for key in ["a", "p1", "p2", "p3", "p4", "p5", "q"] {
+            let value = Value::new(key.to_string(), 0, 0);
+            execute(&mut executor, Command::new_set(key.to_string(), value)).unwrap();
+        }
+        let mut keys = |limit, cursor: Option<&str>| {
+            let command = Command::new_keys("p".to_string(), limit, cursor.map(String::from));
+            execute(&mut executor, command).unwrap()
+        };
+
+        assert_eq!(keys(2, None), "KEY p1\nKEY p2\nCURSOR p2\nEND");
+        assert_eq!(keys(2, Some("p2")), "KEY p3\nKEY p4\nCURSOR p4\nEND");
+        // the last page has no cursor, also when it is exactly full
+        assert_eq!(keys(1, Some("p4")), "KEY p5\nEND");
+        assert_eq!(keys(2, Some("p5")), "END");
+        assert_eq!(keys(0, None), "END");
+        assert_eq!(
+            keys(usize::MAX, None),
+            "KEY p1\nKEY p2\nKEY p3\nKEY p4\nKEY p5\nEND"
+        );
+        // a cursor before the prefix starts at its first key, one after it lists nothing
+        assert_eq!(keys(1, Some("a")), "KEY p1\nCURSOR p1\nEND");
+        assert_eq!(keys(1, Some("q")), "END");
+        drop(executor);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn multi_exec() {
+        let (dir, db) = open("multi_exec", Options::default());
+        let server = Arc::new(ServerStats::new(ServerOptions::default()));
+        let mut executor = Executor::new(db.clone(), server.clone());
+        let mut other = Executor::new(db, server);
+        let set = |key: &str| Command::new_set(key.to_string(), Value::new("v".to_string(), 0, 0));
+        let get = |key: &str| Command::new_get(key.to_string());
+
+        assert_eq!(
+            execute(&mut executor, Command::new_exec()),
+            Err("not in multi".to_string())
+        );
+        assert_eq!(
+            execute(&mut executor, Command::new_discard()),
+            Err("not in multi".to_string())
+        );
+        execute(&mut executor, set("a")).unwrap();
+
+        assert_eq!(
+            execute(&mut executor, Command::new_multi()),
+            Ok("OK".to_string())
+        );
+        assert_eq!(
+            execute(&mut executor, set("dropped")),
+            Ok("QUEUED".to_string())
+        );
+        // a nested multi starts the batch over
+        assert_eq!(
+            execute(&mut executor, Command::new_multi()),
+            Ok("OK".to_string())
+        );
+        assert_eq!(execute(&mut executor, set("b")), Ok("QUEUED".to_string()));
+        assert_eq!(execute(&mut executor, set("c")), Ok("QUEUED".to_string()));
+        assert_eq!(
+            execute(&mut executor, Command::new_delete("a".to_string())),
+            Ok("QUEUED".to_string())
+        );
+        for command in [get("a"), Command::new_stats(None), Command::new_snapshot()] {
+            assert_eq!(
+                execute(&mut executor, command),
+                Err("only set and delete can be queued in multi".to_string())
+            );
+        }
+        // nothing is applied before exec
+        assert_eq!(execute(&mut other, get("b")), Ok("END".to_string()));
+        assert_eq!(
+            execute(&mut other, get("a")).unwrap(),
+            "VALUE a 0 0 1\nv\nEND"
+        );
+
+        assert_eq!(
+            execute(&mut executor, Command::new_exec()),
+            Ok("EXECUTED 3".to_string())
+        );
+        assert_eq!(execute(&mut other, get("a")), Ok("END".to_string()));
+        assert_eq!(
+            execute(&mut other, get("b")).unwrap(),
+            "VALUE b 0 0 1\nv\nEND"
+        );
+        assert_eq!(
+            execute(&mut other, get("c")).unwrap(),
+            "VALUE c 0 0 1\nv\nEND"
+        );
+        assert_eq!(execute(&mut other, get("dropped")), Ok("END".to_string()));
+
+        // discard drops the batch and leaves multi
+        execute(&mut executor, Command::new_multi()).unwrap();
+        execute(&mut executor, set("d")).unwrap();
+        assert_eq!(
+            execute(&mut executor, Command::new_discard()),
+            Ok("DISCARDED".to_string())
+        );
+        assert_eq!(execute(&mut executor, get("d")), Ok("END".to_string()));
+        drop(executor);
+        drop(other);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keyspaces() {
+        let (dir, db) = open("keyspaces", Options::default());
+        let server = Arc::new(ServerStats::new(ServerOptions::default()));
+        let mut executor = Executor::new(db.clone(), server.clone());
+        let mut other = Executor::new(db, server);
+        let keyspace = |command: fn(String) -> Command, name: &str| command(name.to_string());
+        let set =
+            |data: &str| Command::new_set("k".to_string(), Value::new(data.to_string(), 0, 0));
+        let get = || Command::new_get("k".to_string());
+
+        assert_eq!(
+            execute(&mut executor, keyspace(Command::new_use, "users")),
+            Err("unknown keyspace: users".to_string())
+        );
+        assert_eq!(
+            execute(
+                &mut executor,
+                keyspace(Command::new_create_keyspace, "users")
+            ),
+            Ok("CREATED".to_string())
+        );
+        assert!(execute(
+            &mut executor,
+            keyspace(Command::new_create_keyspace, "users")
+        )
+        .is_err());
+        assert!(execute(
+            &mut executor,
+            keyspace(Command::new_drop_keyspace, "default")
+        )
+        .is_err());
+        assert!(execute(
+            &mut executor,
+            keyspace(Command::new_drop_keyspace, "missing")
+        )
+        .is_err());
+
+        execute(&mut executor, set("default")).unwrap();
+        assert_eq!(
+            execute(&mut executor, keyspace(Command::new_use, "users")),
+            Ok("OK".to_string())
+        );
+        assert_eq!(execute(&mut executor, get()), Ok("END".to_string()));
+        execute(&mut executor, set("users")).unwrap();
+        assert_eq!(
+            execute(&mut executor, get()).unwrap(),
+            "VALUE k 0 0 5\nusers\nEND"
+        );
+        // the selection is per connection
+        assert_eq!(
+            execute(&mut other, get()).unwrap(),
+            "VALUE k 0 0 7\ndefault\nEND"
+        );
+
+        // dropped by another connection, also when created again, until the next use
+        assert_eq!(
+            execute(&mut other, keyspace(Command::new_drop_keyspace, "users")),
+            Ok("DROPPED".to_string())
+        );
+        execute(&mut other, keyspace(Command::new_create_keyspace, "users")).unwrap();
+        assert_eq!(
+            execute(&mut executor, get()),
+            Err("keyspace users was dropped".to_string())
+        );
+        assert_eq!(
+            execute(&mut executor, set("lost")),
+            Err("keyspace users was dropped".to_string())
+        );
+        execute(&mut executor, keyspace(Command::new_use, "users")).unwrap();
+        assert_eq!(execute(&mut executor, get()), Ok("END".to_string()));
+        execute(&mut executor, keyspace(Command::new_use, "default")).unwrap();
+        assert_eq!(
+            execute(&mut executor, get()).unwrap(),
+            "VALUE k 0 0 7\ndefault\nEND"
+        );
+        drop(executor);
+        drop(other);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,189 @@
+use crate::key::InternalKey;
+use crate::table::Corruption;
+use crate::value::Value;
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+
+/// Versions sorted in internal key order that can be read from any position, taking locks only
+/// for the duration of a call, so iterators can hold on to it.
+pub trait SortedRun: Sync + Send {
+    /// First version after `bound` in internal key order.
+    fn next_after(
+        &self,
+        bound: Bound<&InternalKey>,
+    ) -> Result<Option<(InternalKey, Option<Value>)>, Corruption>;
+}
+
+impl SortedRun for BTreeMap<InternalKey, Option<Value>> {
+    fn next_after(
+        &self,
+        bound: Bound<&InternalKey>,
+    ) -> Result<Option<(InternalKey, Option<Value>)>, Corruption> {
+        Ok(self
+            .range((bound, Bound::Unbounded))
+            .next()
+            .map(|(key, value)| (key.clone(), value.clone())))
+    }
+}
+
+/// Cursor over one sorted run.
+struct RunIterator {
+    run: Arc<dyn SortedRun>,
+    current: Option<(InternalKey, Option<Value>)>,
+}
+
+impl RunIterator {
+    fn seek(&mut self, target: &InternalKey) -> Result<(), Corruption> {
+        self.current = None;
+        self.current = self.run.next_after(Bound::Included(target))?;
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<(), Corruption> {
+        if let Some((key, _)) = self.current.take() {
+            self.current = self.run.next_after(Bound::Excluded(&key))?;
+        }
+        Ok(())
+    }
+
+    fn key(&self) -> Option<&InternalKey> {
+        self.current.as_ref().map(|(key, _)| key)
+    }
+}
+
+/// Merges sorted runs into one sequence in internal key order.
+///
+/// Runs are given newest first. Equal internal keys, which only legacy tables have, are
+/// yielded once, with the version from the newest run.
+///
+/// A run that fails to read ends the iteration; `status` then returns its error.
+pub struct MergingIterator {
+    children: Vec<RunIterator>,
+    current: Option<usize>,
+    error: Option<Corruption>,
+}
+
+impl MergingIterator {
+    pub fn new(runs: Vec<Arc<dyn SortedRun>>) -> Self {
+        Self {
+            children: runs
+                .into_iter()
+                .map(|run| RunIterator { run, current: None })
+                .collect(),
+            current: None,
+            error: None,
+        }
+    }
+
+    /// Positions at the first version not less than `target`.
+    pub fn seek(&mut self, target: &InternalKey) {
+        self.error = self
+            .children
+            .iter_mut()
+            .map(|child| child.seek(target))
+            .find_map(Result::err);
+        self.find_smallest();
+    }
+
+    pub fn next(&mut self) {
+        if let Some(key) = self.key().cloned() {
+            for child in &mut self.children {
+                if child.key() == Some(&key) {
+                    if let Err(e) = child.next() {
+                        self.error = Some(e);
+                        break;
+                    }
+                }
+            }
+            self.find_smallest();
+        }
+    }
+
+    /// The error that ended the iteration, if any.
+    pub fn status(&self) -> Result<(), Corruption> {
+        self.error.clone().map_or(Ok(()), Err)
+    }
+
+    pub fn valid(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn key(&self) -> Option<&InternalKey> {
+        self.current.and_then(|i| self.children[i].key())
+    }
+
+    pub fn value(&self) -> Option<&Value> {
+        self.current
+            .and_then(|i| self.children[i].current.as_ref())
+            .and_then(|(_, value)| value.as_ref())
+    }
+
+    fn find_smallest(&mut self) {
+        self.current = None;
+        if self.error.is_some() {
+            return;
+        }
+        for (i, child) in self.children.iter().enumerate() {
+            if let Some(key) = child.key() {
+                // strictly smaller, so the newest run wins a tie
+                if self.key().is_none_or(|smallest| key < smallest) {
+                    self.current = Some(i);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::iterator::{MergingIterator, SortedRun};
+    use crate::key::{InternalKey, ValueType};
+    use crate::value::Value;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    #[test]
+    fn merge() {
+        let run = |entries: &[(&str, u64, &str)]| -> Arc<dyn SortedRun> {
+            Arc::new(
+                entries
+                    .iter()
+                    .map(|(key, sequence, data)| {
+                        (
+                            InternalKey::new(key.to_string(), *sequence, ValueType::Value),
+                            Some(Value::new(data.to_string(), 0, 0)),
+                        )
+                    })
+                    .collect::<BTreeMap<_, _>>(),
+            )
+        };
+        let mut iter = MergingIterator::new(vec![
+            run(&[("b", 4, "new"), ("d", 5, "d")]),
+            run(&[("a", 1, "a"), ("b", 2, "b"), ("c", 0, "new")]),
+            run(&[("c", 0, "old"), ("e", 3, "e")]),
+        ]);
+        let mut entries = vec![];
+        iter.seek(&InternalKey::for_seek("", 0));
+        while let (Some(key), Some(value)) = (iter.key(), iter.value()) {
+            entries.push(value.to_string(format!("{}@{}", key.user_key, key.sequence)));
+            iter.next();
+        }
+        let expected: Vec<_> = [
+            ("a@1", "a"),
+            ("b@4", "new"),
+            ("b@2", "b"),
+            ("c@0", "new"),
+            ("d@5", "d"),
+            ("e@3", "e"),
+        ]
+        .iter()
+        .map(|(key, data)| Value::new(data.to_string(), 0, 0).to_string(key.to_string()))
+        .collect();
+        assert_eq!(entries, expected);
+
+        iter.seek(&InternalKey::for_seek("c", u64::MAX >> 8));
+        assert_eq!(iter.key().unwrap().user_key, "c");
+        assert!(iter.valid());
+    }
+}
@@ -0,0 +1,90 @@
+use std::cmp::Ordering;
+
+/// Largest sequence number; sequences are 56 bits so that they pack with the value type into
+/// a `u64` tag.
+pub const MAX_SEQUENCE: u64 = (1 << 56) - 1;
+
+/// Kind of mutation a record holds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ValueType {
+    Deletion = 0,
+    Value = 1,
+}
+
+/// User key with the sequence number and type of the mutation that wrote it.
+///
+/// Ordered by user key ascending, then sequence descending, so the newest version of a key is
+/// the first one found when seeking to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InternalKey {
+    pub user_key: String,
+    pub sequence: u64,
+    pub value_type: ValueType,
+}
+
+impl InternalKey {
+    pub fn new(user_key: String, sequence: u64, value_type: ValueType) -> Self {
+        Self {
+            user_key,
+            sequence,
+            value_type,
+        }
+    }
+
+    /// Key sorting before every version of `user_key` visible at `sequence`.
+    pub fn for_seek(user_key: &str, sequence: u64) -> Self {
+        Self::new(user_key.to_string(), sequence, ValueType::Value)
+    }
+
+    /// `sequence << 8 | value_type`, as persisted in records.
+    pub fn tag(&self) -> u64 {
+        self.sequence << 8 | self.value_type as u64
+    }
+
+    pub fn from_tag(user_key: String, tag: u64) -> Option<Self> {
+        let value_type = match tag & 0xff {
+            0 => ValueType::Deletion,
+            1 => ValueType::Value,
+            _ => return None,
+        };
+        Some(Self::new(user_key, tag >> 8, value_type))
+    }
+}
+
+impl Ord for InternalKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.user_key
+            .cmp(&other.user_key)
+            .then_with(|| other.sequence.cmp(&self.sequence))
+            .then_with(|| other.value_type.cmp(&self.value_type))
+    }
+}
+
+impl PartialOrd for InternalKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::{InternalKey, ValueType, MAX_SEQUENCE};
+
+    #[test]
+    fn ordering() {
+        let key = |k: &str, s| InternalKey::new(k.to_string(), s, ValueType::Value);
+        let mut keys = vec![key("b", 1), key("a", 1), key("a", 3), key("b", 2)];
+        keys.sort();
+        assert_eq!(
+            keys,
+            vec![key("a", 3), key("a", 1), key("b", 2), key("b", 1)]
+        );
+        assert!(InternalKey::for_seek("a", MAX_SEQUENCE) < key("a", MAX_SEQUENCE - 1));
+
+        let deletion = InternalKey::new("a".to_string(), MAX_SEQUENCE, ValueType::Deletion);
+        assert_eq!(
+            InternalKey::from_tag("a".to_string(), deletion.tag()),
+            Some(deletion)
+        );
+    }
+}
@@ -1,9 +1,27 @@
 mod avl;
+mod block;
+pub mod column_family;
 mod command;
+pub mod compression;
+mod crc;
+pub mod db;
 pub mod decoder;
+pub mod dump;
 pub mod executor;
+pub mod iterator;
+pub mod key;
+mod lock;
+pub mod manifest;
 pub mod memtable;
+pub mod options;
 mod record;
+pub mod repair;
+pub mod server;
+pub mod snapshot;
 pub mod sstable;
+pub mod table;
+pub mod transaction;
 mod value;
+mod varint;
 pub mod wal;
+pub mod write_batch;
@@ -0,0 +1,67 @@
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::path::{Path, PathBuf};
+
+pub(crate) const LOCK_FILE: &str = "LOCK";
+
+/// Exclusive advisory lock on the `LOCK` file of a data directory, so that only one process
+/// writes to it. Released when dropped, or by the OS if the process dies.
+pub(crate) struct DirLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl DirLock {
+    /// Locks `dir`, failing at once if another process, or another open in this one, holds it.
+    pub fn acquire(dir: &Path) -> Result<Self> {
+        let path = dir.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("failed to open {:?}", path))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => bail!(
+                "data directory {:?} is in use by another process (lock on {:?} is held)",
+                dir,
+                path
+            ),
+            Err(TryLockError::Error(e)) => {
+                return Err(e).with_context(|| format!("failed to lock {:?}", path))
+            }
+        }
+        info!("locked {:?}", path);
+        Ok(Self { file, path })
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+        info!("unlocked {:?}", self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lock::DirLock;
+    use std::fs::{create_dir_all, remove_dir_all};
+
+    #[test]
+    fn exclusive() {
+        let dir = std::env::temp_dir().join("lsm_engine_lock_exclusive");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+
+        let lock = DirLock::acquire(&dir).unwrap();
+        let error = DirLock::acquire(&dir).err().unwrap();
+        assert!(error.to_string().contains("in use by another process"));
+        drop(lock);
+        DirLock::acquire(&dir).unwrap();
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
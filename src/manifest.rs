@@ -0,0 +1,542 @@
+use crate::column_family::DEFAULT_COLUMN_FAMILY;
+use crate::crc::crc32c;
+use crate::options::{ColumnFamilyOptions, CompactionStyle};
+use anyhow::{bail, Result};
+use log::{info, warn};
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::fs::{rename, File, OpenOptions};
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::path::Path;
+use std::time::Duration;
+
+pub(crate) const MANIFEST_FILE: &str = "MANIFEST";
+const MANIFEST_TMP_FILE: &str = "MANIFEST.tmp";
+
+const TAG_LOG_NUMBER: u8 = 1;
+const TAG_NEXT_FILE_NUMBER: u8 = 2;
+const TAG_NEW_TABLE: u8 = 3;
+const TAG_DELETED_TABLE: u8 = 4;
+const TAG_LAST_SEQUENCE: u8 = 5;
+const TAG_COLUMN_FAMILY: u8 = 6;
+/// The name's length, followed by the name bytes, after the settings of the new column family.
+const TAG_ADD_COLUMN_FAMILY: u8 = 7;
+const TAG_WRITE_BUFFER_SIZE: u8 = 8;
+const TAG_COMPACTION_TRIGGER: u8 = 9;
+const TAG_DROP_COLUMN_FAMILY: u8 = 10;
+const TAG_NEXT_COLUMN_FAMILY: u8 = 11;
+/// 0 for `CompactionStyle::Merge`, 1 for `CompactionStyle::Fifo`.
+const TAG_COMPACTION_STYLE: u8 = 12;
+/// In milliseconds, only written if the column family has a TTL.
+const TAG_TTL: u8 = 13;
+
+/// A change to the set of live files, appended to the MANIFEST as one record.
+///
+/// The log number and tables belong to `column_family`, 0 being the default one, which is all
+/// that edits written before column families existed refer to.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct VersionEdit {
+    pub column_family: u32,
+    /// Creates `column_family` with this name and these settings.
+    pub add_column_family: Option<(String, ColumnFamilyOptions)>,
+    pub drop_column_family: bool,
+    pub log_number: Option<u64>,
+    pub next_file_number: Option<u64>,
+    pub next_column_family: Option<u32>,
+    pub new_tables: Vec<u64>,
+    pub deleted_tables: Vec<u64>,
+    pub last_sequence: Option<u64>,
+}
+
+impl VersionEdit {
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = Vec::new();
+        let mut push = |tag: u8, number: u64| {
+            payload.push(tag);
+            payload.extend(&number.to_le_bytes());
+        };
+        if self.column_family != 0 {
+            push(TAG_COLUMN_FAMILY, self.column_family as u64);
+        }
+        if let Some((_, options)) = &self.add_column_family {
+            push(TAG_WRITE_BUFFER_SIZE, options.write_buffer_size as u64);
+            push(TAG_COMPACTION_TRIGGER, options.compaction_trigger as u64);
+            let style = match options.compaction_style {
+                CompactionStyle::Merge => 0,
+                CompactionStyle::Fifo => 1,
+            };
+            push(TAG_COMPACTION_STYLE, style);
+            if let Some(ttl) = options.ttl {
+                push(TAG_TTL, ttl.as_millis().try_into().unwrap_or(u64::MAX));
+            }
+        }
+        if self.drop_column_family {
+            push(TAG_DROP_COLUMN_FAMILY, 0);
+        }
+        if let Some(n) = self.log_number {
+            push(TAG_LOG_NUMBER, n);
+        }
+        if let Some(n) = self.next_file_number {
+            push(TAG_NEXT_FILE_NUMBER, n);
+        }
+        if let Some(n) = self.next_column_family {
+            push(TAG_NEXT_COLUMN_FAMILY, n as u64);
+        }
+        for n in &self.new_tables {
+            push(TAG_NEW_TABLE, *n);
+        }
+        for n in &self.deleted_tables {
+            push(TAG_DELETED_TABLE, *n);
+        }
+        if let Some(n) = self.last_sequence {
+            push(TAG_LAST_SEQUENCE, n);
+        }
+        if let Some((name, _)) = &self.add_column_family {
+            payload.push(TAG_ADD_COLUMN_FAMILY);
+            payload.extend(&(name.len() as u64).to_le_bytes());
+            payload.extend(name.as_bytes());
+        }
+        payload
+    }
+
+    fn decode(payload: &[u8]) -> Result<Self> {
+        let mut edit = Self::default();
+        let entry_len = 1 + size_of::<u64>();
+        let mut options = None;
+        let mut index = 0;
+        while index < payload.len() {
+            let entry = match payload.get(index..index + entry_len) {
+                Some(entry) => entry,
+                None => bail!("invalid version edit length {}", payload.len()),
+            };
+            index += entry_len;
+            let number = u64::from_le_bytes(entry[1..].try_into()?);
+            match entry[0] {
+                TAG_COLUMN_FAMILY => edit.column_family = number.try_into()?,
+                TAG_ADD_COLUMN_FAMILY => {
+                    let len = number as usize;
+                    let name = match payload.get(index..index + len) {
+                        Some(name) => String::from_utf8(name.to_vec())?,
+                        None => bail!("invalid column family name length {}", len),
+                    };
+                    index += len;
+                    edit.add_column_family = Some((name, options.take().unwrap_or_default()));
+                }
+                TAG_WRITE_BUFFER_SIZE => {
+                    options
+                        .get_or_insert_with(ColumnFamilyOptions::default)
+                        .write_buffer_size = number as usize;
+                }
+                TAG_COMPACTION_TRIGGER => {
+                    options
+                        .get_or_insert_with(ColumnFamilyOptions::default)
+                        .compaction_trigger = number as usize;
+                }
+                TAG_COMPACTION_STYLE => {
+                    options
+                        .get_or_insert_with(ColumnFamilyOptions::default)
+                        .compaction_style = match number {
+                        0 => CompactionStyle::Merge,
+                        1 => CompactionStyle::Fifo,
+                        _ => bail!("unknown compaction style {}", number),
+                    };
+                }
+                TAG_TTL => {
+                    options.get_or_insert_with(ColumnFamilyOptions::default).ttl =
+                        Some(Duration::from_millis(number));
+                }
+                TAG_DROP_COLUMN_FAMILY => edit.drop_column_family = true,
+                TAG_LOG_NUMBER => edit.log_number = Some(number),
+                TAG_NEXT_FILE_NUMBER => edit.next_file_number = Some(number),
+                TAG_NEXT_COLUMN_FAMILY => edit.next_column_family = Some(number.try_into()?),
+                TAG_NEW_TABLE => edit.new_tables.push(number),
+                TAG_DELETED_TABLE => edit.deleted_tables.push(number),
+                TAG_LAST_SEQUENCE => edit.last_sequence = Some(number),
+                tag => bail!("unknown version edit tag {}", tag),
+            }
+        }
+        if options.is_some() {
+            bail!("column family settings without a new column family");
+        }
+        Ok(edit)
+    }
+}
+
+/// Live files of one column family.
+#[derive(Debug, Clone)]
+pub struct ColumnFamilyVersion {
+    pub name: String,
+    /// `None` for the default column family, whose settings come from `Options`.
+    pub options: Option<ColumnFamilyOptions>,
+    /// Oldest WAL that may hold versions of this column family not yet in a table.
+    pub log_number: u64,
+    /// Live table numbers, oldest first.
+    pub tables: Vec<u64>,
+}
+
+#[derive(Debug)]
+struct Version {
+    column_families: BTreeMap<u32, ColumnFamilyVersion>,
+    next_file_number: u64,
+    next_column_family: u32,
+    last_sequence: u64,
+}
+
+impl Version {
+    /// The version of an empty directory: just the default column family.
+    fn new() -> Self {
+        let mut column_families = BTreeMap::new();
+        column_families.insert(
+            0,
+            ColumnFamilyVersion {
+                name: DEFAULT_COLUMN_FAMILY.to_string(),
+                options: None,
+                log_number: 0,
+                tables: vec![],
+            },
+        );
+        Self {
+            column_families,
+            next_file_number: 1,
+            next_column_family: 1,
+            last_sequence: 0,
+        }
+    }
+
+    fn apply(&mut self, edit: &VersionEdit) {
+        if let Some(n) = edit.next_file_number {
+            self.next_file_number = self.next_file_number.max(n);
+        }
+        if let Some(n) = edit.next_column_family {
+            self.next_column_family = self.next_column_family.max(n);
+        }
+        if let Some(n) = edit.last_sequence {
+            self.last_sequence = self.last_sequence.max(n);
+        }
+        for n in &edit.new_tables {
+            self.next_file_number = self.next_file_number.max(n + 1);
+        }
+        if let Some((name, options)) = &edit.add_column_family {
+            self.next_column_family = self.next_column_family.max(edit.column_family + 1);
+            self.column_families.insert(
+                edit.column_family,
+                ColumnFamilyVersion {
+                    name: name.clone(),
+                    options: Some(options.clone()),
+                    log_number: 0,
+                    tables: vec![],
+                },
+            );
+        }
+        if edit.drop_column_family {
+            self.column_families.remove(&edit.column_family);
+        }
+        // e.g. a flush that finished after its column family was dropped
+        let column_family = match self.column_families.get_mut(&edit.column_family) {
+            Some(column_family) => column_family,
+            None => return,
+        };
+        if let Some(n) = edit.log_number {
+            column_family.log_number = n;
+        }
+        column_family
+            .tables
+            .retain(|n| !edit.deleted_tables.contains(n));
+        for n in &edit.new_tables {
+            if !column_family.tables.contains(n) {
+                column_family.tables.push(*n);
+            }
+        }
+    }
+
+    /// Edits recreating this version, the default column family's first.
+    fn snapshot(&self) -> Vec<VersionEdit> {
+        self.column_families
+            .iter()
+            .map(|(id, column_family)| VersionEdit {
+                column_family: *id,
+                add_column_family: column_family
+                    .options
+                    .clone()
+                    .map(|options| (column_family.name.clone(), options)),
+                log_number: Some(column_family.log_number),
+                new_tables: column_family.tables.clone(),
+                ..VersionEdit::default()
+            })
+            .chain(std::iter::once(VersionEdit {
+                next_file_number: Some(self.next_file_number),
+                next_column_family: Some(self.next_column_family),
+                last_sequence: Some(self.last_sequence),
+                ..VersionEdit::default()
+            }))
+            .collect()
+    }
+}
+
+/// Durable log of the column families with their SSTable sets and WAL numbers in use, the next
+/// file number and the last sequence number.
+///
+/// Each record is framed as `crc32c(u32) | length(u32) | payload`. On open the log is replayed
+/// up to the first torn or corrupt record and then rewritten as one snapshot record per column
+/// family.
+pub struct Manifest {
+    file: File,
+    is_new: bool,
+    version: Version,
+}
+
+impl Manifest {
+    pub fn open(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        let is_new = !path.exists();
+        let mut version = Version::new();
+        if is_new {
+            version.column_families.get_mut(&0).unwrap().log_number = version.next_file_number;
+            version.next_file_number += 1;
+        } else {
+            let (edits, ignored) = read_edits(&path)?;
+            if ignored > 0 {
+                warn!(
+                    "manifest {:?}: ignore {} bytes of torn or corrupt tail",
+                    path, ignored
+                );
+            }
+            for edit in edits {
+                version.apply(&edit);
+            }
+        }
+        let file = write_snapshot(dir, &version.snapshot())?;
+        info!("open manifest {:?}: {:?}", path, version);
+        Ok(Self {
+            file,
+            is_new,
+            version,
+        })
+    }
+
+    /// Whether the MANIFEST did not exist before this open.
+    pub fn is_new(&self) -> bool {
+        self.is_new
+    }
+
+    /// Live column families by id.
+    pub fn column_families(&self) -> &BTreeMap<u32, ColumnFamilyVersion> {
+        &self.version.column_families
+    }
+
+    /// Live table numbers of the column family, oldest first.
+    pub fn tables(&self, column_family: u32) -> &[u64] {
+        self.version
+            .column_families
+            .get(&column_family)
+            .map_or(&[], |c| &c.tables)
+    }
+
+    /// Live table numbers of all column families.
+    pub fn all_tables(&self) -> Vec<u64> {
+        self.version
+            .column_families
+            .values()
+            .flat_map(|c| c.tables.iter().copied())
+            .collect()
+    }
+
+    /// Oldest WAL still needed by any column family.
+    pub fn log_number(&self) -> u64 {
+        self.version
+            .column_families
+            .values()
+            .map(|c| c.log_number)
+            .min()
+            .unwrap_or(0)
+    }
+
+    /// Sequence number of the last write in a table, as of the last edit recording it.
+    pub fn last_sequence(&self) -> u64 {
+        self.version.last_sequence
+    }
+
+    /// Reserves a file number. The reservation becomes durable with the next `log_and_apply`.
+    pub fn new_file_number(&mut self) -> u64 {
+        let number = self.version.next_file_number;
+        self.version.next_file_number += 1;
+        number
+    }
+
+    /// Reserves a column family id, never reused even after the column family is dropped, so
+    /// its versions left in WALs are not mistaken for a later one's.
+    pub fn new_column_family_id(&mut self) -> u32 {
+        let id = self.version.next_column_family;
+        self.version.next_column_family += 1;
+        id
+    }
+
+    /// Makes sure `number` is never handed out again, e.g. for a WAL found at recovery whose
+    /// reservation was not yet durable.
+    pub fn mark_file_number_used(&mut self, number: u64) {
+        self.version.next_file_number = self.version.next_file_number.max(number + 1);
+    }
+
+    pub fn log_and_apply(&mut self, mut edit: VersionEdit) -> Result<()> {
+        edit.next_file_number = Some(self.version.next_file_number);
+        edit.next_column_family = Some(self.version.next_column_family);
+        self.file.write_all(&frame(&edit.encode()))?;
+        self.file.sync_data()?;
+        self.version.apply(&edit);
+        Ok(())
+    }
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(payload.len() + 2 * size_of::<u32>());
+    record.extend(&crc32c(payload).to_le_bytes());
+    record.extend(&(payload.len() as u32).to_le_bytes());
+    record.extend(payload);
+    record
+}
+
+/// Replays the MANIFEST in `dir` without rewriting it, e.g. to inspect a directory offline.
+///
+/// Returns the live column families and the length of the torn or corrupt tail ignored.
+pub fn read_column_families(dir: &Path) -> Result<(BTreeMap<u32, ColumnFamilyVersion>, usize)> {
+    let mut version = Version::new();
+    let (edits, ignored) = read_edits(&dir.join(MANIFEST_FILE))?;
+    for edit in edits {
+        version.apply(&edit);
+    }
+    Ok((version.column_families, ignored))
+}
+
+/// The edits up to the first torn or corrupt record, with the length of the tail from there.
+fn read_edits(path: &Path) -> Result<(Vec<VersionEdit>, usize)> {
+    let mut buffer = Vec::new();
+    File::open(path)?.read_to_end(&mut buffer)?;
+    let header_len = 2 * size_of::<u32>();
+    let mut index = 0;
+    let mut edits = vec![];
+    while index + header_len <= buffer.len() {
+        let crc = u32::from_le_bytes(buffer[index..index + 4].try_into()?);
+        let len = u32::from_le_bytes(buffer[index + 4..index + 8].try_into()?) as usize;
+        let start = index + header_len;
+        if start + len > buffer.len() || crc32c(&buffer[start..start + len]) != crc {
+            break;
+        }
+        edits.push(VersionEdit::decode(&buffer[start..start + len])?);
+        index = start + len;
+    }
+    Ok((edits, buffer.len() - index))
+}
+
+/// Atomically replaces the MANIFEST with the snapshot records and returns it opened for append.
+fn write_snapshot(dir: &Path, snapshot: &[VersionEdit]) -> Result<File> {
+    let tmp_path = dir.join(MANIFEST_TMP_FILE);
+    let path = dir.join(MANIFEST_FILE);
+    let mut tmp = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    for edit in snapshot {
+        tmp.write_all(&frame(&edit.encode()))?;
+    }
+    tmp.sync_all()?;
+    rename(&tmp_path, &path)?;
+    sync_dir(dir)?;
+    Ok(OpenOptions::new().append(true).open(path)?)
+}
+
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::{Manifest, VersionEdit, MANIFEST_FILE};
+    use crate::options::{ColumnFamilyOptions, CompactionStyle};
+    use std::fs::{create_dir_all, remove_dir_all, OpenOptions};
+    use std::io::Write;
+    use std::time::Duration;
+
+    #[test]
+    fn replay() {
+        let dir = std::env::temp_dir().join("lsm_engine_manifest_replay");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+
+        let mut manifest = Manifest::open(&dir).unwrap();
+        assert!(manifest.is_new());
+        let first = manifest.new_file_number();
+        let second = manifest.new_file_number();
+        manifest
+            .log_and_apply(VersionEdit {
+                log_number: Some(7),
+                last_sequence: Some(100),
+                new_tables: vec![first, second],
+                ..VersionEdit::default()
+            })
+            .unwrap();
+        manifest
+            .log_and_apply(VersionEdit {
+                deleted_tables: vec![first],
+                ..VersionEdit::default()
+            })
+            .unwrap();
+        let options = ColumnFamilyOptions {
+            write_buffer_size: 10,
+            compaction_trigger: 2,
+            compaction_style: CompactionStyle::Fifo,
+            ttl: Some(Duration::from_secs(3600)),
+        };
+        let add = |manifest: &mut Manifest, name: &str| {
+            let id = manifest.new_column_family_id();
+            let table = manifest.new_file_number();
+            manifest
+                .log_and_apply(VersionEdit {
+                    column_family: id,
+                    add_column_family: Some((name.to_string(), options.clone())),
+                    log_number: Some(9),
+                    new_tables: vec![table],
+                    ..VersionEdit::default()
+                })
+                .unwrap();
+            (id, table)
+        };
+        let (dropped, _) = add(&mut manifest, "dropped");
+        let (kept, kept_table) = add(&mut manifest, "kept");
+        manifest
+            .log_and_apply(VersionEdit {
+                column_family: dropped,
+                drop_column_family: true,
+                ..VersionEdit::default()
+            })
+            .unwrap();
+        drop(manifest);
+
+        // a torn tail record must be ignored
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(MANIFEST_FILE))
+            .unwrap();
+        file.write_all(&[1, 2, 3, 4, 200, 0]).unwrap();
+        drop(file);
+
+        let mut manifest = Manifest::open(&dir).unwrap();
+        assert!(!manifest.is_new());
+        assert_eq!(manifest.tables(0), &[second]);
+        assert_eq!(manifest.tables(kept), &[kept_table]);
+        assert_eq!(manifest.all_tables(), vec![second, kept_table]);
+        assert_eq!(manifest.log_number(), 7);
+        assert_eq!(manifest.last_sequence(), 100);
+        assert_eq!(manifest.new_file_number(), kept_table + 1);
+        assert_eq!(
+            manifest.column_families().keys().collect::<Vec<_>>(),
+            vec![&0, &kept]
+        );
+        assert_eq!(manifest.column_families()[&kept].name, "kept");
+        assert_eq!(manifest.column_families()[&kept].options, Some(options));
+        assert_eq!(manifest.new_column_family_id(), dropped + 2);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,69 +1,207 @@
-use crate::avl::AvlTreeMap;
+use crate::avl::{AvlNode, AvlTreeMap};
+use crate::iterator::SortedRun;
+use crate::key::InternalKey;
+use crate::table::Corruption;
 use crate::value::Value;
-use crate::wal::Wal;
-use anyhow::Result;
-use log::info;
-use std::iter::FromIterator;
-
-pub trait Memtable: Sync + Send {
-    fn insert(&mut self, key: String, value: Value) -> Result<()>;
-    fn delete(&mut self, key: &String) -> Result<()>;
-    fn search(&self, key: &String) -> Option<Option<&Value>>;
-    fn to_vec(&self) -> Vec<(&String, &Value)>;
-    fn to_records(&self) -> Vec<(&String, Option<&Value>)>;
-    fn clear(&mut self) -> Result<()>;
+use std::mem::size_of;
+use std::ops::Bound;
+use std::sync::RwLock;
+
+/// Memtables synchronize internally, so readers and iterators can share one with the writer.
+pub trait Memtable: SortedRun {
+    /// Adds a version of the key; `None` is a deletion.
+    fn add(&self, key: InternalKey, value: Option<Value>) {
+        self.add_all(vec![(key, value)]);
+    }
+    /// Adds the versions under one lock, so readers see all or none of them.
+    fn add_all(&self, records: Vec<(InternalKey, Option<Value>)>);
+    /// Newest version of the key with a sequence up to `sequence`, as its sequence and value,
+    /// `None` for a deletion.
+    fn search(&self, key: &str, sequence: u64) -> Option<(u64, Option<Value>)>;
+    /// Number of keys whose newest version is not deleted.
+    fn live_keys(&self) -> usize;
+    /// Every version, in internal key order.
+    fn to_records(&self) -> Vec<(InternalKey, Option<Value>)>;
+    /// Number of the first WAL segment holding this memtable's records. It spans the segments
+    /// from there up to the next memtable's first one, which are needed until it is flushed.
+    fn log_number(&self) -> u64;
+    /// Approximate memory used by keys, values and tree nodes, in bytes.
+    fn approximate_size(&self) -> usize;
+    /// Number of versions, tombstones included.
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
+const NODE_OVERHEAD: usize = size_of::<AvlNode<InternalKey, Option<Value>>>() + size_of::<usize>();
+
+struct Inner {
+    map: AvlTreeMap<InternalKey, Option<Value>>,
+    size: usize,
+    len: usize,
+}
+
+/// In-memory sorted map of recent versions. The records are logged to the WAL segments
+/// starting at `log_number` by the caller before they are added here.
 pub struct AvlMemtable {
-    wal: Wal,
-    map: AvlTreeMap<String, Option<Value>>,
+    log_number: u64,
+    inner: RwLock<Inner>,
 }
 impl AvlMemtable {
-    pub fn new(mut wal: Wal) -> Result<Self> {
-        let vec = wal.recover()?;
-        Ok(Self {
-            wal,
-            map: AvlTreeMap::from_iter(vec),
-        })
+    pub fn new(log_number: u64) -> Self {
+        Self {
+            log_number,
+            inner: RwLock::new(Inner {
+                map: AvlTreeMap::new(),
+                size: 0,
+                len: 0,
+            }),
+        }
     }
 }
 impl Memtable for AvlMemtable {
-    fn insert(&mut self, key: String, value: Value) -> Result<()> {
-        self.wal.write(&key, Option::from(&value))?;
-        self.map.insert(key, Option::from(value));
-        Ok(())
-    }
-
-    fn delete(&mut self, key: &String) -> Result<()> {
-        self.wal.write(key, None)?;
-        self.map.insert((*key).clone(), None);
-        Ok(())
+    fn add_all(&self, records: Vec<(InternalKey, Option<Value>)>) {
+        let mut inner = self.inner.write().unwrap();
+        for (key, value) in records {
+            let size = NODE_OVERHEAD
+                + key.user_key.len()
+                + value.as_ref().map_or(0, Value::approximate_size);
+            // sequence numbers are unique, so a version is never replaced
+            if inner.map.insert(key, value).is_none() {
+                inner.size += size;
+                inner.len += 1;
+            }
+        }
     }
 
-    fn search(&self, key: &String) -> Option<Option<&Value>> {
-        self.map.search(key).map(|value| value.as_ref())
+    fn search(&self, key: &str, sequence: u64) -> Option<(u64, Option<Value>)> {
+        self.inner
+            .read()
+            .unwrap()
+            .map
+            .seek(&InternalKey::for_seek(key, sequence))
+            .next()
+            .filter(|(k, _)| k.user_key == key)
+            .map(|(k, value)| (k.sequence, value.clone()))
     }
 
-    fn to_vec(&self) -> Vec<(&String, &Value)> {
-        self.map.iter().fold(vec![], |mut state, (key, value)| {
-            if let Some(v) = value {
-                state.push((key, v))
+    fn live_keys(&self) -> usize {
+        let inner = self.inner.read().unwrap();
+        let mut count = 0;
+        let mut last_key = None;
+        for (key, value) in inner.map.iter() {
+            if last_key != Some(&key.user_key) && value.is_some() {
+                count += 1;
             }
-            state
-        })
+            last_key = Some(&key.user_key);
+        }
+        count
     }
 
-    fn to_records(&self) -> Vec<(&String, Option<&Value>)> {
-        self.map
+    fn to_records(&self) -> Vec<(InternalKey, Option<Value>)> {
+        self.inner
+            .read()
+            .unwrap()
+            .map
             .iter()
-            .map(|(key, value)| (key, value.as_ref()))
+            .map(|(key, value)| (key.clone(), value.clone()))
             .collect()
     }
 
-    fn clear(&mut self) -> Result<()> {
-        self.wal.clear()?;
-        self.map = AvlTreeMap::new();
-        info!("clear map");
-        Ok(())
+    fn log_number(&self) -> u64 {
+        self.log_number
+    }
+
+    fn approximate_size(&self) -> usize {
+        self.inner.read().unwrap().size
+    }
+
+    fn len(&self) -> usize {
+        self.inner.read().unwrap().len
+    }
+}
+
+impl SortedRun for AvlMemtable {
+    fn next_after(
+        &self,
+        bound: Bound<&InternalKey>,
+    ) -> Result<Option<(InternalKey, Option<Value>)>, Corruption> {
+        let inner = self.inner.read().unwrap();
+        let mut iter = match bound {
+            Bound::Included(key) | Bound::Excluded(key) => inner.map.seek(key),
+            Bound::Unbounded => inner.map.iter(),
+        };
+        Ok(iter
+            .find(|(key, _)| !matches!(bound, Bound::Excluded(excluded) if *key == excluded))
+            .map(|(key, value)| (key.clone(), value.clone())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::{InternalKey, ValueType, MAX_SEQUENCE};
+    use crate::memtable::{AvlMemtable, Memtable};
+    use crate::value::Value;
+
+    fn put(memtable: &AvlMemtable, sequence: u64, key: &str, data: Option<String>) {
+        let value_type = if data.is_some() {
+            ValueType::Value
+        } else {
+            ValueType::Deletion
+        };
+        memtable.add(
+            InternalKey::new(key.to_string(), sequence, value_type),
+            data.map(|d| Value::new(d, 0, 0)),
+        );
+    }
+
+    #[test]
+    fn approximate_size() {
+        let memtable = AvlMemtable::new(1);
+        assert_eq!(memtable.approximate_size(), 0);
+
+        put(&memtable, 1, "key", Some("a".repeat(100)));
+        let with_long_value = memtable.approximate_size();
+        assert!(with_long_value > 103);
+
+        // every version is kept
+        put(&memtable, 2, "key", Some("a".to_string()));
+        assert_eq!(memtable.approximate_size(), with_long_value * 2 - 99);
+        assert_eq!(memtable.len(), 2);
+
+        put(&memtable, 3, "key", None);
+        put(&memtable, 4, "other", None);
+        assert_eq!(memtable.len(), 4);
+    }
+
+    #[test]
+    fn newest_version() {
+        let memtable = AvlMemtable::new(1);
+        put(&memtable, 1, "a", Some("1".to_string()));
+        put(&memtable, 3, "a", Some("3".to_string()));
+        put(&memtable, 2, "a", Some("2".to_string()));
+        put(&memtable, 4, "b", None);
+        put(&memtable, 5, "c", Some("5".to_string()));
+
+        let newest = |key| {
+            memtable
+                .search(key, MAX_SEQUENCE)
+                .map(|(_, v)| v.map(|v| v.to_string(key.to_string())))
+        };
+        assert_eq!(
+            newest("a"),
+            Some(Some(
+                Value::new("3".to_string(), 0, 0).to_string("a".to_string())
+            ))
+        );
+        assert_eq!(newest("b"), Some(None));
+        assert_eq!(newest("0"), None);
+        assert_eq!(
+            memtable.search("a", 2),
+            Some((2, Some(Value::new("2".to_string(), 0, 0))))
+        );
+        assert!(memtable.search("b", 3).is_none());
+        assert_eq!(memtable.live_keys(), 2);
     }
 }
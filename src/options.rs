@@ -0,0 +1,286 @@
+use crate::compression::Compression;
+use crate::snapshot::Snapshot;
+use anyhow::anyhow;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How WAL recovery treats a record with a bad checksum or framing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WalRecoveryMode {
+    /// Keep the records before it and drop the rest of the file, e.g. a torn tail write.
+    TolerateCorruptedTailRecords,
+    /// Drop only the corrupt bytes and continue with the next intact record.
+    SkipAnyCorruptedRecords,
+}
+
+impl fmt::Display for WalRecoveryMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalRecoveryMode::TolerateCorruptedTailRecords => {
+                write!(f, "tolerate_corrupted_tail_records")
+            }
+            WalRecoveryMode::SkipAnyCorruptedRecords => write!(f, "skip_any_corrupted_records"),
+        }
+    }
+}
+
+/// When WAL writes are fsynced, i.e. what a successful write survives.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WalSyncPolicy {
+    /// Every write is fsynced before it is acknowledged; survives power loss.
+    Always,
+    /// A background thread fsyncs every `interval_ms`; a power loss drops up to that window.
+    Periodic { interval_ms: u64 },
+    /// Writes are left in the OS page cache; survives a process crash only.
+    Never,
+}
+
+impl fmt::Display for WalSyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalSyncPolicy::Always => write!(f, "always"),
+            WalSyncPolicy::Periodic { interval_ms } => write!(f, "periodic_{}ms", interval_ms),
+            WalSyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+impl FromStr for WalSyncPolicy {
+    type Err = anyhow::Error;
+
+    /// Parses the `Display` form: `always`, `never` or `periodic_<n>ms`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(WalSyncPolicy::Always),
+            "never" => Ok(WalSyncPolicy::Never),
+            _ => s
+                .strip_prefix("periodic_")
+                .and_then(|rest| rest.strip_suffix("ms"))
+                .and_then(|interval| interval.parse().ok())
+                .map(|interval_ms| WalSyncPolicy::Periodic { interval_ms })
+                .ok_or_else(|| anyhow!("invalid wal sync policy: {}", s)),
+        }
+    }
+}
+
+/// What a column family does with its tables once there are `compaction_trigger` of them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CompactionStyle {
+    /// Merge them all into one, dropping the versions no reader can see.
+    Merge,
+    /// Delete the oldest tables beyond `compaction_trigger` with all their versions, e.g. for
+    /// logs or metrics only kept while recent.
+    Fifo,
+}
+
+impl fmt::Display for CompactionStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CompactionStyle::Merge => write!(f, "merge"),
+            CompactionStyle::Fifo => write!(f, "fifo"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Approximate memtable size in bytes at which it is switched out and flushed.
+    pub write_buffer_size: usize,
+    /// Writes stall while this many immutable memtables are waiting to be flushed.
+    pub max_immutable_memtables: usize,
+    /// Number of SSTables at which `compaction_style` applies.
+    pub compaction_trigger: usize,
+    pub compaction_style: CompactionStyle,
+    /// Tables written longer ago than this are deleted after a flush; `None` keeps them.
+    pub ttl: Option<Duration>,
+    pub wal_recovery_mode: WalRecoveryMode,
+    pub wal_sync_policy: WalSyncPolicy,
+    /// Size in bytes at which the WAL is rotated to a new segment, besides on memtable switch.
+    pub wal_segment_size: u64,
+    /// Number of obsolete WAL segments kept in `wal/archive`; `None` deletes them instead.
+    pub wal_archive_retention: Option<usize>,
+    /// Block compression of new tables by level: 0 for flushed tables, 1 for the output of
+    /// compaction. Levels past the end use the last entry.
+    pub compression_per_level: Vec<Compression>,
+    /// Whether block and file checksums are verified when tables are read.
+    pub verify_checksums: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            write_buffer_size: 4 * 1024 * 1024,
+            max_immutable_memtables: 4,
+            compaction_trigger: 4,
+            compaction_style: CompactionStyle::Merge,
+            ttl: None,
+            wal_recovery_mode: WalRecoveryMode::TolerateCorruptedTailRecords,
+            wal_sync_policy: WalSyncPolicy::Never,
+            wal_segment_size: 16 * 1024 * 1024,
+            wal_archive_retention: None,
+            compression_per_level: vec![Compression::None, Compression::Snappy],
+            verify_checksums: true,
+        }
+    }
+}
+
+impl Options {
+    /// Settings of the default column family.
+    pub fn column_family_options(&self) -> ColumnFamilyOptions {
+        ColumnFamilyOptions {
+            write_buffer_size: self.write_buffer_size,
+            compaction_trigger: self.compaction_trigger,
+            compaction_style: self.compaction_style,
+            ttl: self.ttl,
+        }
+    }
+
+    /// Name and value of every setting, as reported by `stats settings`.
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("write_buffer_size", self.write_buffer_size.to_string()),
+            (
+                "max_immutable_memtables",
+                self.max_immutable_memtables.to_string(),
+            ),
+            ("compaction_trigger", self.compaction_trigger.to_string()),
+            ("compaction_style", self.compaction_style.to_string()),
+            (
+                "ttl",
+                self.ttl
+                    .map_or("none".to_string(), |t| t.as_secs().to_string()),
+            ),
+            ("wal_recovery_mode", self.wal_recovery_mode.to_string()),
+            ("wal_sync_policy", self.wal_sync_policy.to_string()),
+            ("wal_segment_size", self.wal_segment_size.to_string()),
+            (
+                "wal_archive_retention",
+                self.wal_archive_retention
+                    .map_or("none".to_string(), |n| n.to_string()),
+            ),
+            (
+                "compression_per_level",
+                self.compression_per_level
+                    .iter()
+                    .map(Compression::to_string)
+                    .collect::<Vec<_>>()
+                    .join(","),
+            ),
+            ("verify_checksums", self.verify_checksums.to_string()),
+        ]
+    }
+}
+
+/// Settings of the server's connection handling.
+#[derive(Clone, Debug)]
+pub struct ServerOptions {
+    /// Number of threads running commands; connections are served by one event loop thread.
+    pub worker_threads: usize,
+    /// Open connections beyond which new ones are rejected; each one takes a file descriptor.
+    pub max_connections: usize,
+    /// Connections sending nothing for this long are closed; `None` keeps them open.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ServerOptions {
+    fn default() -> Self {
+        Self {
+            worker_threads: 4,
+            max_connections: 32 * 1024,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl ServerOptions {
+    /// Name and value of every setting, as reported by `stats settings`.
+    pub fn settings(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("worker_threads", self.worker_threads.to_string()),
+            ("max_connections", self.max_connections.to_string()),
+            (
+                "idle_timeout",
+                self.idle_timeout
+                    .map_or("none".to_string(), |t| t.as_secs().to_string()),
+            ),
+        ]
+    }
+}
+
+/// Settings of one column family, given to `Db::create_column_family`. The default column
+/// family takes them from `Options`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ColumnFamilyOptions {
+    /// Approximate memtable size in bytes at which it is switched out and flushed.
+    pub write_buffer_size: usize,
+    /// Number of SSTables at which `compaction_style` applies.
+    pub compaction_trigger: usize,
+    pub compaction_style: CompactionStyle,
+    /// Tables written longer ago than this are deleted after a flush; `None` keeps them.
+    pub ttl: Option<Duration>,
+}
+
+impl Default for ColumnFamilyOptions {
+    fn default() -> Self {
+        Options::default().column_family_options()
+    }
+}
+
+/// Options for a single read.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOptions<'a> {
+    /// Read as of this snapshot instead of the latest state.
+    pub snapshot: Option<&'a Snapshot>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::options::{Options, WalSyncPolicy};
+
+    #[test]
+    fn parse_wal_sync_policy() {
+        for policy in [
+            WalSyncPolicy::Always,
+            WalSyncPolicy::Periodic { interval_ms: 250 },
+            WalSyncPolicy::Never,
+        ] {
+            assert_eq!(policy.to_string().parse::<WalSyncPolicy>().unwrap(), policy);
+        }
+        assert_eq!(
+            "periodic_5ms".parse::<WalSyncPolicy>().unwrap(),
+            WalSyncPolicy::Periodic { interval_ms: 5 }
+        );
+        for invalid in [
+            "",
+            "sometimes",
+            "periodic",
+            "periodic_ms",
+            "periodic_5",
+            "periodic_-1ms",
+        ] {
+            assert!(invalid.parse::<WalSyncPolicy>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn settings() {
+        let options = Options {
+            wal_sync_policy: WalSyncPolicy::Periodic { interval_ms: 100 },
+            wal_archive_retention: Some(2),
+            ..Options::default()
+        };
+        let settings = options.settings();
+        let setting = |name| {
+            settings
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, value)| value.as_str())
+        };
+        assert_eq!(setting("wal_sync_policy"), Some("periodic_100ms"));
+        assert_eq!(setting("wal_archive_retention"), Some("2"));
+        assert_eq!(setting("compression_per_level"), Some("none,snappy"));
+        assert_eq!(setting("compaction_style"), Some("merge"));
+        assert_eq!(setting("ttl"), Some("none"));
+    }
+}
@@ -1,62 +1,206 @@
+use crate::key::{InternalKey, ValueType};
 use crate::value::Value;
-use anyhow::Result;
-use std::collections::HashSet;
-use std::convert::TryInto;
+use crate::varint::{get_bytes, get_varint, put_varint};
+use anyhow::{anyhow, bail, Result};
+use std::convert::{TryFrom, TryInto};
 use std::fs::OpenOptions;
 use std::io::Read;
 use std::mem::size_of;
 use std::path::Path;
 
-pub fn decode_file(path: &Path) -> Result<Vec<(String, Option<Value>)>> {
+/// Reads every record of a file of length-suffixed legacy records, in write order.
+pub fn decode_file(path: &Path) -> Result<Vec<(InternalKey, Option<Value>)>> {
     let mut file = OpenOptions::new().read(true).open(path)?;
     let mut buffer = Vec::new();
     file.read_to_end(&mut buffer)?;
+    decode_records(&buffer)
+}
+
+/// Decodes length-suffixed legacy records, in write order.
+pub fn decode_records(buffer: &[u8]) -> Result<Vec<(InternalKey, Option<Value>)>> {
     let mut index = buffer.len();
-    let mut exist_key = HashSet::new();
     let mut vec = vec![];
     while index > 0 {
-        index -= size_of::<i32>();
-        let binary_len =
-            i32::from_le_bytes(buffer[index..(index + size_of::<i32>())].try_into()?) as usize;
-        index -= binary_len;
-        let (key, value) = decode(buffer[index..(index + binary_len)].to_vec())?;
-        if !exist_key.contains(&key) {
-            exist_key.insert(key.clone());
-            vec.push((key, value));
-        }
+        let end = index;
+        let record = take_back(buffer, &mut index, size_of::<i32>())
+            .and_then(|len| {
+                let len = i32::from_le_bytes(len.try_into()?);
+                take_back(buffer, &mut index, usize::try_from(len)?)
+            })
+            .and_then(|record| decode_legacy(record.to_vec()))
+            .map_err(|e| anyhow!("record ending at offset {}: {}", end, e))?;
+        vec.push(record);
     }
+    vec.reverse();
     Ok(vec)
 }
 
-fn decode(vec: Vec<u8>) -> Result<(String, Option<Value>)> {
+/// Returns the `len` bytes of `buffer` before `end` and moves `end` to their start.
+pub(crate) fn take_back<'a>(buffer: &'a [u8], end: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let start = match end.checked_sub(len) {
+        Some(start) => start,
+        None => bail!("truncated: {} bytes wanted, {} left", len, end),
+    };
+    let bytes = &buffer[start..*end];
+    *end = start;
+    Ok(bytes)
+}
+
+/// Decodes a record in the legacy encoding, see `encode_legacy`. Records written before
+/// sequence numbers existed have no tag and get sequence 0, which callers may replace.
+pub fn decode_legacy(vec: Vec<u8>) -> Result<(InternalKey, Option<Value>)> {
     let mut index = vec.len();
-    index -= size_of::<i16>();
-    let key_len = i16::from_le_bytes(vec[index..(index + size_of::<i16>())].try_into()?) as usize;
-    index -= key_len;
-    let key = String::from_utf8(vec[index..(index + key_len)].to_vec())?;
-
-    index -= size_of::<i32>();
-    let value_len = i32::from_le_bytes(vec[index..(index + size_of::<i32>())].try_into()?);
-    if value_len >= 0 {
-        index -= value_len as usize;
-        let value = Value::from_bytes(vec[index..(index + value_len as usize)].to_vec())?;
-        Ok((key, Option::from(value)))
+    let key_len = i16::from_le_bytes(take_back(&vec, &mut index, size_of::<i16>())?.try_into()?);
+    let key = take_back(&vec, &mut index, usize::try_from(key_len)?)?;
+    let key = String::from_utf8(key.to_vec())?;
+
+    let value_len = i32::from_le_bytes(take_back(&vec, &mut index, size_of::<i32>())?.try_into()?);
+    let value = if value_len >= 0 {
+        let value = take_back(&vec, &mut index, value_len as usize)?;
+        Some(Value::from_bytes(value.to_vec())?)
     } else {
-        Ok((key, None))
+        None
+    };
+    let value_type = if value.is_some() {
+        ValueType::Value
+    } else {
+        ValueType::Deletion
+    };
+    let key = match index {
+        0 => InternalKey::new(key, 0, value_type),
+        8 => match InternalKey::from_tag(key, u64::from_le_bytes(vec[..index].try_into()?)) {
+            Some(key) if key.value_type == value_type => key,
+            _ => bail!("invalid record tag"),
+        },
+        _ => bail!("invalid record length {}", vec.len()),
+    };
+    Ok((key, value))
+}
+
+/// How the records of a file are encoded, given by the file's format version.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RecordFormat {
+    /// Files without a format version, see `encode_legacy`.
+    Legacy,
+    /// See `encode`.
+    Varint,
+}
+
+impl RecordFormat {
+    pub fn decode(self, record: &[u8]) -> Result<(InternalKey, Option<Value>)> {
+        match self {
+            RecordFormat::Legacy => decode_legacy(record.to_vec()),
+            RecordFormat::Varint => decode(record),
+        }
     }
 }
-const TOMBSTONE: i32 = -1;
 
-pub fn encode(key: &String, value: Option<&Value>) -> Vec<u8> {
+/// Encodes a record as `tag | key length | key`, followed for a value by the value as written
+/// by `put_value`, all integers varints.
+pub fn encode(key: &InternalKey, value: Option<&Value>) -> Vec<u8> {
     let mut record = Vec::new();
+    put_varint(&mut record, key.tag());
+    put_varint(&mut record, key.user_key.len() as u64);
+    record.extend(key.user_key.as_bytes());
+    if let Some(v) = value {
+        put_value(&mut record, v);
+    }
+    record
+}
+
+/// Appends the value as `flags | exptime | data length | data`, integers as varints.
+pub(crate) fn put_value(buffer: &mut Vec<u8>, value: &Value) {
+    put_varint(buffer, value.flags() as u64);
+    put_varint(buffer, value.exptime() as u64);
+    put_varint(buffer, value.data().len() as u64);
+    buffer.extend(value.data().as_bytes());
+}
+
+/// Reads the value at `index` written by `put_value` and moves `index` past it.
+pub(crate) fn get_value(buffer: &[u8], index: &mut usize) -> Result<Value> {
+    let flags = usize::try_from(get_varint(buffer, index)?)?;
+    let exptime = usize::try_from(get_varint(buffer, index)?)?;
+    let data_len = usize::try_from(get_varint(buffer, index)?)?;
+    let data = String::from_utf8(get_bytes(buffer, index, data_len)?.to_vec())?;
+    Ok(Value::new(data, flags, exptime))
+}
+
+/// Decodes a record written by `encode`.
+pub fn decode(record: &[u8]) -> Result<(InternalKey, Option<Value>)> {
+    let mut index = 0;
+    let tag = get_varint(record, &mut index)?;
+    let key_len = usize::try_from(get_varint(record, &mut index)?)?;
+    let user_key = String::from_utf8(get_bytes(record, &mut index, key_len)?.to_vec())?;
+    let key = match InternalKey::from_tag(user_key, tag) {
+        Some(key) => key,
+        None => bail!("invalid record tag {}", tag),
+    };
+    let value = match key.value_type {
+        ValueType::Value => Some(get_value(record, &mut index)?),
+        ValueType::Deletion => None,
+    };
+    if index != record.len() {
+        bail!("{} bytes after the record", record.len() - index);
+    }
+    Ok((key, value))
+}
+
+/// Encodes a record as written before format versions:
+/// `tag(u64) | value | value length(i32) | key | key length(i16)`, a tombstone having no value
+/// and length -1. It is decoded from the end, so the tag is an optional prefix.
+#[cfg(test)]
+pub fn encode_legacy(key: &InternalKey, value: Option<&Value>) -> Vec<u8> {
+    let mut record = Vec::new();
+    record.extend(&key.tag().to_le_bytes());
     if let Some(v) = value {
         record.extend(v.as_bytes());
         let len = v.as_bytes().len() as i32;
         record.extend(len.to_le_bytes().to_vec());
     } else {
-        record.extend(TOMBSTONE.to_le_bytes().to_vec());
+        record.extend((-1i32).to_le_bytes().to_vec());
     }
-    record.extend(key.as_bytes());
-    record.extend(&(key.len() as i16).to_le_bytes());
+    record.extend(key.user_key.as_bytes());
+    record.extend(&(key.user_key.len() as i16).to_le_bytes());
     record
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::key::{InternalKey, ValueType};
+    use crate::record::{decode, decode_legacy, encode, encode_legacy};
+    use crate::value::Value;
+
+    #[test]
+    fn encode_decode() {
+        let key = InternalKey::new("key".to_string(), 42, ValueType::Value);
+        let value = Value::new("value".to_string(), 1, 2);
+        let record = encode(&key, Some(&value));
+        // tag(2) | key length(1) | key(3) | flags(1) | exptime(1) | data length(1) | data(5)
+        assert_eq!(record.len(), 14);
+        let (decoded, decoded_value) = decode(&record).unwrap();
+        assert_eq!(decoded, key);
+        assert_eq!(decoded_value, Some(value.clone()));
+        assert!(decode(&record[..record.len() - 1]).is_err());
+        let deletion = InternalKey::new("key".to_string(), 7, ValueType::Deletion);
+        assert_eq!(
+            decode(&encode(&deletion, None)).unwrap(),
+            (deletion.clone(), None)
+        );
+
+        let (decoded, legacy_value) = decode_legacy(encode_legacy(&key, Some(&value))).unwrap();
+        assert_eq!(decoded, key);
+        assert_eq!(
+            legacy_value.unwrap().to_string("key".to_string()),
+            "VALUE key 1 2 5\nvalue\n"
+        );
+
+        // a legacy record without tag is read with sequence 0
+        let legacy = encode_legacy(&deletion, None)[8..].to_vec();
+        let (decoded, value) = decode_legacy(legacy).unwrap();
+        assert_eq!(
+            decoded,
+            InternalKey::new("key".to_string(), 0, ValueType::Deletion)
+        );
+        assert!(value.is_none());
+    }
+}
@@ -0,0 +1,499 @@
+use crate::compression::Compression;
+use crate::db::{SSTABLE_DIR, WAL_DIR};
+use crate::key::InternalKey;
+use crate::lock::DirLock;
+use crate::manifest::{
+    read_column_families, ColumnFamilyVersion, Manifest, VersionEdit, MANIFEST_FILE,
+};
+use crate::options::{Options, WalRecoveryMode};
+use crate::record::decode_file;
+use crate::sstable::{parse_table_file_name, prepare_dir, table_file_name, write_table_file};
+use crate::table;
+use crate::value::Value;
+use crate::wal::{log_file_name, parse_log_file_name, read_log, write_log, LEGACY_WAL_FILE};
+use anyhow::{bail, Result};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Subdirectory damaged files are moved to by `repair`, one subdirectory per run.
+const LOST_DIR: &str = "lost";
+
+/// What `verify` found in one file of a data directory.
+#[derive(Debug)]
+pub struct FileReport {
+    pub path: PathBuf,
+    /// e.g. the number of records read.
+    pub summary: String,
+    pub problems: Vec<String>,
+}
+
+/// Result of checking a data directory, see `verify`.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub files: Vec<FileReport>,
+    /// Table files the MANIFEST does not reference, which the next open deletes.
+    pub unreferenced_tables: Vec<PathBuf>,
+}
+
+impl Report {
+    pub fn is_ok(&self) -> bool {
+        self.files.iter().all(|f| f.problems.is_empty())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for file in &self.files {
+            let status = if file.problems.is_empty() {
+                "ok"
+            } else {
+                "BAD"
+            };
+            writeln!(f, "{:<3} {:?}: {}", status, file.path, file.summary)?;
+            for problem in &file.problems {
+                writeln!(f, "      {}", problem)?;
+            }
+        }
+        for path in &self.unreferenced_tables {
+            writeln!(f, "    {:?}: not referenced by the MANIFEST", path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks the MANIFEST, every WAL segment and every table of the data directory `dir` without
+/// changing anything: framing, checksums and the order of sequences and keys.
+pub fn verify(dir: &Path) -> Result<Report> {
+    if !dir.is_dir() {
+        bail!("no data directory at {:?}", dir);
+    }
+    let mut report = Report::default();
+    let families = verify_manifest(dir, &mut report);
+    let mut referenced = BTreeMap::new();
+    for family in families.values() {
+        for number in &family.tables {
+            referenced.insert(*number, family.name.clone());
+        }
+    }
+
+    let sstable_dir = dir.join(SSTABLE_DIR);
+    let on_disk = list_numbers(&sstable_dir, parse_table_file_name)?;
+    for (number, name) in &referenced {
+        if !on_disk.contains(number) {
+            report.files.push(FileReport {
+                path: sstable_dir.join(table_file_name(*number)),
+                summary: format!("table of column family {}", name),
+                problems: vec!["missing".to_string()],
+            });
+        }
+    }
+    for number in on_disk {
+        let path = sstable_dir.join(table_file_name(number));
+        let name = match referenced.get(&number) {
+            Some(name) => name,
+            None => {
+                report.unreferenced_tables.push(path);
+                continue;
+            }
+        };
+        let (records, problems) = check_table(&path)?;
+        report.files.push(FileReport {
+            path,
+            summary: format!("{} records of column family {}", records, name),
+            problems,
+        });
+    }
+
+    let wal_dir = dir.join(WAL_DIR);
+    let legacy_path = wal_dir.join(LEGACY_WAL_FILE);
+    if legacy_path.exists() {
+        let (summary, problems) = match decode_file(&legacy_path) {
+            Ok(records) => (format!("{} records", records.len()), vec![]),
+            Err(e) => ("unreadable".to_string(), vec![format!("{:#}", e)]),
+        };
+        report.files.push(FileReport {
+            path: legacy_path,
+            summary,
+            problems,
+        });
+    }
+    let mut last_sequence = 0;
+    for number in list_numbers(&wal_dir, parse_log_file_name)? {
+        let path = wal_dir.join(log_file_name(number));
+        let recovered = read_log(&path, WalRecoveryMode::SkipAnyCorruptedRecords)?;
+        let mut problems = vec![];
+        if recovered.dropped_bytes > 0 {
+            problems.push(format!(
+                "{} bytes of corrupt records",
+                recovered.dropped_bytes
+            ));
+        }
+        for (_, key, _) in &recovered.records {
+            // records logged before sequence numbers existed have none
+            if key.sequence == 0 {
+                continue;
+            }
+            if key.sequence <= last_sequence {
+                problems.push(format!("sequence {} after {}", key.sequence, last_sequence));
+                break;
+            }
+            last_sequence = key.sequence;
+        }
+        report.files.push(FileReport {
+            path,
+            summary: format!("{} records", recovered.records.len()),
+            problems,
+        });
+    }
+    Ok(report)
+}
+
+/// Adds the MANIFEST's report and returns its column families, none if it is unreadable.
+fn verify_manifest(dir: &Path, report: &mut Report) -> BTreeMap<u32, ColumnFamilyVersion> {
+    let path = dir.join(MANIFEST_FILE);
+    let mut problems = vec![];
+    let mut families = BTreeMap::new();
+    let summary = if !path.exists() {
+        problems.push("missing".to_string());
+        "missing".to_string()
+    } else {
+        match read_column_families(dir) {
+            Ok((read, ignored)) => {
+                if ignored > 0 {
+                    problems.push(format!("{} bytes of torn or corrupt tail", ignored));
+                }
+                families = read;
+                format!(
+                    "{} column families with {} tables",
+                    families.len(),
+                    families.values().map(|f| f.tables.len()).sum::<usize>()
+                )
+            }
+            Err(e) => {
+                problems.push(format!("{:#}", e));
+                "unreadable".to_string()
+            }
+        }
+    };
+    report.files.push(FileReport {
+        path,
+        summary,
+        problems,
+    });
+    families
+}
+
+/// The number of readable records of the table and its problems.
+fn check_table(path: &Path) -> Result<(usize, Vec<String>)> {
+    let buffer = fs::read(path)?;
+    let (records, damage) = table::salvage(&buffer);
+    let mut problems: Vec<_> = damage
+        .iter()
+        .map(|c| format!("at offset {}: {}", c.offset, c.reason))
+        .collect();
+    if out_of_order(&records) {
+        problems.push("records out of internal key order".to_string());
+    }
+    Ok((records.len(), problems))
+}
+
+/// Whether the records of a table are not strictly ascending in internal key order. Versions
+/// in a legacy table all have sequence 0 and are in no particular order.
+fn out_of_order(records: &[(InternalKey, Option<Value>)]) -> bool {
+    let legacy = records.iter().all(|(key, _)| key.sequence == 0);
+    !legacy && records.windows(2).any(|w| w[0].0 >= w[1].0)
+}
+
+/// Makes the data directory `dir` openable again, returning what was done.
+///
+/// Damaged files are moved to `lost/<unix time>/`. The readable records of a damaged table
+/// replace it under the same number, so its place among the column family's tables is kept;
+/// a table with none left, or missing, is removed from the MANIFEST. A damaged WAL segment is
+/// rewritten with its intact records. An unreadable MANIFEST is rebuilt with every table in the
+/// default column family, losing the other column families, and the highest sequence found as
+/// the last one. Must not run while a server has the directory open.
+pub fn repair(dir: &Path, options: &Options) -> Result<Vec<String>> {
+    if !dir.is_dir() {
+        bail!("no data directory at {:?}", dir);
+    }
+    // a running server would keep writing the files being repaired
+    let _lock = DirLock::acquire(dir)?;
+    let sstable_dir = dir.join(SSTABLE_DIR);
+    let wal_dir = dir.join(WAL_DIR);
+    fs::create_dir_all(&sstable_dir)?;
+    fs::create_dir_all(&wal_dir)?;
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut lost = Lost {
+        dir: dir.join(LOST_DIR).join(seconds.to_string()),
+        actions: vec![],
+    };
+
+    let manifest_path = dir.join(MANIFEST_FILE);
+    if manifest_path.exists() {
+        if let Err(e) = read_column_families(dir) {
+            lost.quarantine(&manifest_path, &format!("unreadable MANIFEST: {:#}", e))?;
+        }
+    }
+    // opening rewrites the MANIFEST without a torn tail
+    let mut manifest = Manifest::open(dir)?;
+    if manifest.is_new() {
+        prepare_dir(&sstable_dir, &mut manifest)?;
+        // new writes must sort as newer than every version already in the tables or the WAL
+        let last_sequence = max_sequence(&sstable_dir, &wal_dir, &manifest.all_tables())?;
+        manifest.log_and_apply(VersionEdit {
+            last_sequence: Some(last_sequence),
+            ..VersionEdit::default()
+        })?;
+        lost.actions.push(format!(
+            "rebuilt the MANIFEST with tables {:?} in the default column family and last \
+             sequence {}",
+            manifest.all_tables(),
+            last_sequence
+        ));
+    }
+
+    let compression = options
+        .compression_per_level
+        .last()
+        .copied()
+        .unwrap_or(Compression::None);
+    let families = manifest.column_families().clone();
+    for (id, family) in families {
+        let mut edit = VersionEdit {
+            column_family: id,
+            ..VersionEdit::default()
+        };
+        for number in family.tables {
+            let path = sstable_dir.join(table_file_name(number));
+            if !path.exists() {
+                lost.actions.push(format!(
+                    "removed missing table {:?} of column family {}",
+                    path, family.name
+                ));
+                edit.deleted_tables.push(number);
+                continue;
+            }
+            let buffer = fs::read(&path)?;
+            let (records, damage) = table::salvage(&buffer);
+            if damage.is_empty() && !out_of_order(&records) {
+                continue;
+            }
+            lost.quarantine(&path, "damaged table")?;
+            // of equal keys, which only legacy tables have, the last one read wins as on load
+            let records: BTreeMap<_, _> = records.into_iter().collect();
+            if records.is_empty() {
+                lost.actions.push(format!(
+                    "removed table {:?} of column family {}, no records were readable",
+                    path, family.name
+                ));
+                edit.deleted_tables.push(number);
+                continue;
+            }
+            let records: Vec<_> = records.iter().map(|(k, v)| (k, v.as_ref())).collect();
+            write_table_file(&sstable_dir, number, &records, compression)?;
+            lost.actions.push(format!(
+                "rewrote table {:?} with its {} readable records",
+                path,
+                records.len()
+            ));
+        }
+        if !edit.deleted_tables.is_empty() {
+            manifest.log_and_apply(edit)?;
+        }
+    }
+
+    for number in list_numbers(&wal_dir, parse_log_file_name)? {
+        let path = wal_dir.join(log_file_name(number));
+        let recovered = read_log(&path, WalRecoveryMode::SkipAnyCorruptedRecords)?;
+        if recovered.dropped_bytes == 0 {
+            continue;
+        }
+        lost.quarantine(&path, "damaged wal")?;
+        write_log(&path, &recovered.records)?;
+        lost.actions.push(format!(
+            "rewrote wal {:?} with its {} intact records, dropping {} bytes",
+            path,
+            recovered.records.len(),
+            recovered.dropped_bytes
+        ));
+    }
+    Ok(lost.actions)
+}
+
+/// Highest sequence among the readable records of the tables `numbers` and the WAL segments.
+fn max_sequence(sstable_dir: &Path, wal_dir: &Path, numbers: &[u64]) -> Result<u64> {
+    let mut last_sequence = 0;
+    for number in numbers {
+        let buffer = fs::read(sstable_dir.join(table_file_name(*number)))?;
+        let (records, _) = table::salvage(&buffer);
+        for (key, _) in records {
+            last_sequence = last_sequence.max(key.sequence);
+        }
+    }
+    for number in list_numbers(wal_dir, parse_log_file_name)? {
+        let path = wal_dir.join(log_file_name(number));
+        for (_, key, _) in read_log(&path, WalRecoveryMode::SkipAnyCorruptedRecords)?.records {
+            last_sequence = last_sequence.max(key.sequence);
+        }
+    }
+    Ok(last_sequence)
+}
+
+/// Where `repair` moves damaged files, and what it did.
+struct Lost {
+    dir: PathBuf,
+    actions: Vec<String>,
+}
+
+impl Lost {
+    /// Moves the file to the lost directory under its parent directory's and its own name.
+    fn quarantine(&mut self, path: &Path, reason: &str) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let name = match (path.parent().and_then(Path::file_name), path.file_name()) {
+            (Some(parent), Some(name)) => {
+                format!("{}_{}", parent.to_string_lossy(), name.to_string_lossy())
+            }
+            _ => bail!("invalid path {:?}", path),
+        };
+        let target = self.dir.join(name);
+        fs::rename(path, &target)?;
+        self.actions
+            .push(format!("{}: moved {:?} to {:?}", reason, path, target));
+        Ok(())
+    }
+}
+
+/// Numbers of the files in `dir` that `parse` recognizes, ascending.
+fn list_numbers(dir: &Path, parse: fn(&Path) -> Option<u64>) -> Result<BTreeSet<u64>> {
+    let mut numbers = BTreeSet::new();
+    if dir.is_dir() {
+        for entry in fs::read_dir(dir)? {
+            if let Some(number) = parse(&entry?.path()) {
+                numbers.insert(number);
+            }
+        }
+    }
+    Ok(numbers)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::manifest::MANIFEST_FILE;
+    use crate::options::{Options, ReadOptions};
+    use crate::repair::{repair, verify};
+    use crate::value::Value;
+    use std::fs::{read, read_dir, remove_dir_all, remove_file, write};
+    use std::path::PathBuf;
+
+    fn files(dir: PathBuf, extension: &str) -> Vec<PathBuf> {
+        let mut files: Vec<_> = read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().is_some_and(|e| e == extension))
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn verify_and_repair() {
+        let dir = std::env::temp_dir().join("lsm_engine_repair_verify_and_repair");
+        let _ = remove_dir_all(&dir);
+        let options = Options {
+            write_buffer_size: 16 * 1024,
+            compaction_trigger: 100,
+            ..Options::default()
+        };
+        let db = Db::open(&dir, options.clone()).unwrap();
+        for i in 0..1000 {
+            db.put(format!("key{:04}", i), Value::new("v".repeat(100), 0, 0))
+                .unwrap();
+        }
+        // not while a server has the directory open
+        assert!(repair(&dir, &options).is_err());
+        drop(db);
+        let report = verify(&dir).unwrap();
+        assert!(report.is_ok(), "{}", report);
+
+        // a flipped bit in the second block of a table and a torn tail of the last segment
+        let table = files(dir.join("sstable"), "bin").remove(0);
+        let mut binary = read(&table).unwrap();
+        binary[6000] ^= 1;
+        write(&table, &binary).unwrap();
+        let wal = files(dir.join("wal"), "log").pop().unwrap();
+        let mut binary = read(&wal).unwrap();
+        binary.extend(&[1, 2, 3]);
+        write(&wal, &binary).unwrap();
+        let report = verify(&dir).unwrap();
+        let bad: Vec<_> = report
+            .files
+            .iter()
+            .filter(|f| !f.problems.is_empty())
+            .map(|f| f.path.clone())
+            .collect();
+        assert_eq!(bad, vec![table.clone(), wal.clone()]);
+
+        let actions = repair(&dir, &options).unwrap();
+        assert_eq!(actions.len(), 4, "{:?}", actions);
+        let report = verify(&dir).unwrap();
+        assert!(report.is_ok(), "{}", report);
+        let lost = read_dir(dir.join("lost")).unwrap().next().unwrap().unwrap();
+        assert_eq!(read_dir(lost.path()).unwrap().count(), 2);
+
+        // only the damaged block's keys are lost
+        let db = Db::open(&dir, options).unwrap();
+        let found = (0..1000)
+            .filter(|i| db.get(&format!("key{:04}", i)).unwrap().is_some())
+            .count();
+        assert!(found < 1000 && found > 900, "{}", found);
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn repair_missing_manifest() {
+        let dir = std::env::temp_dir().join("lsm_engine_repair_missing_manifest");
+        let _ = remove_dir_all(&dir);
+        let options = Options {
+            compaction_trigger: 2,
+            ..Options::default()
+        };
+        let value = |data: &str| Some(Value::new(data.to_string(), 0, 0));
+
+        let db = Db::open(&dir, options.clone()).unwrap();
+        for round in 0..10 {
+            db.put("table".to_string(), value(&round.to_string()).unwrap())
+                .unwrap();
+        }
+        db.put("deleted".to_string(), value("old").unwrap())
+            .unwrap();
+        // nothing left in the WAL, so the last sequence can only come from the tables
+        db.flush().unwrap();
+        drop(db);
+        remove_file(dir.join(MANIFEST_FILE)).unwrap();
+
+        let actions = repair(&dir, &options).unwrap();
+        assert!(actions[0].ends_with("last sequence 11"), "{:?}", actions);
+        // new versions replace the adopted ones, also once flushed and compacted
+        let db = Db::open(&dir, options.clone()).unwrap();
+        assert_eq!(db.get("table").unwrap(), value("9"));
+        db.put("table".to_string(), value("new").unwrap()).unwrap();
+        db.delete("deleted").unwrap();
+        assert_eq!(db.get("table").unwrap(), value("new"));
+        assert_eq!(db.get("deleted").unwrap(), None);
+        assert_eq!(db.iter(&ReadOptions::default()).count(), 1);
+        db.flush().unwrap();
+        drop(db);
+
+        let db = Db::open(&dir, options).unwrap();
+        assert_eq!(db.get("table").unwrap(), value("new"));
+        assert_eq!(db.get("deleted").unwrap(), None);
+        assert_eq!(db.iter(&ReadOptions::default()).count(), 1);
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,704 @@
+use crate::command::Command;
+use crate::db::Db;
+use crate::decoder::{self, Decoder};
+use crate::executor::{Executor, ServerStats};
+use anyhow::Result;
+use log::{debug, error, info, warn};
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Read, Write};
+use std::mem;
+use std::net;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+/// Token of the first connection; tokens are not reused, so a late response of a closed
+/// connection cannot reach another one.
+const FIRST_CONNECTION: usize = 2;
+/// Longest wait for events, so that a shutdown or idle connections are noticed.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How often connections are checked for the idle timeout.
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a shutdown waits for responses to be written.
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const READ_BUFFER_SIZE: usize = 16 * 1024;
+/// Output beyond which a connection is not read until its client takes its responses.
+const MAX_PENDING_OUTPUT: usize = 1024 * 1024;
+/// Requests queued behind a running job beyond which a connection is not read until the job
+/// is done. Undecoded input needs no limit, the decoder keeping at most one line of
+/// `decoder::MAX_LINE_LENGTH`.
+const MAX_PENDING_REQUESTS: usize = 1024;
+/// Sent to a connection beyond `max_connections` before it is closed.
+const TOO_MANY_CONNECTIONS: &[u8] = b"SERVER_ERROR too many connections\n";
+
+/// A decoded command, or why a line is not one; both are answered in order.
+type Request = Result<Command, String>;
+
+/// Runs a command with a connection's executor and returns the response.
+type Handler = fn(&mut Executor, Command) -> String;
+
+/// Requests of a connection run on a worker with its executor.
+struct Job {
+    token: Token,
+    executor: Executor,
+    requests: Vec<Request>,
+}
+
+/// The executor of a `Job` handed back with the responses.
+struct Done {
+    token: Token,
+    executor: Executor,
+    output: Vec<u8>,
+    executed: u64,
+}
+
+struct Connection {
+    stream: TcpStream,
+    decoder: Decoder,
+    /// `None` while a worker runs the connection's requests.
+    executor: Option<Executor>,
+    /// Requests decoded while a worker is busy with earlier ones.
+    requests: Vec<Request>,
+    output: Vec<u8>,
+    /// Whether writable events are registered, as output is waiting.
+    writable: bool,
+    /// Whether reading stopped at `MAX_PENDING_OUTPUT` or `MAX_PENDING_REQUESTS` and has to
+    /// be resumed.
+    paused: bool,
+    /// Whether no more input is read, the client having closed its side or the server
+    /// shutting down; the connection is closed once its responses are written.
+    read_closed: bool,
+    last_active: Instant,
+}
+
+impl Connection {
+    /// Whether nothing is running, waiting to run or waiting to be written.
+    fn is_idle(&self) -> bool {
+        self.executor.is_some() && self.requests.is_empty() && self.output.is_empty()
+    }
+
+    /// Whether more input is not read until requests or output are taken.
+    fn is_backlogged(&self) -> bool {
+        self.output.len() >= MAX_PENDING_OUTPUT || self.requests.len() >= MAX_PENDING_REQUESTS
+    }
+}
+
+/// Event loop over non-blocking connections; commands run on a pool of workers.
+struct Server {
+    poll: Poll,
+    db: Arc<Db>,
+    stats: Arc<ServerStats>,
+    connections: HashMap<Token, Connection>,
+    next_token: usize,
+    jobs: Sender<Job>,
+    commands: u64,
+}
+
+/// Serves connections on `listener` until `shutdown` is set, then answers the commands
+/// already received and returns the number of commands executed.
+pub fn serve(
+    listener: net::TcpListener,
+    db: Arc<Db>,
+    stats: Arc<ServerStats>,
+    shutdown: &AtomicBool,
+) -> Result<u64> {
+    serve_with(listener, db, stats, shutdown, execute)
+}
+
+/// `serve` with the commands run by `handler`.
+fn serve_with(
+    listener: net::TcpListener,
+    db: Arc<Db>,
+    stats: Arc<ServerStats>,
+    shutdown: &AtomicBool,
+    handler: Handler,
+) -> Result<u64> {
+    listener.set_nonblocking(true)?;
+    let mut listener = TcpListener::from_std(listener);
+    let poll = Poll::new()?;
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)?;
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+    let (done_sender, done_receiver) = mpsc::channel();
+    let (jobs, workers) = start_workers(
+        stats.options.worker_threads.max(1),
+        handler,
+        done_sender,
+        waker,
+    )?;
+    let mut server = Server {
+        poll,
+        db,
+        stats,
+        connections: HashMap::new(),
+        next_token: FIRST_CONNECTION,
+        jobs,
+        commands: 0,
+    };
+
+    let mut events = Events::with_capacity(1024);
+    let mut last_idle_check = Instant::now();
+    let mut shutdown_started: Option<Instant> = None;
+    loop {
+        if let Err(e) = server.poll.poll(&mut events, Some(POLL_INTERVAL)) {
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e.into());
+            }
+        }
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => server.accept(&listener),
+                WAKER => {}
+                token => {
+                    if event.is_readable() {
+                        server.read(token);
+                    }
+                    server.process(token);
+                }
+            }
+        }
+        while let Ok(done) = done_receiver.try_recv() {
+            server.complete(done);
+        }
+        if shutdown_started.is_none() && shutdown.load(Ordering::Relaxed) {
+            info!(
+                "shutting down, waiting for {} connections",
+                server.connections.len()
+            );
+            server.poll.registry().deregister(&mut listener)?;
+            // commands that already arrived are still answered, later input is not read
+            let tokens: Vec<_> = server.connections.keys().copied().collect();
+            for token in tokens {
+                server.read(token);
+                if let Some(connection) = server.connections.get_mut(&token) {
+                    connection.read_closed = true;
+                }
+                server.process(token);
+            }
+            shutdown_started = Some(Instant::now());
+        }
+        if let Some(started) = shutdown_started {
+            if server.connections.is_empty() || started.elapsed() >= SHUTDOWN_TIMEOUT {
+                break;
+            }
+        }
+        if last_idle_check.elapsed() >= IDLE_CHECK_INTERVAL {
+            server.close_idle();
+            last_idle_check = Instant::now();
+        }
+    }
+
+    let commands = server.commands;
+    drop(server);
+    for worker in workers {
+        let _ = worker.join();
+    }
+    Ok(commands)
+}
+
+/// Starts the workers, which stop once the returned sender is dropped.
+fn start_workers(
+    count: usize,
+    handler: Handler,
+    done: Sender<Done>,
+    waker: Arc<Waker>,
+) -> Result<(Sender<Job>, Vec<JoinHandle<()>>)> {
+    let (jobs, receiver) = mpsc::channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let workers = (0..count)
+        .map(|i| {
+            let receiver: Arc<Mutex<Receiver<Job>>> = receiver.clone();
+            let done = done.clone();
+            let waker = waker.clone();
+            thread::Builder::new()
+                .name(format!("worker-{}", i))
+                .spawn(move || loop {
+                    let next = receiver.lock().unwrap().recv();
+                    let job = match next {
+                        Ok(job) => job,
+                        Err(_) => return,
+                    };
+                    if done.send(run(job, handler)).is_err() {
+                        return;
+                    }
+                    if let Err(e) = waker.wake() {
+                        error!("failed to wake the event loop: {}", e);
+                    }
+                })
+        })
+        .collect::<Result<_, _>>()?;
+    Ok((jobs, workers))
+}
+
+/// Executes the requests of a job in order. A command that panics is answered with an error,
+/// so that neither the worker nor the connection's executor is lost.
+fn run(job: Job, handler: Handler) -> Done {
+    let Job {
+        token,
+        mut executor,
+        requests,
+    } = job;
+    let mut output = vec![];
+    let mut executed = 0;
+    for request in requests {
+        let response = match request {
+            Ok(command) => {
+                executed += 1;
+                match panic::catch_unwind(AssertUnwindSafe(|| handler(&mut executor, command))) {
+                    Ok(response) => response,
+                    Err(_) => {
+                        error!("command panicked on connection {:?}", token);
+                        "[error] internal error".to_string()
+                    }
+                }
+            }
+            Err(e) => format!("[error] {}", e),
+        };
+        debug!("write response: {}", response);
+        output.extend(response.as_bytes());
+        output.push(b'\n');
+    }
+    Done {
+        token,
+        executor,
+        output,
+        executed,
+    }
+}
+
+/// The `Handler` of `serve`, answering a failed command with its error.
+fn execute(executor: &mut Executor, command: Command) -> String {
+    match executor.execute(command) {
+        Ok(result) => result,
+        Err(e) => format!("[error] {}", e),
+    }
+}
+
+impl Server {
+    fn accept(&mut self, listener: &TcpListener) {
+        loop {
+            let (mut stream, address) = match listener.accept() {
+                Ok(accepted) => accepted,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("listener incoming error: {}", e);
+                    return;
+                }
+            };
+            if self.connections.len() >= self.stats.options.max_connections {
+                self.stats
+                    .rejected_connections
+                    .fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "reject connection from {}: {} connections open",
+                    address,
+                    self.connections.len()
+                );
+                let _ = stream.write(TOO_MANY_CONNECTIONS);
+                continue;
+            }
+            let token = Token(self.next_token);
+            self.next_token += 1;
+            if let Err(e) = self
+                .poll
+                .registry()
+                .register(&mut stream, token, Interest::READABLE)
+            {
+                error!("failed to register connection from {}: {}", address, e);
+                continue;
+            }
+            debug!("Connection from {}", address);
+            self.connections.insert(
+                token,
+                Connection {
+                    stream,
+                    decoder: decoder::new(),
+                    executor: Some(Executor::new(self.db.clone(), self.stats.clone())),
+                    requests: vec![],
+                    output: vec![],
+                    writable: false,
+                    paused: false,
+                    read_closed: false,
+                    last_active: Instant::now(),
+                },
+            );
+            self.stats.curr_connections.fetch_add(1, Ordering::Relaxed);
+            self.stats.total_connections.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Reads and decodes everything available, as readiness is only reported on changes.
+    fn read(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        let mut buffer = [0; READ_BUFFER_SIZE];
+        while !connection.read_closed {
+            if connection.is_backlogged() {
+                connection.paused = true;
+                return;
+            }
+            match connection.stream.read(&mut buffer) {
+                Ok(0) => connection.read_closed = true,
+                Ok(n) => {
+                    connection.last_active = Instant::now();
+                    connection.decoder.feed(&buffer[..n]);
+                    loop {
+                        match connection.decoder.decode() {
+                            Ok(Some(command)) => connection.requests.push(Ok(command)),
+                            Ok(None) => break,
+                            Err(e) => connection.requests.push(Err(e.to_string())),
+                        }
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    debug!("read failed: {:?}", e);
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Hands waiting requests to a worker, writes waiting output and closes the connection
+    /// once it is done.
+    fn process(&mut self, token: Token) {
+        let connection = match self.connections.get_mut(&token) {
+            Some(connection) => connection,
+            None => return,
+        };
+        if !connection.requests.is_empty() {
+            if let Some(executor) = connection.executor.take() {
+                let job = Job {
+                    token,
+                    executor,
+                    requests: mem::take(&mut connection.requests),
+                };
+                if self.jobs.send(job).is_err() {
+                    error!("no worker left to run commands");
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+        if let Err(e) = write_output(connection, self.poll.registry(), token) {
+            debug!("write failed: {:?}", e);
+            self.close(token);
+            return;
+        }
+        if connection.paused && !connection.is_backlogged() {
+            connection.paused = false;
+            self.read(token);
+            return self.process(token);
+        }
+        if connection.read_closed && connection.is_idle() {
+            self.close(token);
+        }
+    }
+
+    fn complete(&mut self, done: Done) {
+        self.commands += done.executed;
+        // the connection may have failed while its commands ran
+        if let Some(connection) = self.connections.get_mut(&done.token) {
+            connection.executor = Some(done.executor);
+            connection.output.extend(done.output);
+            connection.last_active = Instant::now();
+            self.process(done.token);
+        }
+    }
+
+    fn close_idle(&mut self) {
+        let timeout = match self.stats.options.idle_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let idle: Vec<_> = self
+            .connections
+            .iter()
+            .filter(|(_, c)| c.is_idle() && c.last_active.elapsed() >= timeout)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in idle {
+            debug!("close idle connection {:?}", token);
+            self.close(token);
+        }
+    }
+
+    fn close(&mut self, token: Token) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+            self.stats.curr_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Writes as much output as the socket takes, registering for writable events while some is
+/// left.
+fn write_output(
+    connection: &mut Connection,
+    registry: &Registry,
+    token: Token,
+) -> std::io::Result<()> {
+    while !connection.output.is_empty() {
+        match connection.stream.write(&connection.output) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => {
+                connection.output.drain(..n);
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let writable = !connection.output.is_empty();
+    if writable != connection.writable {
+        let interest = if writable {
+            Interest::READABLE | Interest::WRITABLE
+        } else {
+            Interest::READABLE
+        };
+        registry.reregister(&mut connection.stream, token, interest)?;
+        connection.writable = writable;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::command::Command;
+    use crate::db::Db;
+    use crate::executor::{Executor, ServerStats};
+    use crate::options::{Options, ServerOptions};
+    use crate::server::{execute, serve_with, Handler, MAX_PENDING_REQUESTS};
+    use std::fs::remove_dir_all;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::path::PathBuf;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread::{self, JoinHandle};
+    use std::time::Duration;
+
+    fn read_until(stream: &mut TcpStream, end: &str) -> String {
+        let mut response = vec![];
+        let mut buffer = [0; 1024];
+        while !String::from_utf8_lossy(&response).ends_with(end) {
+            let n = stream.read(&mut buffer).unwrap();
+            assert!(
+                n > 0,
+                "closed after {:?}",
+                String::from_utf8_lossy(&response)
+            );
+            response.extend(&buffer[..n]);
+        }
+        String::from_utf8(response).unwrap()
+    }
+
+    /// A server on a free port over a new database in the test's directory, which is
+    /// removed once `server` has been joined.
+    struct TestServer {
+        dir: PathBuf,
+        address: SocketAddr,
+        stats: Arc<ServerStats>,
+        shutdown: Arc<AtomicBool>,
+        server: JoinHandle<u64>,
+    }
+
+    fn start(name: &str, options: ServerOptions, handler: Handler) -> TestServer {
+        let dir = std::env::temp_dir().join(format!("lsm_engine_server_{}", name));
+        let _ = remove_dir_all(&dir);
+        let db = Arc::new(Db::open(&dir, Options::default()).unwrap());
+        let stats = Arc::new(ServerStats::new(options));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = {
+            let (stats, shutdown) = (stats.clone(), shutdown.clone());
+            thread::spawn(move || serve_with(listener, db, stats, &shutdown, handler).unwrap())
+        };
+        TestServer {
+            dir,
+            address,
+            stats,
+            shutdown,
+            server,
+        }
+    }
+
+    #[test]
+    fn serve_and_shut_down() {
+        let TestServer {
+            dir,
+            address,
+            stats,
+            shutdown,
+            server,
+        } = start(
+            "serve_and_shut_down",
+            ServerOptions {
+                worker_threads: 2,
+                max_connections: 2,
+                idle_timeout: None,
+            },
+            execute,
+        );
+
+        // commands split across writes and pipelined are answered in order
+        let mut client = TcpStream::connect(address).unwrap();
+        for piece in [
+            "set key 0 0 5\r",
+            "\nva",
+            "lue\r\nget key\r\nbogus\r\nget ",
+            "key\r\n",
+        ] {
+            client.write_all(piece.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        let response = read_until(
+            &mut client,
+            "END\n[error] unknown command: bogus\nVALUE key 0 0 5\nvalue\nEND\n",
+        );
+        assert!(response.starts_with("STORED\n"), "{}", response);
+
+        let mut idle = TcpStream::connect(address).unwrap();
+        let mut rejected = TcpStream::connect(address).unwrap();
+        assert_eq!(
+            read_until(&mut rejected, "\n"),
+            "SERVER_ERROR too many connections\n"
+        );
+        client.write_all(b"stats\r\n").unwrap();
+        let response = read_until(&mut client, "END\n");
+        assert!(
+            response.contains("STAT curr_connections 2\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.contains("STAT rejected_connections 1\n"),
+            "{}",
+            response
+        );
+
+        // a command received before shutdown is still answered, then connections are closed
+        client.write_all(b"get key\r\n").unwrap();
+        shutdown.store(true, Ordering::Relaxed);
+        assert_eq!(server.join().unwrap(), 5);
+        let mut rest = String::new();
+        client.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "VALUE key 0 0 5\nvalue\nEND\n");
+        assert_eq!(idle.read(&mut [0; 16]).unwrap(), 0);
+        assert_eq!(stats.curr_connections.load(Ordering::Relaxed), 0);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    /// `execute`, except that `get panic` panics.
+    fn panic_on_get_panic(executor: &mut Executor, command: Command) -> String {
+        if matches!(&command, Command::Get { key } if key == "panic") {
+            panic!("get panic");
+        }
+        execute(executor, command)
+    }
+
+    #[test]
+    fn connection_limit_and_panics() {
+        let TestServer {
+            dir,
+            address,
+            stats,
+            shutdown,
+            server,
+        } = start(
+            "connection_limit_and_panics",
+            ServerOptions {
+                worker_threads: 1,
+                max_connections: 1,
+                idle_timeout: None,
+            },
+            panic_on_get_panic,
+        );
+
+        // a panicking command is answered with an error and the only worker keeps running
+        let mut client = TcpStream::connect(address).unwrap();
+        client
+            .write_all(b"get panic\r\nset key 0 0 1\r\nv\r\n")
+            .unwrap();
+        assert_eq!(
+            read_until(&mut client, "STORED\n"),
+            "[error] internal error\nSTORED\n"
+        );
+
+        let mut rejected = TcpStream::connect(address).unwrap();
+        assert_eq!(
+            read_until(&mut rejected, "\n"),
+            "SERVER_ERROR too many connections\n"
+        );
+        assert_eq!(rejected.read(&mut [0; 16]).unwrap(), 0);
+
+        // a closed connection frees its slot
+        drop(client);
+        while stats.curr_connections.load(Ordering::Relaxed) > 0 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let mut client = TcpStream::connect(address).unwrap();
+        client.write_all(b"get key\r\n").unwrap();
+        assert_eq!(
+            read_until(&mut client, "END\n"),
+            "VALUE key 0 0 1\nv\nEND\n"
+        );
+        assert_eq!(stats.rejected_connections.load(Ordering::Relaxed), 1);
+
+        shutdown.store(true, Ordering::Relaxed);
+        assert_eq!(server.join().unwrap(), 3);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let TestServer {
+            dir,
+            address,
+            shutdown,
+            server,
+            ..
+        } = start(
+            "pipelined_requests",
+            ServerOptions {
+                worker_threads: 1,
+                max_connections: 1,
+                idle_timeout: None,
+            },
+            execute,
+        );
+
+        // reading pauses while requests are queued and resumes as the worker takes them
+        let count = 5 * MAX_PENDING_REQUESTS;
+        let mut client = TcpStream::connect(address).unwrap();
+        let mut input = String::new();
+        for i in 0..count {
+            input.push_str(&format!("set key{} 0 0 1\r\n{}\r\n", i, i % 10));
+        }
+        input.push_str("get key0\r\n");
+        let writer = {
+            let mut client = client.try_clone().unwrap();
+            thread::spawn(move || client.write_all(input.as_bytes()).unwrap())
+        };
+        let response = read_until(&mut client, "END\n");
+        writer.join().unwrap();
+        assert_eq!(response.matches("STORED\n").count(), count);
+        assert!(response.ends_with("STORED\nVALUE key0 0 0 1\n0\nEND\n"));
+
+        shutdown.store(true, Ordering::Relaxed);
+        assert_eq!(server.join().unwrap(), count as u64 + 1);
+        remove_dir_all(&dir).unwrap();
+    }
+}
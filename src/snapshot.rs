@@ -0,0 +1,114 @@
+use crate::key::{InternalKey, ValueType};
+use crate::value::Value;
+use std::collections::BTreeMap;
+
+/// A consistent read view: reads with it ignore versions written after it was taken.
+///
+/// Taken with `Db::snapshot` and given back with `Db::release_snapshot`; until then compaction
+/// keeps the versions it sees. Not `Clone`, so that each snapshot is released exactly once.
+#[derive(Debug, PartialEq)]
+pub struct Snapshot {
+    sequence: u64,
+}
+
+impl Snapshot {
+    /// Sequence number of the last write visible in the snapshot.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+/// Sequence numbers pinned by live snapshots, with how many snapshots pin each.
+#[derive(Default)]
+pub(crate) struct SnapshotList {
+    counts: BTreeMap<u64, usize>,
+}
+
+impl SnapshotList {
+    pub fn acquire(&mut self, sequence: u64) -> Snapshot {
+        *self.counts.entry(sequence).or_insert(0) += 1;
+        Snapshot { sequence }
+    }
+
+    pub fn release(&mut self, snapshot: Snapshot) {
+        if let Some(count) = self.counts.get_mut(&snapshot.sequence) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(&snapshot.sequence);
+            }
+        }
+    }
+
+    /// Pinned sequence numbers, ascending.
+    pub fn sequences(&self) -> Vec<u64> {
+        self.counts.keys().copied().collect()
+    }
+}
+
+/// Drops the versions no read can see from `records`, sorted in internal key order.
+///
+/// A version is kept if it is the newest of its key, or if some snapshot sees it, i.e. one in
+/// `snapshots` (ascending) falls between its sequence and that of the next newer version. When
+/// `bottommost`, no older version of any key exists elsewhere, so a deletion seen by every read
+/// is dropped as well.
+pub(crate) fn drop_hidden_versions<'a>(
+    records: Vec<(&'a InternalKey, Option<&'a Value>)>,
+    snapshots: &[u64],
+    bottommost: bool,
+) -> Vec<(&'a InternalKey, Option<&'a Value>)> {
+    let oldest_snapshot = snapshots.first().copied().unwrap_or(u64::MAX);
+    let mut newer: Option<(&str, u64)> = None;
+    let mut kept = Vec::with_capacity(records.len());
+    for (key, value) in records {
+        let visible = match newer {
+            Some((user_key, sequence)) if user_key == key.user_key => snapshots
+                .iter()
+                .any(|s| key.sequence <= *s && *s < sequence),
+            _ => true,
+        };
+        newer = Some((&key.user_key, key.sequence));
+        let obsolete_deletion =
+            bottommost && key.value_type == ValueType::Deletion && key.sequence <= oldest_snapshot;
+        if visible && !obsolete_deletion {
+            kept.push((key, value));
+        }
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::{InternalKey, ValueType};
+    use crate::snapshot::drop_hidden_versions;
+    use crate::value::Value;
+
+    #[test]
+    fn hidden_versions() {
+        let value = Value::new("v".to_string(), 0, 0);
+        let put = |s| InternalKey::new("a".to_string(), s, ValueType::Value);
+        let mut keys = [
+            put(9),
+            InternalKey::new("a".to_string(), 7, ValueType::Deletion),
+            put(5),
+            put(3),
+            put(1),
+            put(2),
+        ];
+        keys.sort();
+        let records: Vec<_> = keys
+            .iter()
+            .map(|k| (k, (k.value_type == ValueType::Value).then_some(&value)))
+            .collect();
+        let sequences = |snapshots: &[u64], bottommost| {
+            drop_hidden_versions(records.clone(), snapshots, bottommost)
+                .into_iter()
+                .map(|(k, _)| k.sequence)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sequences(&[], false), vec![9]);
+        // snapshot 6 sees 5, snapshot 8 sees the deletion at 7, snapshot 3 sees 3
+        assert_eq!(sequences(&[3, 6, 8], false), vec![9, 7, 5, 3]);
+        assert_eq!(sequences(&[8], true), vec![9]);
+        assert_eq!(sequences(&[6], true), vec![9, 5]);
+    }
+}
@@ -1,63 +1,334 @@
-use crate::record::{decode_file, encode};
+use crate::compression::Compression;
+use crate::iterator::SortedRun;
+use crate::key::InternalKey;
+use crate::manifest::{sync_dir, Manifest, VersionEdit};
+use crate::options::Options;
+use crate::snapshot::drop_hidden_versions;
+use crate::table::{self, Table};
 use crate::value::Value;
 use anyhow::Result;
 use log::info;
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, VecDeque};
 use std::fs;
 use std::fs::OpenOptions;
 use std::io::Write;
-use std::iter::FromIterator;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+const TMP_EXTENSION: &str = "tmp";
+/// Level of the tables written by flushes.
+const FLUSH_LEVEL: usize = 0;
+/// Level of the table written by compaction.
+const COMPACTION_LEVEL: usize = 1;
 
 pub trait SSTable: Sync + Send {
-    fn search(&self, key: &String) -> Option<Option<&Value>>;
-    fn create(&mut self, records: Vec<(&String, Option<&Value>)>) -> Result<()>;
+    /// Newest version of the key with a sequence up to `sequence`, as its sequence and value,
+    /// `None` for a deletion.
+    fn search(&self, key: &str, sequence: u64) -> Result<Option<(u64, Option<Value>)>>;
+    /// Durably writes `records`, sorted in internal key order, as a new table and logs it to the
+    /// MANIFEST together with `edit`.
+    ///
+    /// Readers are only blocked while the finished table is installed, not during the write.
+    fn create(&self, records: Vec<(&InternalKey, Option<&Value>)>, edit: VersionEdit)
+        -> Result<()>;
+    /// The tables, newest first.
+    fn runs(&self) -> Vec<Arc<dyn SortedRun>>;
+    fn table_count(&self) -> usize;
+    /// Whether a table is in an older on-disk format, which compaction rewrites in the current
+    /// one.
+    fn needs_upgrade(&self) -> bool;
+    /// Merges all tables into one, keeping only the versions visible to the latest state or
+    /// to a snapshot in `snapshots` (ascending).
+    fn compact(&self, snapshots: &[u64]) -> Result<()>;
+    /// Number of the oldest tables written longer ago than `ttl`.
+    fn expired_tables(&self, ttl: Duration) -> Result<usize>;
+    /// Deletes the `count` oldest tables with all their versions, whatever snapshots see.
+    fn drop_oldest(&self, count: usize) -> Result<()>;
 }
 
+/// The tables of one column family.
 pub struct HashMapSSTable {
-    dir: &'static Path,
-    maps: VecDeque<HashMap<String, Option<Value>>>,
+    dir: PathBuf,
+    manifest: Arc<Mutex<Manifest>>,
+    column_family: u32,
+    compression_per_level: Vec<Compression>,
+    tables: RwLock<VecDeque<Arc<Table>>>,
+    /// Whether a table loaded at open is of an older format version.
+    outdated: AtomicBool,
 }
 
 impl HashMapSSTable {
-    pub fn new(dir: &'static Path) -> Result<Self> {
-        let mut maps = VecDeque::new();
-        let mut paths: Vec<_> = fs::read_dir(dir)?.map(|r| r.unwrap().path()).collect();
-        paths.sort();
-        for path in paths {
-            info!("load and push_front sstable {:?}", path);
-            maps.push_front(HashMap::from_iter(decode_file(path.as_ref())?))
+    /// Loads the column family's tables, see `prepare_dir` for the directory itself.
+    pub fn new(
+        dir: &Path,
+        manifest: Arc<Mutex<Manifest>>,
+        column_family: u32,
+        options: &Options,
+    ) -> Result<Self> {
+        let mut tables = VecDeque::new();
+        let mut outdated = false;
+        {
+            let manifest = manifest.lock().unwrap();
+            for number in manifest.tables(column_family) {
+                let path = dir.join(table_file_name(*number));
+                info!("load and push_front sstable {:?}", path);
+                let table = Table::open(&path, options.verify_checksums)?;
+                outdated |= table.version() < table::FORMAT_VERSION;
+                tables.push_front(Arc::new(table))
+            }
         }
-        Ok(Self { dir, maps })
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            manifest,
+            column_family,
+            compression_per_level: options.compression_per_level.clone(),
+            tables: RwLock::new(tables),
+            outdated: AtomicBool::new(outdated),
+        })
+    }
+
+    /// Durably writes the records as a new table file of `level` and returns its number and
+    /// the table.
+    fn write_table(
+        &self,
+        records: &[(&InternalKey, Option<&Value>)],
+        level: usize,
+    ) -> Result<(u64, Table)> {
+        let number = self.manifest.lock().unwrap().new_file_number();
+        let compression = self
+            .compression_per_level
+            .get(level)
+            .or_else(|| self.compression_per_level.last())
+            .copied()
+            .unwrap_or(Compression::None);
+        info!("write sstable {} with {} compression", number, compression);
+        let buffer = write_table_file(&self.dir, number, records, compression)?;
+        let table = Table::new(self.dir.join(table_file_name(number)), buffer, false)?;
+        Ok((number, table))
     }
 }
 
 impl SSTable for HashMapSSTable {
-    fn search(&self, key: &String) -> Option<Option<&Value>> {
-        for map in &self.maps {
-            if let Some(v) = map.get(key) {
-                return Option::from(v.as_ref());
+    fn search(&self, key: &str, sequence: u64) -> Result<Option<(u64, Option<Value>)>> {
+        for table in self.tables.read().unwrap().iter() {
+            if let Some(found) = table.get(key, sequence)? {
+                return Ok(Some(found));
+            }
+        }
+        Ok(None)
+    }
+
+    fn create(
+        &self,
+        records: Vec<(&InternalKey, Option<&Value>)>,
+        mut edit: VersionEdit,
+    ) -> Result<()> {
+        let (number, table) = self.write_table(&records, FLUSH_LEVEL)?;
+        edit.column_family = self.column_family;
+        edit.new_tables.push(number);
+        self.manifest.lock().unwrap().log_and_apply(edit)?;
+        self.tables.write().unwrap().push_front(Arc::new(table));
+        Ok(())
+    }
+
+    fn runs(&self) -> Vec<Arc<dyn SortedRun>> {
+        self.tables
+            .read()
+            .unwrap()
+            .iter()
+            .map(|table| table.clone() as Arc<dyn SortedRun>)
+            .collect()
+    }
+
+    fn table_count(&self) -> usize {
+        self.tables.read().unwrap().len()
+    }
+
+    fn needs_upgrade(&self) -> bool {
+        self.outdated.load(Ordering::Acquire)
+    }
+
+    fn compact(&self, snapshots: &[u64]) -> Result<()> {
+        // tables only change on the flush thread, which is the caller
+        let (merged, inputs) = {
+            let tables = self.tables.read().unwrap();
+            (
+                merge(&tables)?,
+                self.manifest
+                    .lock()
+                    .unwrap()
+                    .tables(self.column_family)
+                    .to_vec(),
+            )
+        };
+        let records = drop_hidden_versions(
+            merged
+                .iter()
+                .map(|(key, value)| (key, value.as_ref()))
+                .collect(),
+            snapshots,
+            true,
+        );
+        info!(
+            "compact sstables {:?}: {} of {} versions kept",
+            inputs,
+            records.len(),
+            merged.len()
+        );
+        let mut edit = VersionEdit {
+            column_family: self.column_family,
+            deleted_tables: inputs.clone(),
+            ..VersionEdit::default()
+        };
+        let output = if records.is_empty() {
+            None
+        } else {
+            let (number, table) = self.write_table(&records, COMPACTION_LEVEL)?;
+            edit.new_tables.push(number);
+            Some(table)
+        };
+        self.manifest.lock().unwrap().log_and_apply(edit)?;
+        {
+            let mut tables = self.tables.write().unwrap();
+            tables.clear();
+            if let Some(table) = output {
+                tables.push_front(Arc::new(table));
             }
         }
+        self.outdated.store(false, Ordering::Release);
+        remove_tables(&self.dir, &inputs)
+    }
+
+    fn expired_tables(&self, ttl: Duration) -> Result<usize> {
+        let numbers = self
+            .manifest
+            .lock()
+            .unwrap()
+            .tables(self.column_family)
+            .to_vec();
+        let now = SystemTime::now();
+        let mut count = 0;
+        // oldest first, so the expired tables are a prefix
+        for number in numbers {
+            let written = fs::metadata(self.dir.join(table_file_name(number)))?.modified()?;
+            if now.duration_since(written).unwrap_or_default() <= ttl {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn drop_oldest(&self, count: usize) -> Result<()> {
+        // tables only change on the flush thread, which is the caller
+        let inputs = self.manifest.lock().unwrap().tables(self.column_family)[..count].to_vec();
+        info!("drop oldest sstables {:?}", inputs);
+        self.manifest.lock().unwrap().log_and_apply(VersionEdit {
+            column_family: self.column_family,
+            deleted_tables: inputs.clone(),
+            ..VersionEdit::default()
+        })?;
+        {
+            let mut tables = self.tables.write().unwrap();
+            let kept = tables.len() - count;
+            tables.truncate(kept);
+        }
+        remove_tables(&self.dir, &inputs)
+    }
+}
+
+/// Merges tables given newest first; of equal keys, which only legacy tables have, the newer
+/// table's version wins.
+fn merge(tables: &VecDeque<Arc<Table>>) -> Result<BTreeMap<InternalKey, Option<Value>>> {
+    let mut merged = BTreeMap::new();
+    for table in tables.iter().rev() {
+        merged.extend(table.records()?);
+    }
+    Ok(merged)
+}
+
+/// Durably writes the records as the table file numbered `number`, replacing any file there,
+/// and returns its contents.
+pub(crate) fn write_table_file(
+    dir: &Path,
+    number: u64,
+    records: &[(&InternalKey, Option<&Value>)],
+    compression: Compression,
+) -> Result<Vec<u8>> {
+    let path = dir.join(table_file_name(number));
+    let tmp_path = dir.join(format!("{}.{}", table_file_name(number), TMP_EXTENSION));
+    let binary = table::encode(records, compression.codec())?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&tmp_path)?;
+    file.write_all(&binary)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, &path)?;
+    sync_dir(dir)?;
+    Ok(binary)
+}
+
+pub(crate) fn table_file_name(number: u64) -> String {
+    format!("{:>05}.bin", number)
+}
+
+pub(crate) fn parse_table_file_name(path: &Path) -> Option<u64> {
+    if path.extension()? != "bin" {
         return None;
     }
+    path.file_stem()?.to_str()?.parse().ok()
+}
 
-    fn create(&mut self, records: Vec<(&String, Option<&Value>)>) -> Result<()> {
-        let file_name = format!("{:>05}.bin", self.maps.len());
-        let path = self.dir.join(file_name);
-        info!("create and push_front sstable {:?}", path);
-        let mut file = OpenOptions::new().create(true).append(true).open(path)?;
-        for (key, value) in records.clone() {
-            let binary = encode(key, value);
-            file.write(&binary)?;
-            let binary_len = binary.len() as i32;
-            file.write(&binary_len.to_le_bytes())?;
+/// Sets up the table directory at open, before the tables of any column family are loaded.
+pub fn prepare_dir(dir: &Path, manifest: &mut Manifest) -> Result<()> {
+    if manifest.is_new() {
+        adopt_legacy_tables(dir, manifest)?;
+    }
+    remove_obsolete_tables(dir, &manifest.all_tables())
+}
+
+/// Deletes the files of tables no longer in the MANIFEST, e.g. those of a dropped column family.
+pub fn remove_tables(dir: &Path, numbers: &[u64]) -> Result<()> {
+    for number in numbers {
+        let path = dir.join(table_file_name(*number));
+        info!("remove sstable {:?}", path);
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Registers tables written before the MANIFEST existed, in file name order.
+fn adopt_legacy_tables(dir: &Path, manifest: &mut Manifest) -> Result<()> {
+    let mut numbers: Vec<_> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().and_then(|e| parse_table_file_name(&e.path())))
+        .collect();
+    if numbers.is_empty() {
+        return Ok(());
+    }
+    numbers.sort_unstable();
+    info!("adopt legacy sstables {:?}", numbers);
+    manifest.log_and_apply(VersionEdit {
+        new_tables: numbers,
+        ..VersionEdit::default()
+    })
+}
+
+/// Deletes table files that the MANIFEST does not reference, e.g. left by a crash mid-flush.
+fn remove_obsolete_tables(dir: &Path, live: &[u64]) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let obsolete = match parse_table_file_name(&path) {
+            Some(number) => !live.contains(&number),
+            None => path.extension().is_some_and(|e| e == TMP_EXTENSION),
+        };
+        if obsolete {
+            info!("remove obsolete sstable {:?}", path);
+            fs::remove_file(path)?;
         }
-        self.maps.push_front(HashMap::from_iter(
-            records
-                .iter()
-                .map(|(key, value)| ((*key).clone(), value.clone().map(|v| v.clone()))),
-        ));
-        Ok(())
     }
+    Ok(())
 }
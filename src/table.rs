@@ -0,0 +1,884 @@
+use crate::block::{Block, BlockBuilder};
+use crate::compression::{codec, Codec};
+use crate::crc::crc32c;
+use crate::iterator::SortedRun;
+use crate::key::InternalKey;
+use crate::record::{self, RecordFormat};
+use crate::value::Value;
+use crate::varint::{get_bytes, get_varint, put_varint};
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::iter::FromIterator;
+use std::mem::size_of;
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Version of the tables written, recorded in their header: 4 has prefix-compressed blocks
+/// and the last key of each block in the index, 3 blocks of records. Tables without a header
+/// are of version 2 if they end with `MAGIC`, 1 if with `UNCHECKED_MAGIC` and 0, length-suffixed
+/// legacy records, otherwise.
+pub const FORMAT_VERSION: u32 = 4;
+/// Starts a table with a header, followed by the format version(u32).
+const HEADER_MAGIC: u64 = 0x7672_5f6c_6273_6d6c;
+/// `magic(u64) | format version(u32)`.
+const HEADER_SIZE: usize = size_of::<u64>() + size_of::<u32>();
+/// Uncompressed size at which a block is closed.
+const BLOCK_SIZE: usize = 4096;
+/// Ends every table in the block format; a legacy table ends with a record length instead.
+const MAGIC: u64 = 0x6c73_6d5f_7462_6c32;
+/// Ends tables in the block format written before checksums, see `decode_unchecked`.
+const UNCHECKED_MAGIC: u64 = 0x6c73_6d5f_7462_6c31;
+/// `index offset(u64) | file checksum(u32) | magic(u64)`.
+const FOOTER_SIZE: usize = 2 * size_of::<u64>() + size_of::<u32>();
+/// `offset(u64) | size(u64)` of a block in the index before version 4.
+const HANDLE_SIZE: usize = 2 * size_of::<u64>();
+/// `codec id(u8) | crc32c(u32)`, the checksum covering the block data and the codec id.
+const TRAILER_SIZE: usize = 1 + size_of::<u32>();
+
+/// Damaged table contents, found while reading the file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Corruption {
+    pub file: PathBuf,
+    /// Offset of the damaged block, index or footer.
+    pub offset: u64,
+    pub reason: String,
+}
+
+impl fmt::Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "corruption in {:?} at offset {}: {}",
+            self.file, self.offset, self.reason
+        )
+    }
+}
+
+impl Error for Corruption {}
+
+fn corruption(offset: usize, reason: impl fmt::Display) -> Corruption {
+    Corruption {
+        file: PathBuf::new(),
+        offset: offset as u64,
+        reason: reason.to_string(),
+    }
+}
+
+/// Encodes records sorted in internal key order as `header | block* | index | footer`.
+///
+/// A block, see `BlockBuilder`, is compressed with `codec` and followed by its trailer. The
+/// index holds `offset | size | last key length | last user key | last tag` per block,
+/// integers as varints and the size including the trailer, so that a lookup reads only the
+/// block that can hold its key. The file checksum covers everything before it.
+pub fn encode(records: &[(&InternalKey, Option<&Value>)], codec: &dyn Codec) -> Result<Vec<u8>> {
+    let mut binary = Vec::new();
+    binary.extend(&HEADER_MAGIC.to_le_bytes());
+    binary.extend(&FORMAT_VERSION.to_le_bytes());
+    let mut index: Vec<u8> = Vec::new();
+    let mut block = BlockBuilder::new();
+    let mut records = records.iter().peekable();
+    while let Some((key, value)) = records.next() {
+        block.add(key, *value);
+        if block.len() >= BLOCK_SIZE || records.peek().is_none() {
+            let offset = binary.len();
+            binary.extend(codec.compress(&block.finish())?);
+            binary.push(codec.id());
+            let crc = crc32c(&binary[offset..]);
+            binary.extend(&crc.to_le_bytes());
+            put_varint(&mut index, offset as u64);
+            put_varint(&mut index, (binary.len() - offset) as u64);
+            put_varint(&mut index, key.user_key.len() as u64);
+            index.extend(key.user_key.as_bytes());
+            put_varint(&mut index, key.tag());
+        }
+    }
+    let index_offset = binary.len() as u64;
+    binary.extend(index);
+    binary.extend(&index_offset.to_le_bytes());
+    let crc = crc32c(&binary);
+    binary.extend(&crc.to_le_bytes());
+    binary.extend(&MAGIC.to_le_bytes());
+    Ok(binary)
+}
+
+/// A table opened for reads. From version 4 on only its index is parsed when it is opened
+/// and blocks are decoded as lookups and iterators reach them; older tables are decoded in
+/// full.
+pub struct Table {
+    file: PathBuf,
+    version: u32,
+    contents: Contents,
+}
+
+/// The records of one block, in order.
+type BlockRecords = Arc<Vec<(InternalKey, Option<Value>)>>;
+
+enum Contents {
+    Blocks {
+        buffer: Vec<u8>,
+        handles: Vec<BlockHandle>,
+        /// Index and records of the block `next_after` read last, as iterators mostly read
+        /// on in the same block.
+        last_block: Mutex<Option<(usize, BlockRecords)>>,
+    },
+    /// Versions of a table before version 4. Those of a legacy table all have sequence 0, so
+    /// the last one written wins.
+    Records(BTreeMap<InternalKey, Option<Value>>),
+}
+
+impl Table {
+    /// Reads the table file; damage is reported as `Corruption`.
+    pub fn open(path: &Path, verify_checksums: bool) -> Result<Self> {
+        let buffer = fs::read(path)?;
+        Ok(Self::new(path.to_path_buf(), buffer, verify_checksums)?)
+    }
+
+    /// The table in `buffer`, read from `file`. Checksums are verified if `verify_checksums`,
+    /// here rather than on each read of a block.
+    pub fn new(file: PathBuf, buffer: Vec<u8>, verify_checksums: bool) -> Result<Self, Corruption> {
+        let parsed = format_version(&buffer).and_then(|version| {
+            if version < 4 {
+                let records = decode(&buffer, verify_checksums)?;
+                return Ok((version, Contents::Records(BTreeMap::from_iter(records))));
+            }
+            let (handles, footer_offset, checksum) = parse_footer(&buffer, version)?;
+            if verify_checksums {
+                // blocks are checked first so that damage in one is reported at its offset
+                for handle in &handles {
+                    verify_block(&buffer, handle)?;
+                }
+                if crc32c(&buffer[..footer_offset + 8]) != checksum {
+                    return Err(corruption(footer_offset, "file checksum mismatch"));
+                }
+            }
+            let contents = Contents::Blocks {
+                buffer,
+                handles,
+                last_block: Mutex::new(None),
+            };
+            Ok((version, contents))
+        });
+        match parsed {
+            Ok((version, contents)) => Ok(Self {
+                file,
+                version,
+                contents,
+            }),
+            Err(mut e) => {
+                e.file = file;
+                Err(e)
+            }
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Newest version of `key` with a sequence up to `sequence`, as its sequence and value,
+    /// `None` for a deletion.
+    ///
+    /// Only the block that can hold the version is read, found by a binary search over the
+    /// last keys in the index and then over the block's restart points.
+    pub fn get(
+        &self,
+        key: &str,
+        sequence: u64,
+    ) -> Result<Option<(u64, Option<Value>)>, Corruption> {
+        let target = InternalKey::for_seek(key, sequence);
+        let found = match &self.contents {
+            Contents::Blocks {
+                buffer, handles, ..
+            } => {
+                let index =
+                    handles.partition_point(|h| h.last_key.as_ref().is_some_and(|k| *k < target));
+                match handles.get(index) {
+                    Some(handle) => read_checked_block_data(buffer, handle, false)
+                        .and_then(|data| {
+                            Block::new(&data)
+                                .and_then(|block| block.seek(&target))
+                                .map_err(|e| corruption(handle.offset, e))
+                        })
+                        .map_err(|e| self.in_file(e))?,
+                    None => None,
+                }
+            }
+            Contents::Records(records) => records
+                .range(&target..)
+                .next()
+                .map(|(k, v)| (k.clone(), v.clone())),
+        };
+        Ok(found
+            .filter(|(k, _)| k.user_key == key)
+            .map(|(k, v)| (k.sequence, v)))
+    }
+
+    /// Every version, in internal key order.
+    pub fn records(&self) -> Result<Vec<(InternalKey, Option<Value>)>, Corruption> {
+        match &self.contents {
+            Contents::Blocks { handles, .. } => {
+                let mut records = vec![];
+                for index in 0..handles.len() {
+                    records.extend(self.block(index)?.iter().cloned());
+                }
+                Ok(records)
+            }
+            Contents::Records(records) => Ok(records
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect()),
+        }
+    }
+
+    /// The records of the block at `index` of a table of version 4 on.
+    fn block(&self, index: usize) -> Result<BlockRecords, Corruption> {
+        let (buffer, handle, last_block) = match &self.contents {
+            Contents::Blocks {
+                buffer,
+                handles,
+                last_block,
+            } => (buffer, &handles[index], last_block),
+            Contents::Records(_) => unreachable!("a table before version 4 has no block index"),
+        };
+        if let Some((last, records)) = &*last_block.lock().unwrap() {
+            if *last == index {
+                return Ok(records.clone());
+            }
+        }
+        let mut records = vec![];
+        read_checked_block_data(buffer, handle, false)
+            .and_then(|data| {
+                Block::new(&data)
+                    .and_then(|block| block.read_all(&mut records))
+                    .map_err(|e| corruption(handle.offset, e))
+            })
+            .map_err(|e| self.in_file(e))?;
+        let records = Arc::new(records);
+        *last_block.lock().unwrap() = Some((index, records.clone()));
+        Ok(records)
+    }
+
+    fn in_file(&self, mut e: Corruption) -> Corruption {
+        e.file.clone_from(&self.file);
+        e
+    }
+}
+
+impl SortedRun for Table {
+    fn next_after(
+        &self,
+        bound: Bound<&InternalKey>,
+    ) -> Result<Option<(InternalKey, Option<Value>)>, Corruption> {
+        let handles = match &self.contents {
+            Contents::Blocks { handles, .. } => handles,
+            Contents::Records(records) => return records.next_after(bound),
+        };
+        let after = |key: &InternalKey| match bound {
+            Bound::Included(bound) => key >= bound,
+            Bound::Excluded(bound) => key > bound,
+            Bound::Unbounded => true,
+        };
+        // the first block whose last key is after the bound holds the version
+        let index = handles.partition_point(|h| h.last_key.as_ref().is_some_and(|key| !after(key)));
+        if index == handles.len() {
+            return Ok(None);
+        }
+        let block = self.block(index)?;
+        let position = block.partition_point(|(key, _)| !after(key));
+        Ok(block.get(position).cloned())
+    }
+}
+
+/// The format version of a table, see `FORMAT_VERSION`.
+pub fn format_version(buffer: &[u8]) -> Result<u32, Corruption> {
+    let magic = buffer
+        .len()
+        .checked_sub(size_of::<u64>())
+        .map(|start| u64::from_le_bytes(buffer[start..].try_into().unwrap()));
+    match magic {
+        Some(MAGIC) => {}
+        Some(UNCHECKED_MAGIC) => return Ok(1),
+        _ => return Ok(0),
+    }
+    if buffer.len() < HEADER_SIZE
+        || u64::from_le_bytes(buffer[..8].try_into().unwrap()) != HEADER_MAGIC
+    {
+        return Ok(2);
+    }
+    match u32::from_le_bytes(buffer[8..HEADER_SIZE].try_into().unwrap()) {
+        version @ 3..=FORMAT_VERSION => Ok(version),
+        version => Err(corruption(
+            8,
+            format!("unsupported format version {}", version),
+        )),
+    }
+}
+
+/// How the records of a table of the format version are encoded.
+fn record_format(version: u32) -> RecordFormat {
+    if version >= 3 {
+        RecordFormat::Varint
+    } else {
+        RecordFormat::Legacy
+    }
+}
+
+/// Decodes every record of a table in any format, in order. Checksums, which tables before
+/// version 2 do not have, are verified if `verify_checksums`.
+pub fn decode(
+    buffer: &[u8],
+    verify_checksums: bool,
+) -> Result<Vec<(InternalKey, Option<Value>)>, Corruption> {
+    let version = format_version(buffer)?;
+    match version {
+        0 => return record::decode_records(buffer).map_err(|e| corruption(0, e)),
+        1 => return decode_unchecked(buffer),
+        _ => {}
+    }
+    let (handles, footer_offset, checksum) = parse_footer(buffer, version)?;
+    let mut records = vec![];
+    for handle in &handles {
+        read_checked_block(buffer, handle, verify_checksums, version, &mut records)?;
+    }
+    // blocks are checked first so that damage in one is reported at its offset
+    if verify_checksums && crc32c(&buffer[..footer_offset + 8]) != checksum {
+        return Err(corruption(footer_offset, "file checksum mismatch"));
+    }
+    Ok(records)
+}
+
+/// Layout of a table file, e.g. for inspection.
+#[derive(Debug, PartialEq)]
+pub struct Layout {
+    pub version: u32,
+    pub format: &'static str,
+    /// `(offset, size, codec id)` of each block, none for a legacy table.
+    pub blocks: Vec<(u64, u64, u8)>,
+}
+
+/// The format and blocks of a table, without reading the blocks.
+pub fn layout(buffer: &[u8]) -> Result<Layout, Corruption> {
+    let version = format_version(buffer)?;
+    let (format, handles, trailer_size) = match version {
+        0 => {
+            return Ok(Layout {
+                version,
+                format: "legacy",
+                blocks: vec![],
+            })
+        }
+        1 => (
+            "block without checksums",
+            parse_unchecked_footer(buffer)?,
+            1,
+        ),
+        2 => (
+            "block",
+            handles(parse_footer(buffer, version)?.0),
+            TRAILER_SIZE,
+        ),
+        3 => (
+            "block with header",
+            handles(parse_footer(buffer, version)?.0),
+            TRAILER_SIZE,
+        ),
+        _ => (
+            "prefix-compressed block",
+            handles(parse_footer(buffer, version)?.0),
+            TRAILER_SIZE,
+        ),
+    };
+    let blocks = handles
+        .into_iter()
+        .map(|(offset, size)| {
+            let codec_id = buffer[offset + size - trailer_size];
+            (offset as u64, size as u64, codec_id)
+        })
+        .collect();
+    Ok(Layout {
+        version,
+        format,
+        blocks,
+    })
+}
+
+/// Decodes the records of a possibly damaged table that are still readable, in order, with
+/// the damage found. Tables with checksums lose just their damaged blocks; a damaged table of
+/// an older format yields no records.
+pub fn salvage(buffer: &[u8]) -> (Vec<(InternalKey, Option<Value>)>, Vec<Corruption>) {
+    let version = match format_version(buffer) {
+        Ok(version) if version >= 2 => version,
+        _ => {
+            return match decode(buffer, true) {
+                Ok(records) => (records, vec![]),
+                Err(e) => (vec![], vec![e]),
+            }
+        }
+    };
+    let (handles, footer_offset, checksum) = match parse_footer(buffer, version) {
+        Ok(footer) => footer,
+        Err(e) => return (vec![], vec![e]),
+    };
+    let mut records = vec![];
+    let mut damage = vec![];
+    for handle in &handles {
+        let mut block = vec![];
+        match read_checked_block(buffer, handle, true, version, &mut block) {
+            Ok(()) => records.extend(block),
+            Err(e) => damage.push(e),
+        }
+    }
+    if damage.is_empty() && crc32c(&buffer[..footer_offset + 8]) != checksum {
+        damage.push(corruption(footer_offset, "file checksum mismatch"));
+    }
+    (records, damage)
+}
+
+/// Location of a block, with its last key from version 4 on.
+struct BlockHandle {
+    offset: usize,
+    size: usize,
+    last_key: Option<InternalKey>,
+}
+
+fn handles(handles: Vec<BlockHandle>) -> Vec<(usize, usize)> {
+    handles.into_iter().map(|h| (h.offset, h.size)).collect()
+}
+
+/// Block handles, footer offset and file checksum of a table in the block format with
+/// checksums.
+fn parse_footer(buffer: &[u8], version: u32) -> Result<(Vec<BlockHandle>, usize, u32), Corruption> {
+    let footer_offset = buffer
+        .len()
+        .checked_sub(FOOTER_SIZE)
+        .ok_or_else(|| corruption(0, "table shorter than its footer"))?;
+    let footer = &buffer[footer_offset..];
+    let index_offset = u64::from_le_bytes(footer[..8].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(footer[8..12].try_into().unwrap());
+    let index = buffer
+        .get(index_offset..footer_offset)
+        .ok_or_else(|| corruption(footer_offset, "invalid index offset"))?;
+    let handles = if version >= 4 {
+        parse_keyed_index(index).map_err(|e| corruption(index_offset, e))?
+    } else {
+        parse_index(index)
+            .map_err(|reason| corruption(index_offset, reason))?
+            .into_iter()
+            .map(|(offset, size)| BlockHandle {
+                offset,
+                size,
+                last_key: None,
+            })
+            .collect()
+    };
+    if handles.iter().any(|h| {
+        h.size < TRAILER_SIZE
+            || h.offset
+                .checked_add(h.size)
+                .is_none_or(|end| end > index_offset)
+    }) {
+        return Err(corruption(index_offset, "invalid block handle"));
+    }
+    Ok((handles, footer_offset, checksum))
+}
+
+/// Block handles of a version 4 index, see `encode`.
+fn parse_keyed_index(index: &[u8]) -> Result<Vec<BlockHandle>> {
+    let mut handles = vec![];
+    let mut position = 0;
+    while position < index.len() {
+        let offset = usize::try_from(get_varint(index, &mut position)?)?;
+        let size = usize::try_from(get_varint(index, &mut position)?)?;
+        let key_len = usize::try_from(get_varint(index, &mut position)?)?;
+        let user_key = String::from_utf8(get_bytes(index, &mut position, key_len)?.to_vec())?;
+        let tag = get_varint(index, &mut position)?;
+        let last_key = match InternalKey::from_tag(user_key, tag) {
+            Some(key) => key,
+            None => bail!("invalid index tag {}", tag),
+        };
+        handles.push(BlockHandle {
+            offset,
+            size,
+            last_key: Some(last_key),
+        });
+    }
+    Ok(handles)
+}
+
+/// Verifies the checksum in the block's trailer.
+fn verify_block(buffer: &[u8], handle: &BlockHandle) -> Result<(), Corruption> {
+    let block = &buffer[handle.offset..handle.offset + handle.size];
+    let (data, crc) = block.split_at(handle.size - size_of::<u32>());
+    if crc32c(data) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(corruption(handle.offset, "block checksum mismatch"));
+    }
+    Ok(())
+}
+
+/// Verifies the block if `verify_checksum` and returns its decompressed data.
+fn read_checked_block_data(
+    buffer: &[u8],
+    handle: &BlockHandle,
+    verify_checksum: bool,
+) -> Result<Vec<u8>, Corruption> {
+    if verify_checksum {
+        verify_block(buffer, handle)?;
+    }
+    let data = &buffer[handle.offset..handle.offset + handle.size - size_of::<u32>()];
+    let (data, codec_id) = data.split_at(data.len() - 1);
+    decompress(data, codec_id[0]).map_err(|e| corruption(handle.offset, e))
+}
+
+/// Verifies the block if `verify_checksum` and appends its records.
+fn read_checked_block(
+    buffer: &[u8],
+    handle: &BlockHandle,
+    verify_checksum: bool,
+    version: u32,
+    records: &mut Vec<(InternalKey, Option<Value>)>,
+) -> Result<(), Corruption> {
+    let data = read_checked_block_data(buffer, handle, verify_checksum)?;
+    let result = if version >= 4 {
+        Block::new(&data).and_then(|block| block.read_all(records))
+    } else {
+        read_records(&data, record_format(version), records)
+    };
+    result.map_err(|e| corruption(handle.offset, e))
+}
+
+/// Decodes a table whose blocks end with just the codec id and whose footer has no checksum.
+fn decode_unchecked(buffer: &[u8]) -> Result<Vec<(InternalKey, Option<Value>)>, Corruption> {
+    let mut records = vec![];
+    for (offset, size) in parse_unchecked_footer(buffer)? {
+        let (data, codec_id) = buffer[offset..offset + size].split_at(size - 1);
+        decompress(data, codec_id[0])
+            .and_then(|data| read_records(&data, RecordFormat::Legacy, &mut records))
+            .map_err(|e| corruption(offset, e))?;
+    }
+    Ok(records)
+}
+
+/// Block handles of a table written before checksums.
+fn parse_unchecked_footer(buffer: &[u8]) -> Result<Vec<(usize, usize)>, Corruption> {
+    let footer_offset = buffer
+        .len()
+        .checked_sub(2 * size_of::<u64>())
+        .ok_or_else(|| corruption(0, "table shorter than its footer"))?;
+    let index_offset =
+        u64::from_le_bytes(buffer[footer_offset..footer_offset + 8].try_into().unwrap()) as usize;
+    let index = buffer
+        .get(index_offset..footer_offset)
+        .ok_or_else(|| corruption(footer_offset, "invalid index offset"))?;
+    let handles = parse_index(index).map_err(|reason| corruption(index_offset, reason))?;
+    if handles.iter().any(|(offset, size)| {
+        *size == 0
+            || offset
+                .checked_add(*size)
+                .is_none_or(|end| end > index_offset)
+    }) {
+        return Err(corruption(index_offset, "invalid block handle"));
+    }
+    Ok(handles)
+}
+
+/// Block handles as `(offset, size)`.
+fn parse_index(index: &[u8]) -> Result<Vec<(usize, usize)>, String> {
+    if !index.len().is_multiple_of(HANDLE_SIZE) {
+        return Err(format!("invalid index length {}", index.len()));
+    }
+    Ok(index
+        .chunks(HANDLE_SIZE)
+        .map(|handle| {
+            let offset = u64::from_le_bytes(handle[..8].try_into().unwrap());
+            let size = u64::from_le_bytes(handle[8..].try_into().unwrap());
+            (offset as usize, size as usize)
+        })
+        .collect())
+}
+
+/// Decompresses block data with the codec `codec_id`.
+fn decompress(data: &[u8], codec_id: u8) -> Result<Vec<u8>> {
+    match codec(codec_id) {
+        Some(codec) => codec.decompress(data),
+        None => bail!("unknown codec {}", codec_id),
+    }
+}
+
+/// Appends the `(length(u32) | record)*` of a block before version 4.
+fn read_records(
+    data: &[u8],
+    format: RecordFormat,
+    records: &mut Vec<(InternalKey, Option<Value>)>,
+) -> Result<()> {
+    let mut index = 0;
+    while index < data.len() {
+        let len = match data.get(index..index + size_of::<u32>()) {
+            Some(len) => u32::from_le_bytes(len.try_into()?) as usize,
+            None => bail!("block truncated"),
+        };
+        index += size_of::<u32>();
+        match data.get(index..index + len) {
+            Some(record) => records.push(format.decode(record)?),
+            None => bail!("block truncated"),
+        }
+        index += len;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::Compression;
+    use crate::crc::crc32c;
+    use crate::iterator::SortedRun;
+    use crate::key::{InternalKey, ValueType};
+    use crate::record;
+    use crate::table::{
+        decode, encode, format_version, layout, salvage, Table, FORMAT_VERSION, MAGIC,
+        UNCHECKED_MAGIC,
+    };
+    use crate::value::Value;
+    use std::ops::Bound;
+    use std::path::PathBuf;
+
+    fn table(binary: &[u8]) -> Table {
+        Table::new(PathBuf::new(), binary.to_vec(), true).unwrap()
+    }
+
+    #[test]
+    fn encode_decode() {
+        let value = Value::new("{\"field\":\"value\"}".repeat(10), 0, 0);
+        let keys: Vec<_> = (0..500)
+            .map(|i| {
+                let value_type = if i % 7 == 0 {
+                    ValueType::Deletion
+                } else {
+                    ValueType::Value
+                };
+                InternalKey::new(format!("key{:04}", i), i + 1, value_type)
+            })
+            .collect();
+        let records: Vec<_> = keys
+            .iter()
+            .map(|k| (k, (k.value_type == ValueType::Value).then_some(&value)))
+            .collect();
+        let expected: Vec<_> = records
+            .iter()
+            .map(|(k, v)| ((*k).clone(), v.cloned()))
+            .collect();
+        let plain = encode(&records, Compression::None.codec()).unwrap();
+        for compression in [Compression::None, Compression::Snappy, Compression::Lz4] {
+            let binary = encode(&records, compression.codec()).unwrap();
+            if compression != Compression::None {
+                assert!(binary.len() < plain.len() / 4);
+            }
+            assert_eq!(decode(&binary, true).unwrap(), expected);
+        }
+        assert_eq!(
+            decode(&encode(&[], Compression::Lz4.codec()).unwrap(), true).unwrap(),
+            vec![]
+        );
+
+        assert_eq!(format_version(&plain), Ok(FORMAT_VERSION));
+
+        // legacy tables are length-suffixed legacy records without blocks
+        let mut legacy = Vec::new();
+        for (key, value) in &records {
+            let record = record::encode_legacy(key, *value);
+            legacy.extend(&record);
+            legacy.extend(&(record.len() as i32).to_le_bytes());
+        }
+        assert_eq!(format_version(&legacy), Ok(0));
+        assert_eq!(decode(&legacy, true).unwrap(), expected);
+
+        // blocks written before checksums end with just the codec id
+        let mut block = Vec::new();
+        for (key, value) in &records[..2] {
+            let record = record::encode_legacy(key, *value);
+            block.extend(&(record.len() as u32).to_le_bytes());
+            block.extend(record);
+        }
+        block.push(Compression::None.codec().id());
+        let mut unchecked = block.clone();
+        let block_size = unchecked.len() as u64;
+        unchecked.extend(&0u64.to_le_bytes());
+        unchecked.extend(&block_size.to_le_bytes());
+        unchecked.extend(&block_size.to_le_bytes());
+        unchecked.extend(&UNCHECKED_MAGIC.to_le_bytes());
+        assert_eq!(format_version(&unchecked), Ok(1));
+        assert_eq!(decode(&unchecked, true).unwrap(), expected[..2]);
+
+        // and tables with checksums before the header have legacy records
+        let mut checked = block;
+        checked.extend(&crc32c(&checked).to_le_bytes());
+        let block_size = checked.len() as u64;
+        checked.extend(&0u64.to_le_bytes());
+        checked.extend(&block_size.to_le_bytes());
+        checked.extend(&block_size.to_le_bytes());
+        checked.extend(&crc32c(&checked).to_le_bytes());
+        checked.extend(&MAGIC.to_le_bytes());
+        assert_eq!(format_version(&checked), Ok(2));
+        assert_eq!(decode(&checked, true).unwrap(), expected[..2]);
+
+        assert_eq!(
+            table(&legacy).get("key0001", u64::MAX).unwrap(),
+            Some((2, Some(value.clone())))
+        );
+        assert_eq!(
+            table(&checked).get("key0000", u64::MAX).unwrap(),
+            Some((1, None))
+        );
+        assert_eq!(table(&legacy).records().unwrap(), expected);
+        assert_eq!(table(&plain).records().unwrap(), expected);
+
+        let mut future = plain.clone();
+        future[8] += 1;
+        assert!(decode(&future, false).is_err());
+    }
+
+    #[test]
+    fn detect_corruption() {
+        let value = Value::new("v".repeat(100), 0, 0);
+        let keys: Vec<_> = (0..200)
+            .map(|i| InternalKey::new(format!("key{:04}", i), i + 1, ValueType::Value))
+            .collect();
+        let records: Vec<_> = keys.iter().map(|k| (k, Some(&value))).collect();
+        let binary = encode(&records, Compression::None.codec()).unwrap();
+
+        // a flipped bit in a value in the second block, which starts past the first 4096 bytes
+        let mut corrupted = binary.clone();
+        let position = (6000..)
+            .find(|i| binary[i - 2..i + 3].iter().all(|b| *b == b'v'))
+            .unwrap();
+        corrupted[position] ^= 1;
+        let error = decode(&corrupted, true).unwrap_err();
+        assert_eq!(error.reason, "block checksum mismatch");
+        assert!(error.offset > 4096 && error.offset <= position as u64);
+        // without verification the damaged value is read as is
+        let decoded = decode(&corrupted, false).unwrap();
+        assert_eq!(decoded.len(), records.len());
+        assert!(decoded.iter().any(|(_, v)| v.as_ref() != Some(&value)));
+        // salvage keeps the other blocks
+        let (salvaged, damage) = salvage(&corrupted);
+        assert_eq!(damage, vec![error]);
+        assert!(!salvaged.is_empty() && salvaged.len() < records.len());
+        assert!(salvaged.iter().all(|(_, v)| v.as_ref() == Some(&value)));
+        assert_eq!(salvaged[0].0, keys[0]);
+        assert_eq!(salvaged.last().unwrap().0, keys[keys.len() - 1]);
+
+        // a flipped bit in the index is caught by the file checksum
+        let mut corrupted = binary.clone();
+        let index_offset = binary.len() - 20 - 16;
+        corrupted[index_offset + 1] ^= 1;
+        assert!(decode(&corrupted, true).is_err());
+
+        // a truncated file falls back to the legacy format and fails without panicking
+        assert!(decode(&binary[..binary.len() - 3], true).is_err());
+        assert!(decode(&binary[..5], false).is_err());
+
+        // a block that does not decode is reported by the reads that reach it, not at open
+        let (offset, size, _) = layout(&binary).unwrap().blocks[1];
+        let count_offset = (offset + size) as usize - 4 - 1 - 4;
+        let mut corrupted = binary.clone();
+        corrupted[count_offset..count_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        let file = PathBuf::from("00001.bin");
+        assert!(Table::new(file.clone(), corrupted.clone(), true).is_err());
+        let damaged = Table::new(file.clone(), corrupted, false).unwrap();
+        assert_eq!(
+            damaged.get("key0000", u64::MAX).unwrap(),
+            Some((1, Some(value.clone())))
+        );
+        let error = keys
+            .iter()
+            .find_map(|k| damaged.get(&k.user_key, u64::MAX).err())
+            .unwrap();
+        assert_eq!((error.file, error.offset), (file, offset));
+        assert!(damaged.records().is_err());
+        let mut bound = Bound::Unbounded;
+        let error = loop {
+            match damaged.next_after(bound.as_ref()) {
+                Ok(Some((key, _))) => bound = Bound::Excluded(key),
+                Ok(None) => panic!("iterated past a damaged block"),
+                Err(e) => break e,
+            }
+        };
+        assert_eq!(error.offset, offset);
+    }
+
+    #[test]
+    fn get_across_blocks() {
+        let value = |i: u64| Value::new(format!("{}", i).repeat(30), 0, 0);
+        // a few versions per key, newest first
+        let keys: Vec<_> = (0..300u64)
+            .flat_map(|i| {
+                (0..3).map(move |v| {
+                    let value_type = if i % 10 == 0 && v == 0 {
+                        ValueType::Deletion
+                    } else {
+                        ValueType::Value
+                    };
+                    InternalKey::new(
+                        format!("tenant:1:user:{:04}", i),
+                        10 * i + 3 - v,
+                        value_type,
+                    )
+                })
+            })
+            .collect();
+        let values: Vec<_> = keys.iter().map(|k| value(k.sequence)).collect();
+        let records: Vec<_> = keys
+            .iter()
+            .zip(&values)
+            .map(|(k, v)| (k, (k.value_type == ValueType::Value).then_some(v)))
+            .collect();
+        let loaded = table(&encode(&records, Compression::Snappy.codec()).unwrap());
+        for i in [0u64, 1, 137, 150, 299] {
+            let key = format!("tenant:1:user:{:04}", i);
+            let newest = if i % 10 == 0 {
+                None
+            } else {
+                Some(value(10 * i + 3))
+            };
+            assert_eq!(
+                loaded.get(&key, u64::MAX).unwrap(),
+                Some((10 * i + 3, newest))
+            );
+            assert_eq!(
+                loaded.get(&key, 10 * i + 2).unwrap(),
+                Some((10 * i + 2, Some(value(10 * i + 2))))
+            );
+            assert_eq!(loaded.get(&key, 10 * i).unwrap(), None);
+        }
+        assert_eq!(loaded.get("tenant:1:user:0300", u64::MAX).unwrap(), None);
+        assert_eq!(loaded.get("tenant:1:user:0005a", u64::MAX).unwrap(), None);
+        assert_eq!(loaded.get("a", u64::MAX).unwrap(), None);
+
+        // iteration crosses the blocks in order, from any bound
+        let expected: Vec<_> = records
+            .iter()
+            .map(|(k, v)| ((*k).clone(), v.cloned()))
+            .collect();
+        let mut iterated = vec![];
+        let mut bound = Bound::Unbounded;
+        while let Some(record) = loaded.next_after(bound.as_ref()).unwrap() {
+            bound = Bound::Excluded(record.0.clone());
+            iterated.push(record);
+        }
+        assert_eq!(iterated, expected);
+        for i in [0, 1, 400, 899] {
+            assert_eq!(
+                loaded.next_after(Bound::Included(&keys[i])).unwrap(),
+                Some(expected[i].clone())
+            );
+        }
+        assert_eq!(
+            loaded.next_after(Bound::Excluded(&keys[899])).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use crate::column_family::ColumnFamily;
+use crate::db::Db;
+use crate::value::Value;
+use crate::write_batch::WriteBatch;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+/// Commit failure because a key the transaction read was written by someone else since.
+#[derive(Debug, PartialEq)]
+pub struct Conflict {
+    pub key: String,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "transaction conflict on key {}", self.key)
+    }
+}
+
+impl Error for Conflict {}
+
+/// Optimistic read-modify-write transaction, started with `Db::begin_transaction`.
+///
+/// Writes are buffered and read back by `get`. Each key read remembers the sequence of the
+/// version seen; `commit` fails with `Conflict` if any of them has been written since, and
+/// otherwise applies the writes as one atomic batch.
+pub struct Transaction<'a> {
+    db: &'a Db,
+    batch: WriteBatch,
+    /// Values written per column family id and key.
+    writes: HashMap<(u32, String), Option<Value>>,
+    /// Sequence of the version read per column family id and key, 0 if there was none.
+    reads: HashMap<(u32, String), u64>,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn new(db: &'a Db) -> Self {
+        Self {
+            db,
+            batch: WriteBatch::new(),
+            writes: HashMap::new(),
+            reads: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<Value>> {
+        self.get_cf(&ColumnFamily::default(), key)
+    }
+
+    pub fn put(&mut self, key: String, value: Value) {
+        self.put_cf(&ColumnFamily::default(), key, value);
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        self.delete_cf(&ColumnFamily::default(), key);
+    }
+
+    pub fn get_cf(&mut self, column_family: &ColumnFamily, key: &str) -> Result<Option<Value>> {
+        let id = (column_family.id(), key.to_string());
+        if let Some(value) = self.writes.get(&id) {
+            return Ok(value.clone());
+        }
+        let (sequence, value) = self
+            .db
+            .get_version(column_family, key)?
+            .unwrap_or((0, None));
+        // a later read of the same key must not move the validated sequence forward
+        self.reads.entry(id).or_insert(sequence);
+        Ok(value)
+    }
+
+    pub fn put_cf(&mut self, column_family: &ColumnFamily, key: String, value: Value) {
+        self.writes
+            .insert((column_family.id(), key.clone()), Some(value.clone()));
+        self.batch.put_cf(column_family, key, value);
+    }
+
+    pub fn delete_cf(&mut self, column_family: &ColumnFamily, key: &str) {
+        self.writes
+            .insert((column_family.id(), key.to_string()), None);
+        self.batch.delete_cf(column_family, key.to_string());
+    }
+
+    /// Validates the reads and applies the writes atomically, or fails with `Conflict`.
+    pub fn commit(self) -> Result<()> {
+        let reads = self
+            .reads
+            .into_iter()
+            .map(|((id, key), sequence)| (id, key, sequence))
+            .collect();
+        self.db.commit_transaction(self.batch, reads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use crate::options::Options;
+    use crate::transaction::Conflict;
+    use crate::value::Value;
+    use std::fs::remove_dir_all;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn value(data: &str) -> Value {
+        Value::new(data.to_string(), 0, 0)
+    }
+
+    #[test]
+    fn conflict() {
+        let dir = std::env::temp_dir().join("lsm_engine_transaction_conflict");
+        let _ = remove_dir_all(&dir);
+        let db = Db::open(&dir, Options::default()).unwrap();
+        db.put("a".to_string(), value("1")).unwrap();
+
+        let mut transaction = db.begin_transaction();
+        assert_eq!(transaction.get("a").unwrap(), Some(value("1")));
+        transaction.put("a".to_string(), value("2"));
+        transaction.delete("b");
+        assert_eq!(transaction.get("a").unwrap(), Some(value("2")));
+        assert_eq!(transaction.get("b").unwrap(), None);
+        db.put("a".to_string(), value("other")).unwrap();
+        let error = transaction.commit().unwrap_err();
+        assert_eq!(
+            error.downcast_ref::<Conflict>(),
+            Some(&Conflict {
+                key: "a".to_string()
+            })
+        );
+        assert_eq!(db.get("a").unwrap(), Some(value("other")));
+
+        // a key that did not exist conflicts once it is created
+        let mut transaction = db.begin_transaction();
+        assert_eq!(transaction.get("c").unwrap(), None);
+        db.put("c".to_string(), value("1")).unwrap();
+        assert!(transaction.commit().is_err());
+
+        // writes to keys not read do not conflict
+        let mut transaction = db.begin_transaction();
+        transaction.get("a").unwrap();
+        transaction.put("d".to_string(), value("1"));
+        db.put("c".to_string(), value("2")).unwrap();
+        transaction.commit().unwrap();
+        assert_eq!(db.get("d").unwrap(), Some(value("1")));
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_increments() {
+        let dir = std::env::temp_dir().join("lsm_engine_transaction_concurrent_increments");
+        let _ = remove_dir_all(&dir);
+        let db = Arc::new(Db::open(&dir, Options::default()).unwrap());
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let db = db.clone();
+                thread::spawn(move || {
+                    for _ in 0..25 {
+                        loop {
+                            let mut transaction = db.begin_transaction();
+                            let count = transaction.get("count").unwrap().map_or(0, |v| {
+                                v.to_string(String::new())
+                                    .lines()
+                                    .nth(1)
+                                    .unwrap()
+                                    .parse()
+                                    .unwrap()
+                            });
+                            transaction.put("count".to_string(), value(&(count + 1).to_string()));
+                            if transaction.commit().is_ok() {
+                                break;
+                            }
+                        }
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(db.get("count").unwrap(), Some(value("100")));
+        drop(db);
+        remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,8 +1,9 @@
+use crate::record::take_back;
 use anyhow::Result;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
 use std::mem::size_of;
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct Value {
     data: String,
     flags: usize,
@@ -16,32 +17,51 @@ impl Value {
             exptime,
         }
     }
+    /// Encodes the value as in legacy records, with flags and exptime as `u64`, the width of
+    /// `usize` on the 64-bit builds that wrote them.
+    #[cfg(test)]
     pub fn as_bytes(&self) -> Vec<u8> {
         let mut value_bytes: Vec<u8> = Vec::new();
-        value_bytes.extend(self.flags.to_le_bytes().to_vec());
-        value_bytes.extend(self.exptime.to_le_bytes().to_vec());
+        value_bytes.extend((self.flags as u64).to_le_bytes().to_vec());
+        value_bytes.extend((self.exptime as u64).to_le_bytes().to_vec());
         let data_bytes = self.data.clone().into_bytes();
         value_bytes.extend(&data_bytes);
         value_bytes.extend(&(data_bytes.len() as i32).to_le_bytes());
         value_bytes
     }
+    /// Decodes a value of a legacy record, see `as_bytes`.
     pub fn from_bytes(vec: Vec<u8>) -> Result<Self> {
         let mut index = vec.len();
-        index -= size_of::<i32>();
         let data_len =
-            i32::from_le_bytes(vec[index..(index + size_of::<i32>())].try_into()?) as usize;
-        index -= data_len;
-        let data = String::from_utf8(vec[index..(index + data_len)].to_vec())?;
+            i32::from_le_bytes(take_back(&vec, &mut index, size_of::<i32>())?.try_into()?);
+        let data = take_back(&vec, &mut index, usize::try_from(data_len)?)?;
+        let data = String::from_utf8(data.to_vec())?;
 
-        index -= size_of::<usize>();
-        let exptime = usize::from_le_bytes(vec[index..(index + size_of::<usize>())].try_into()?);
-
-        index -= size_of::<usize>();
-        let flags = usize::from_le_bytes(vec[index..(index + size_of::<usize>())].try_into()?);
+        let exptime =
+            u64::from_le_bytes(take_back(&vec, &mut index, size_of::<u64>())?.try_into()?);
+        let flags = u64::from_le_bytes(take_back(&vec, &mut index, size_of::<u64>())?.try_into()?);
+        let (flags, exptime) = (usize::try_from(flags)?, usize::try_from(exptime)?);
 
         Ok(Self::new(data, flags, exptime))
     }
 
+    pub fn data(&self) -> &str {
+        &self.data
+    }
+
+    pub fn flags(&self) -> usize {
+        self.flags
+    }
+
+    pub fn exptime(&self) -> usize {
+        self.exptime
+    }
+
+    /// Approximate number of bytes the value occupies in memory.
+    pub fn approximate_size(&self) -> usize {
+        size_of::<Self>() + self.data.len()
+    }
+
     pub fn to_string(&self, key: String) -> String {
         format!(
             "VALUE {} {} {} {}\n{}\n",
@@ -0,0 +1,69 @@
+use anyhow::{bail, Result};
+
+/// Appends `value` as a LEB128 varint: 7 bits per byte, least significant first, the high bit
+/// set on all but the last byte.
+pub fn put_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// Reads the varint at `index` and moves `index` past it.
+pub fn get_varint(buffer: &[u8], index: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = match buffer.get(*index) {
+            Some(byte) => *byte,
+            None => bail!("varint truncated at {}", index),
+        };
+        *index += 1;
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte < 0x80 {
+            return Ok(value);
+        }
+    }
+    bail!("varint too long at {}", index)
+}
+
+/// Reads `len` bytes at `index` and moves `index` past them.
+pub fn get_bytes<'a>(buffer: &'a [u8], index: &mut usize, len: usize) -> Result<&'a [u8]> {
+    match index
+        .checked_add(len)
+        .and_then(|end| buffer.get(*index..end))
+    {
+        Some(bytes) => {
+            *index += len;
+            Ok(bytes)
+        }
+        None => bail!(
+            "{} bytes wanted at {}, {} available",
+            len,
+            index,
+            buffer.len()
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::varint::{get_varint, put_varint};
+
+    #[test]
+    fn round_trip() {
+        let values = [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX];
+        let mut buffer = vec![];
+        for value in values {
+            put_varint(&mut buffer, value);
+        }
+        assert_eq!(buffer[..4], [0, 1, 127, 0x80]);
+        let mut index = 0;
+        for value in values {
+            assert_eq!(get_varint(&buffer, &mut index).unwrap(), value);
+        }
+        assert_eq!(index, buffer.len());
+        assert!(get_varint(&[0x80], &mut 0).is_err());
+        assert!(get_varint(&[0xff; 11], &mut 0).is_err());
+    }
+}
@@ -1,43 +1,634 @@
-use crate::record::{decode_file, encode};
-use crate::value::Value;
-use anyhow::Result;
-use log::info;
-use std::fs::{remove_file, File, OpenOptions};
-use std::io::Write;
-use std::path::Path;
+use crate::crc::{crc32c, extend};
+use crate::manifest::sync_dir;
+use crate::options::{WalRecoveryMode, WalSyncPolicy};
+use crate::record::{decode_file, RecordFormat};
+use crate::write_batch::{self, BatchRecord};
+use anyhow::{anyhow, bail, Result};
+use log::{info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::fs::{create_dir_all, read_dir, remove_file, rename, File, OpenOptions};
+use std::io::{Read, Write};
+use std::mem::size_of;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
 
+/// Name of the WAL written before segments, a plain sequence of records.
+pub(crate) const LEGACY_WAL_FILE: &str = "wal.bin";
+/// Version of the segments written, recorded in their header. Segments without one are of
+/// version 1, their records in the legacy encoding.
+pub const FORMAT_VERSION: u32 = 2;
+/// Starts a segment with a header, followed by the format version(u32).
+const WAL_MAGIC: u64 = 0x7672_5f6c_6177_6d6c;
+/// `magic(u64) | format version(u32)`.
+const WAL_HEADER_SIZE: usize = size_of::<u64>() + size_of::<u32>();
+/// Subdirectory obsolete segments are moved to when archiving is enabled.
+const ARCHIVE_DIR: &str = "archive";
+
+/// `crc32c(u32) | length(u32) | type(u8)`, the checksum covering type and payload.
+const HEADER_SIZE: usize = 9;
+/// A single record, as logged before write batches.
+const TYPE_RECORD: u8 = 1;
+/// A write batch of the default column family only, see `write_batch::encode`.
+const TYPE_BATCH: u8 = 2;
+/// A write batch with the column family of each record.
+const TYPE_COLUMN_FAMILY_BATCH: u8 = 3;
+
+/// One WAL segment. A memtable's writes span one or more consecutive segments, rotated when a
+/// segment reaches `Options::wal_segment_size` or the memtable is switched.
 pub struct Wal {
-    path: &'static Path,
-    write_file: File,
+    number: u64,
+    path: PathBuf,
+    write_file: Arc<File>,
+    sync_policy: WalSyncPolicy,
+    size: u64,
+    /// Makes the next write stop halfway and fail, as a full disk or I/O error would.
+    #[cfg(test)]
+    pub(crate) fail_next_write: bool,
 }
 
 impl Wal {
-    pub fn new(path: &'static Path) -> Result<Self> {
-        let file = OpenOptions::new().create(true).append(true).open(path)?;
+    /// Opens the WAL numbered `number` in `dir` for append, creating it with a header if
+    /// needed.
+    pub fn open(dir: &Path, number: u64, sync_policy: WalSyncPolicy) -> Result<Self> {
+        let path = dir.join(log_file_name(number));
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(&header())?;
+        }
+        let size = file.metadata()?.len();
         Ok(Self {
+            number,
             path,
-            write_file: file,
+            write_file: Arc::new(file),
+            sync_policy,
+            size,
+            #[cfg(test)]
+            fail_next_write: false,
         })
     }
 
-    pub fn write(&mut self, key: &String, value: Option<&Value>) -> Result<()> {
-        let binary = encode(key, value);
-        self.write_file.write(&binary)?;
-        let binary_len = binary.len() as i32;
-        self.write_file.write(&binary_len.to_le_bytes())?;
-        Ok(())
+    pub fn number(&self) -> u64 {
+        self.number
     }
-    pub fn recover(&mut self) -> Result<Vec<(String, Option<Value>)>> {
-        info!("recover from wal {}", self.path.to_str().unwrap());
-        decode_file(self.path)
-    }
-    pub fn clear(&mut self) -> Result<()> {
-        remove_file(self.path)?;
-        self.write_file = OpenOptions::new()
-            .create_new(true)
-            .append(true)
-            .open(self.path)?;
-        info!("clear wal {}", self.path.to_str().unwrap());
+
+    /// Bytes written to the segment so far.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Appends the records, which have consecutive sequences, as one atomic WAL record with a
+    /// single write, followed by one fsync under `WalSyncPolicy::Always`.
+    pub fn write(&mut self, records: &[BatchRecord]) -> Result<()> {
+        let binary = frame_batch(records);
+        #[cfg(test)]
+        {
+            if std::mem::take(&mut self.fail_next_write) {
+                (&*self.write_file).write_all(&binary[..binary.len() / 2])?;
+                bail!("injected wal write failure");
+            }
+        }
+        (&*self.write_file).write_all(&binary)?;
+        self.size += binary.len() as u64;
+        if self.sync_policy == WalSyncPolicy::Always {
+            self.write_file.sync_data()?;
+        }
         Ok(())
     }
+
+    /// Handle for syncing the file from another thread, e.g. under `WalSyncPolicy::Periodic`.
+    pub fn sync_handle(&self) -> Arc<File> {
+        self.write_file.clone()
+    }
+
+    /// Reads the records back in write order.
+    ///
+    /// Reading stops at the first record with a bad checksum or framing, which is what a torn
+    /// write at the tail looks like; `SkipAnyCorruptedRecords` instead resumes at the next
+    /// intact record. Dropped bytes are logged and returned.
+    pub fn recover(&self, mode: WalRecoveryMode) -> Result<Recovered> {
+        info!("recover from wal {:?}", self.path);
+        read_log(&self.path, mode)
+    }
+}
+
+/// Reads the records of the WAL file at `path`, see `Wal::recover`.
+pub fn read_log(path: &Path, mode: WalRecoveryMode) -> Result<Recovered> {
+    let mut buffer = Vec::new();
+    File::open(path)?.read_to_end(&mut buffer)?;
+    let mut recovered = Recovered {
+        format_version: 1,
+        records: vec![],
+        dropped_bytes: 0,
+    };
+    let mut index = 0;
+    if buffer.len() >= WAL_HEADER_SIZE && u64::from_le_bytes(buffer[..8].try_into()?) == WAL_MAGIC {
+        recovered.format_version = u32::from_le_bytes(buffer[8..WAL_HEADER_SIZE].try_into()?);
+        if !(2..=FORMAT_VERSION).contains(&recovered.format_version) {
+            bail!(
+                "wal {:?}: unsupported format version {}",
+                path,
+                recovered.format_version
+            );
+        }
+        index = WAL_HEADER_SIZE;
+    }
+    let format = if recovered.format_version >= 2 {
+        RecordFormat::Varint
+    } else {
+        RecordFormat::Legacy
+    };
+    while index < buffer.len() {
+        match read_record(&buffer[index..], format) {
+            Some((records, len)) => {
+                recovered.records.extend(records);
+                index += len;
+            }
+            None if mode == WalRecoveryMode::SkipAnyCorruptedRecords => {
+                recovered.dropped_bytes += 1;
+                index += 1;
+            }
+            None => {
+                recovered.dropped_bytes = buffer.len() - index;
+                break;
+            }
+        }
+    }
+    if recovered.dropped_bytes > 0 {
+        warn!(
+            "wal {:?}: dropped {} bytes of corrupt records",
+            path, recovered.dropped_bytes
+        );
+    }
+    Ok(recovered)
+}
+
+const MAX_GROUP_SIZE: usize = 128;
+
+struct GroupState<T> {
+    next_id: u64,
+    /// Queued writers in arrival order; the front one is the leader.
+    queue: VecDeque<(u64, Option<T>)>,
+    /// Results of followers whose group the leader has written.
+    done: HashMap<u64, Result<()>>,
+}
+
+/// Group commit for concurrent writers.
+///
+/// Writers queue their item and the one at the front becomes the leader: it takes the items of
+/// every writer queued behind it, writes them with a single call to `write`, e.g. one WAL write
+/// and one fsync, then wakes the followers with their results. Writers arriving meanwhile
+/// queue up for the next group, so durable throughput grows with the number of writers.
+pub struct GroupCommit<T> {
+    state: Mutex<GroupState<T>>,
+    cv: Condvar,
+}
+
+impl<T> GroupCommit<T> {
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::new(GroupState {
+                next_id: 0,
+                queue: VecDeque::new(),
+                done: HashMap::new(),
+            }),
+            cv: Condvar::new(),
+        }
+    }
+
+    /// Returns once `item` has been written as part of a group.
+    ///
+    /// `write` returns a result per item, e.g. to reject some of them, or an error failing the
+    /// whole group.
+    pub fn commit<F>(&self, item: T, write: F) -> Result<()>
+    where
+        F: FnOnce(Vec<T>) -> Result<Vec<Result<()>>>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queue.push_back((id, Some(item)));
+        loop {
+            if let Some(result) = state.done.remove(&id) {
+                return result;
+            }
+            if state.queue.front().map(|(front, _)| *front) == Some(id) {
+                break;
+            }
+            state = self.cv.wait(state).unwrap();
+        }
+
+        // the group stays queued while it is written so that new writers wait behind it
+        let group: Vec<T> = state
+            .queue
+            .iter_mut()
+            .take(MAX_GROUP_SIZE)
+            .filter_map(|(_, item)| item.take())
+            .collect();
+        drop(state);
+        let leader = Leader {
+            group_commit: self,
+            id,
+            len: group.len(),
+            finished: false,
+        };
+        let mut results: Vec<Result<()>> = match write(group) {
+            Ok(results) => results,
+            Err(e) => {
+                let e = e.to_string();
+                (0..leader.len).map(|_| Err(anyhow!(e.clone()))).collect()
+            }
+        };
+        results.resize_with(leader.len, || {
+            Err(anyhow!("no result for group commit item"))
+        });
+        leader.finish(results)
+    }
+
+    /// Dequeues the group led by `id`, hands the followers their results and returns the
+    /// leader's.
+    fn complete(&self, id: u64, results: Vec<Result<()>>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let mut result = Ok(());
+        for item_result in results {
+            if let Some((writer, _)) = state.queue.pop_front() {
+                if writer == id {
+                    result = item_result;
+                } else {
+                    state.done.insert(writer, item_result);
+                }
+            }
+        }
+        self.cv.notify_all();
+        result
+    }
+}
+
+/// The group being written by its leader. Should `write` panic, dropping it fails the group,
+/// so that its followers and the writers queued behind it do not wait forever.
+struct Leader<'a, T> {
+    group_commit: &'a GroupCommit<T>,
+    id: u64,
+    len: usize,
+    finished: bool,
+}
+
+impl<T> Leader<'_, T> {
+    fn finish(mut self, results: Vec<Result<()>>) -> Result<()> {
+        self.finished = true;
+        self.group_commit.complete(self.id, results)
+    }
+}
+
+impl<T> Drop for Leader<'_, T> {
+    fn drop(&mut self) {
+        if !self.finished {
+            let results = (0..self.len)
+                .map(|_| Err(anyhow!("group commit write panicked")))
+                .collect();
+            let _ = self.group_commit.complete(self.id, results);
+        }
+    }
+}
+
+impl<T> Default for GroupCommit<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Recovered {
+    pub format_version: u32,
+    pub records: Vec<BatchRecord>,
+    pub dropped_bytes: usize,
+}
+
+/// Durably writes the records as a new WAL file at `path`, one WAL record each, e.g. to
+/// replace a damaged segment with its intact records.
+pub fn write_log(path: &Path, records: &[BatchRecord]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&header())?;
+    for record in records.chunks(1) {
+        file.write_all(&frame_batch(record))?;
+    }
+    file.sync_all()?;
+    rename(tmp_path, path)?;
+    if let Some(dir) = path.parent() {
+        sync_dir(dir)?;
+    }
+    Ok(())
+}
+
+fn header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(WAL_HEADER_SIZE);
+    header.extend(&WAL_MAGIC.to_le_bytes());
+    header.extend(&FORMAT_VERSION.to_le_bytes());
+    header
+}
+
+fn frame_batch(records: &[BatchRecord]) -> Vec<u8> {
+    if records
+        .iter()
+        .all(|(column_family, _, _)| *column_family == 0)
+    {
+        frame(TYPE_BATCH, &write_batch::encode(records, false))
+    } else {
+        frame(
+            TYPE_COLUMN_FAMILY_BATCH,
+            &write_batch::encode(records, true),
+        )
+    }
+}
+
+fn frame(record_type: u8, payload: &[u8]) -> Vec<u8> {
+    let mut binary = Vec::with_capacity(HEADER_SIZE + payload.len());
+    binary.extend(&[0; 4]);
+    binary.extend(&(payload.len() as u32).to_le_bytes());
+    binary.push(record_type);
+    binary.extend(payload);
+    let crc = extend(crc32c(&binary[8..9]), payload);
+    binary[..4].copy_from_slice(&crc.to_le_bytes());
+    binary
+}
+
+/// Decodes the WAL record at the start of `buffer`, its records encoded in `format`, returning
+/// its versions with its framed length, or `None` if it is truncated or corrupt.
+fn read_record(buffer: &[u8], format: RecordFormat) -> Option<(Vec<BatchRecord>, usize)> {
+    if buffer.len() < HEADER_SIZE {
+        return None;
+    }
+    let crc = u32::from_le_bytes(buffer[0..4].try_into().ok()?);
+    let len = u32::from_le_bytes(buffer[4..8].try_into().ok()?) as usize;
+    let record_type = buffer[8];
+    let payload = buffer.get(HEADER_SIZE..HEADER_SIZE + len)?;
+    if extend(crc32c(&[record_type]), payload) != crc {
+        return None;
+    }
+    let records = match record_type {
+        TYPE_RECORD => {
+            let (key, value) = format.decode(payload).ok()?;
+            vec![(0, key, value)]
+        }
+        TYPE_BATCH => write_batch::decode(payload, false, format).ok()?,
+        TYPE_COLUMN_FAMILY_BATCH => write_batch::decode(payload, true, format).ok()?,
+        _ => return None,
+    };
+    Some((records, HEADER_SIZE + len))
+}
+
+pub(crate) fn log_file_name(number: u64) -> String {
+    format!("{:>06}.log", number)
+}
+
+pub(crate) fn parse_log_file_name(path: &Path) -> Option<u64> {
+    if path.extension()? != "log" {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+/// Numbers of the WALs in `dir` still needed for recovery, oldest first.
+///
+/// A legacy `wal.bin` is rewritten as the WAL numbered `log_number`.
+pub fn list_logs(dir: &Path, log_number: u64) -> Result<Vec<u64>> {
+    let legacy_path = dir.join(LEGACY_WAL_FILE);
+    if legacy_path.exists() {
+        let path = dir.join(log_file_name(log_number));
+        info!("convert legacy wal {:?} to {:?}", legacy_path, path);
+        let records: Vec<_> = decode_file(&legacy_path)?
+            .into_iter()
+            .map(|(key, value)| (0, key, value))
+            .collect();
+        write_log(&path, &records)?;
+        remove_file(legacy_path)?;
+    }
+    let mut numbers = vec![];
+    for entry in read_dir(dir)? {
+        if let Some(number) = parse_log_file_name(&entry?.path()) {
+            if number >= log_number {
+                numbers.push(number);
+            }
+        }
+    }
+    numbers.sort_unstable();
+    Ok(numbers)
+}
+
+/// Retires WALs numbered below `log_number`, whose contents the MANIFEST records as flushed.
+///
+/// With `archive_retention` the segments are moved to the archive directory, which then keeps
+/// only the newest `archive_retention` of them; otherwise they are deleted.
+pub fn remove_obsolete_logs(
+    dir: &Path,
+    log_number: u64,
+    archive_retention: Option<usize>,
+) -> Result<()> {
+    let archive_dir = dir.join(ARCHIVE_DIR);
+    for entry in read_dir(dir)? {
+        let path = entry?.path();
+        if parse_log_file_name(&path).is_none_or(|n| n >= log_number) {
+            continue;
+        }
+        match (archive_retention, path.file_name()) {
+            (Some(_), Some(name)) => {
+                create_dir_all(&archive_dir)?;
+                info!("archive obsolete wal {:?}", path);
+                rename(&path, archive_dir.join(name))?;
+            }
+            _ => {
+                info!("remove obsolete wal {:?}", path);
+                remove_file(path)?;
+            }
+        }
+    }
+    if let Some(retention) = archive_retention {
+        if archive_dir.exists() {
+            let mut archived = vec![];
+            for entry in read_dir(&archive_dir)? {
+                let path = entry?.path();
+                if let Some(number) = parse_log_file_name(&path) {
+                    archived.push((number, path));
+                }
+            }
+            archived.sort_unstable();
+            let expired = archived.len().saturating_sub(retention);
+            for (_, path) in archived.into_iter().take(expired) {
+                info!("remove expired archived wal {:?}", path);
+                remove_file(path)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key::{InternalKey, ValueType};
+    use crate::options::{WalRecoveryMode, WalSyncPolicy};
+    use crate::record::encode_legacy;
+    use crate::value::Value;
+    use crate::wal::{frame, log_file_name, read_log, GroupCommit, Wal, TYPE_RECORD};
+    use std::fs::{create_dir_all, read, remove_dir_all, write};
+    use std::sync::{mpsc, Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    fn keys(wal: &Wal, mode: WalRecoveryMode) -> (Vec<String>, usize) {
+        let recovered = wal.recover(mode).unwrap();
+        let keys = recovered
+            .records
+            .into_iter()
+            .map(|(_, key, _)| key.user_key)
+            .collect();
+        (keys, recovered.dropped_bytes)
+    }
+
+    #[test]
+    fn recover_corrupted() {
+        let dir = std::env::temp_dir().join("lsm_engine_wal_recover_corrupted");
+        let _ = remove_dir_all(&dir);
+        create_dir_all(&dir).unwrap();
+        let mut wal = Wal::open(&dir, 1, WalSyncPolicy::Never).unwrap();
+        let path = dir.join(log_file_name(1));
+        let mut ends = vec![];
+        for (sequence, key) in ["a", "b", "c"].iter().enumerate() {
+            let value = Value::new("value".to_string(), 0, 0);
+            let key = InternalKey::new(key.to_string(), sequence as u64 + 1, ValueType::Value);
+            wal.write(&[(0, key, Some(value))]).unwrap();
+            ends.push(read(&path).unwrap().len());
+        }
+        let key = InternalKey::new("d".to_string(), 4, ValueType::Deletion);
+        wal.write(&[(1, key, None)]).unwrap();
+        let intact = read(&path).unwrap();
+        assert_eq!(
+            keys(&wal, WalRecoveryMode::TolerateCorruptedTailRecords),
+            (
+                vec!["a", "b", "c", "d"]
+                    .into_iter()
+                    .map(String::from)
+                    .collect(),
+                0
+            )
+        );
+
+        // torn tail: the last record is cut in the middle
+        write(&path, &intact[..intact.len() - 3]).unwrap();
+        assert_eq!(
+            keys(&wal, WalRecoveryMode::TolerateCorruptedTailRecords),
+            (
+                vec!["a", "b", "c"].into_iter().map(String::from).collect(),
+                intact.len() - 3 - ends[2]
+            )
+        );
+
+        // flipped bit in the second record
+        let mut corrupted = intact.clone();
+        corrupted[ends[0] + 12] ^= 1;
+        write(&path, &corrupted).unwrap();
+        assert_eq!(
+            keys(&wal, WalRecoveryMode::TolerateCorruptedTailRecords),
+            (vec!["a".to_string()], intact.len() - ends[0])
+        );
+        assert_eq!(
+            keys(&wal, WalRecoveryMode::SkipAnyCorruptedRecords),
+            (
+                vec!["a", "c", "d"].into_iter().map(String::from).collect(),
+                ends[1] - ends[0]
+            )
+        );
+
+        // segments without header have legacy records
+        let key = InternalKey::new("e".to_string(), 5, ValueType::Deletion);
+        let path = dir.join(log_file_name(2));
+        write(&path, frame(TYPE_RECORD, &encode_legacy(&key, None))).unwrap();
+        let recovered = read_log(&path, WalRecoveryMode::TolerateCorruptedTailRecords).unwrap();
+        assert_eq!(recovered.format_version, 1);
+        assert_eq!(recovered.records, vec![(0, key, None)]);
+        remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn group_commit() {
+        let group_commit = Arc::new(GroupCommit::new());
+        let groups = Arc::new(Mutex::new(vec![]));
+        let handles: Vec<_> = (0..8)
+            .map(|writer| {
+                let group_commit = group_commit.clone();
+                let groups = groups.clone();
+                thread::spawn(move || {
+                    for i in 0..50 {
+                        group_commit
+                            .commit((writer, i), |group| {
+                                thread::sleep(Duration::from_micros(200));
+                                let results = group.iter().map(|_| Ok(())).collect();
+                                groups.lock().unwrap().push(group);
+                                Ok(results)
+                            })
+                            .unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let groups = groups.lock().unwrap();
+        assert!(groups.len() < 8 * 50);
+        // every item is written exactly once and each writer's items stay in order
+        for writer in 0..8 {
+            let items: Vec<_> = groups
+                .iter()
+                .flatten()
+                .filter(|(w, _)| *w == writer)
+                .map(|(_, i)| *i)
+                .collect();
+            assert_eq!(items, (0..50).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn panicking_group_commit() {
+        let group_commit = Arc::new(GroupCommit::new());
+        // a first group is being written while the next leader and a follower queue up
+        let (started, wait_started) = mpsc::channel();
+        let (release, wait_release) = mpsc::channel::<()>();
+        let first = {
+            let group_commit = group_commit.clone();
+            thread::spawn(move || {
+                group_commit.commit(1, |group| {
+                    started.send(()).unwrap();
+                    wait_release.recv().unwrap();
+                    Ok(group.iter().map(|_| Ok(())).collect())
+                })
+            })
+        };
+        wait_started.recv().unwrap();
+        let commit = |item, panics| {
+            let group_commit = group_commit.clone();
+            let handle = thread::spawn(move || {
+                group_commit.commit(item, |group| {
+                    if panics {
+                        panic!("write panicked");
+                    }
+                    Ok(group.iter().map(|_| Ok(())).collect())
+                })
+            });
+            thread::sleep(Duration::from_millis(50));
+            handle
+        };
+        let leader = commit(2, true);
+        let follower = commit(3, false);
+        release.send(()).unwrap();
+
+        assert!(first.join().unwrap().is_ok());
+        assert!(leader.join().is_err());
+        assert_eq!(
+            follower.join().unwrap().unwrap_err().to_string(),
+            "group commit write panicked"
+        );
+        // later writers are not stuck behind the failed group
+        assert!(commit(4, false).join().unwrap().is_ok());
+    }
 }
@@ -0,0 +1,175 @@
+use crate::column_family::ColumnFamily;
+use crate::key::{InternalKey, ValueType};
+use crate::record::{self, RecordFormat};
+use crate::value::Value;
+use anyhow::{bail, Result};
+use std::convert::TryInto;
+use std::mem::size_of;
+
+/// A version with the id of its column family.
+pub type BatchRecord = (u32, InternalKey, Option<Value>);
+
+/// Puts and deletes applied atomically by `Db::write`: all of them are logged as one WAL record
+/// with consecutive sequence numbers, and become visible together, also across column families.
+#[derive(Clone, Default)]
+pub struct WriteBatch {
+    ops: Vec<(u32, String, Option<Value>)>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, key: String, value: Value) {
+        self.put_cf(&ColumnFamily::default(), key, value);
+    }
+
+    pub fn delete(&mut self, key: String) {
+        self.delete_cf(&ColumnFamily::default(), key);
+    }
+
+    pub fn put_cf(&mut self, column_family: &ColumnFamily, key: String, value: Value) {
+        self.ops.push((column_family.id(), key, Some(value)));
+    }
+
+    pub fn delete_cf(&mut self, column_family: &ColumnFamily, key: String) {
+        self.ops.push((column_family.id(), key, None));
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Column family ids and keys written, in order, with repeats.
+    pub(crate) fn keys(&self) -> impl Iterator<Item = (u32, &String)> {
+        self.ops
+            .iter()
+            .map(|(column_family, key, _)| (*column_family, key))
+    }
+
+    /// Appends the operations of `other`, which then apply after those of `self`.
+    pub(crate) fn append(&mut self, other: WriteBatch) {
+        self.ops.extend(other.ops);
+    }
+
+    /// The operations as versions numbered from `first_sequence`, in order.
+    pub(crate) fn into_records(self, first_sequence: u64) -> Vec<BatchRecord> {
+        self.ops
+            .into_iter()
+            .zip(first_sequence..)
+            .map(|((column_family, key, value), sequence)| {
+                let value_type = if value.is_some() {
+                    ValueType::Value
+                } else {
+                    ValueType::Deletion
+                };
+                (
+                    column_family,
+                    InternalKey::new(key, sequence, value_type),
+                    value,
+                )
+            })
+            .collect()
+    }
+}
+
+/// Encodes versions with consecutive sequences as
+/// `first sequence(u64) | count(u32) | (column family(u32) | length(u32) | record)*`, or
+/// without the column family ids, which are then all 0, unless `with_column_families`.
+pub(crate) fn encode(records: &[BatchRecord], with_column_families: bool) -> Vec<u8> {
+    let first_sequence = records.first().map_or(0, |(_, key, _)| key.sequence);
+    let mut binary = Vec::new();
+    binary.extend(&first_sequence.to_le_bytes());
+    binary.extend(&(records.len() as u32).to_le_bytes());
+    for (column_family, key, value) in records {
+        if with_column_families {
+            binary.extend(&column_family.to_le_bytes());
+        }
+        let record = record::encode(key, value.as_ref());
+        binary.extend(&(record.len() as u32).to_le_bytes());
+        binary.extend(record);
+    }
+    binary
+}
+
+/// Decodes a write batch whose records are encoded in `format`.
+pub(crate) fn decode(
+    binary: &[u8],
+    with_column_families: bool,
+    format: RecordFormat,
+) -> Result<Vec<BatchRecord>> {
+    let header_len = size_of::<u64>() + size_of::<u32>();
+    if binary.len() < header_len {
+        bail!("write batch too short: {} bytes", binary.len());
+    }
+    let first_sequence = u64::from_le_bytes(binary[..8].try_into()?);
+    let count = u32::from_le_bytes(binary[8..12].try_into()?) as u64;
+    let mut records = vec![];
+    let mut index = header_len;
+    while index < binary.len() {
+        let mut column_family = 0;
+        if with_column_families {
+            column_family = match binary.get(index..index + size_of::<u32>()) {
+                Some(id) => u32::from_le_bytes(id.try_into()?),
+                None => bail!("write batch truncated at {}", index),
+            };
+            index += size_of::<u32>();
+        }
+        let len = match binary.get(index..index + size_of::<u32>()) {
+            Some(len) => u32::from_le_bytes(len.try_into()?) as usize,
+            None => bail!("write batch truncated at {}", index),
+        };
+        index += size_of::<u32>();
+        let (key, value) = match binary.get(index..index + len) {
+            Some(record) => format.decode(record)?,
+            None => bail!("write batch truncated at {}", index),
+        };
+        if key.sequence != first_sequence + records.len() as u64 {
+            bail!("write batch sequence {} out of order", key.sequence);
+        }
+        records.push((column_family, key, value));
+        index += len;
+    }
+    if records.len() as u64 != count {
+        bail!("write batch has {} of {} records", records.len(), count);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::column_family::ColumnFamily;
+    use crate::record::RecordFormat;
+    use crate::value::Value;
+    use crate::write_batch::{decode, encode, WriteBatch};
+
+    #[test]
+    fn encode_decode() {
+        let mut batch = WriteBatch::new();
+        batch.put("a".to_string(), Value::new("1".to_string(), 0, 0));
+        batch.delete("b".to_string());
+        batch.put("a".to_string(), Value::new("2".to_string(), 0, 0));
+        let records = batch.clone().into_records(10);
+        let binary = encode(&records, false);
+        assert_eq!(
+            decode(&binary, false, RecordFormat::Varint).unwrap(),
+            records
+        );
+        assert!(decode(&binary[..binary.len() - 1], false, RecordFormat::Varint).is_err());
+
+        batch.delete_cf(&ColumnFamily::new(3, "other".to_string()), "a".to_string());
+        let records = batch.into_records(10);
+        let binary = encode(&records, true);
+        assert_eq!(
+            decode(&binary, true, RecordFormat::Varint).unwrap(),
+            records
+        );
+        assert_eq!(decode(&binary, true, RecordFormat::Varint).unwrap()[3].0, 3);
+        assert!(decode(&binary[..binary.len() - 1], true, RecordFormat::Varint).is_err());
+    }
+}